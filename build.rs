@@ -6,4 +6,13 @@ fn main() {
         res.set_icon("tango.ico");
         res.compile().unwrap();
     }
+
+    if std::env::var_os("CARGO_FEATURE_EMBEDDED_WEB").is_some() {
+        let bundle = std::env::var("TANGO_WEB_BUNDLE")
+            .expect("embedded-web needs TANGO_WEB_BUNDLE set to a web UI zip");
+        let bundle = std::fs::canonicalize(&bundle).expect("TANGO_WEB_BUNDLE not found");
+        println!("cargo:rerun-if-env-changed=TANGO_WEB_BUNDLE");
+        println!("cargo:rerun-if-changed={}", bundle.display());
+        println!("cargo:rustc-env=TANGO_WEB_BUNDLE={}", bundle.display());
+    }
 }
@@ -1,10 +1,37 @@
-use std::{env, mem, path::Path, process::Stdio, time::Duration};
+use std::{
+    env, mem,
+    net::SocketAddr,
+    path::Path,
+    process::Stdio,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    process::Command,
+};
+
+use crate::config::AdbConfig;
+
+/// Whether the bridge has started an ADB server, rather than finding one.
+static SPAWNED: AtomicBool = AtomicBool::new(false);
+
+pub fn spawned() -> bool {
+    SPAWNED.load(Ordering::Relaxed)
+}
+
+static FOUND: AtomicBool = AtomicBool::new(false);
 
-use tokio::{net::TcpStream, process::Command};
+/// Whether an ADB server has accepted a connection since the bridge started.
+pub fn found() -> bool {
+    FOUND.load(Ordering::Relaxed)
+}
 
-async fn adb_start(path: &Path) -> tokio::io::Result<()> {
+async fn adb_start(path: &Path, port: u16) -> tokio::io::Result<()> {
     let mut command = Command::new(path);
-    command.args(&["server", "nodaemon"]);
+    command.args(["-P", &port.to_string(), "server", "nodaemon"]);
 
     if path.is_absolute() {
         command.current_dir(path.parent().unwrap());
@@ -25,18 +52,21 @@ async fn adb_start(path: &Path) -> tokio::io::Result<()> {
     // Tokio documentation recommends not dropping the `Child`
     // to make sure the child process exits correctly on Unix platforms
     mem::forget(command.spawn()?);
+    SPAWNED.store(true, Ordering::Relaxed);
 
     Ok(())
 }
 
-async fn adb_connect() -> tokio::io::Result<TcpStream> {
-    TcpStream::connect("127.0.0.1:5037").await
+async fn adb_connect(addr: SocketAddr) -> tokio::io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+    FOUND.store(true, Ordering::Relaxed);
+    Ok(stream)
 }
 
-async fn adb_connect_retry() -> tokio::io::Result<TcpStream> {
+async fn adb_connect_retry(addr: SocketAddr) -> tokio::io::Result<TcpStream> {
     let mut i = 0;
     loop {
-        match adb_connect().await {
+        match adb_connect(addr).await {
             Ok(result) => return Ok(result),
             Err(err) => {
                 if i == 10 {
@@ -50,14 +80,21 @@ async fn adb_connect_retry() -> tokio::io::Result<TcpStream> {
     }
 }
 
-pub async fn connect_or_start() -> tokio::io::Result<TcpStream> {
-    if let Ok(stream) = adb_connect().await {
+pub async fn connect_or_start(config: &AdbConfig) -> tokio::io::Result<TcpStream> {
+    let addr = config.server;
+    let port = addr.port();
+    if let Ok(stream) = adb_connect(addr).await {
         return Ok(stream);
     }
 
+    if let Some(path) = &config.executable {
+        adb_start(path, port).await?;
+        return adb_connect_retry(addr).await;
+    }
+
     // Try system installed adb first
-    if adb_start(Path::new("adb")).await.is_ok() {
-        return adb_connect_retry().await;
+    if adb_start(Path::new("adb"), port).await.is_ok() {
+        return adb_connect_retry(addr).await;
     }
 
     #[cfg(windows)]
@@ -82,7 +119,7 @@ pub async fn connect_or_start() -> tokio::io::Result<TcpStream> {
             )?;
         }
 
-        adb_start(&adb_path).await?;
+        adb_start(&adb_path, port).await?;
     }
 
     #[cfg(target_os = "linux")]
@@ -99,7 +136,7 @@ pub async fn connect_or_start() -> tokio::io::Result<TcpStream> {
             std::fs::set_permissions(&adb_path, std::fs::Permissions::from_mode(0o755))?;
         }
 
-        adb_start(&adb_path).await?;
+        adb_start(&adb_path, port).await?;
     }
 
     #[cfg(target_os = "macos")]
@@ -111,9 +148,39 @@ pub async fn connect_or_start() -> tokio::io::Result<TcpStream> {
                 .unwrap()
                 .join("adb")
                 .as_path(),
+            port,
         )
         .await?;
     }
 
-    adb_connect_retry().await
+    adb_connect_retry(addr).await
+}
+
+/// Asks the server at `addr` for its protocol version, without starting one.
+pub async fn version(addr: SocketAddr) -> io::Result<u32> {
+    let mut stream = adb_connect(addr).await?;
+    let request = "host:version";
+    stream
+        .write_all(format!("{:04x}{}", request.len(), request).as_bytes())
+        .await?;
+
+    // OKAY, then a hex length and the version itself in hex.
+    let mut header = [0; 8];
+    stream.read_exact(&mut header).await?;
+    if &header[..4] != b"OKAY" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ADB server refused host:version",
+        ));
+    }
+    let mut payload = vec![0; parse_hex(&header[4..])? as usize];
+    stream.read_exact(&mut payload).await?;
+    parse_hex(&payload)
+}
+
+fn parse_hex(digits: &[u8]) -> io::Result<u32> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad ADB reply"))
 }
@@ -0,0 +1,284 @@
+//! Parses the ADB host-protocol requests a client sends at the start of a
+//! smart-socket connection, before the connection turns into a raw stream.
+
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+
+/// Longest request the inspector parses. adb takes longer ones, so policies
+/// refuse them rather than let them through unchecked.
+const MAX_REQUEST_LEN: usize = 1024;
+
+/// One length-prefixed request, e.g. `host:transport:<serial>` or `shell:ls`.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostRequest {
+    /// Device selected by this or an earlier request on the same connection.
+    pub serial: Option<String>,
+    pub service: String,
+}
+
+impl HostRequest {
+    /// Whether this request only selects the device for the next one.
+    pub fn is_transport(&self) -> bool {
+        transport_serial(&self.service).is_some()
+    }
+}
+
+/// Who is on the other end of the bridge socket.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClientInfo {
+    pub origin: Option<String>,
+    #[serde(skip)]
+    pub token: Option<String>,
+}
+
+pub enum Verdict {
+    Allow,
+    Deny(String),
+}
+
+/// Decides whether a request may reach the ADB server.
+pub trait RequestPolicy: Send + Sync {
+    fn check(&self, client: &ClientInfo, request: &HostRequest) -> Verdict;
+
+    /// Whether input that can't be parsed is refused instead of forwarded.
+    fn fails_closed(&self) -> bool {
+        true
+    }
+}
+
+pub struct AllowAll;
+
+impl RequestPolicy for AllowAll {
+    fn check(&self, _client: &ClientInfo, _request: &HostRequest) -> Verdict {
+        Verdict::Allow
+    }
+
+    fn fails_closed(&self) -> bool {
+        false
+    }
+}
+
+/// What the relay should do with the bytes fed to the inspector.
+pub enum Chunk {
+    /// Bytes after the host-protocol phase, forward as-is.
+    Raw(Bytes),
+    /// A complete request with its encoded bytes, forward only if allowed.
+    Request(HostRequest, Bytes),
+    /// Bytes that don't start with a request the inspector can check, e.g.
+    /// a non-hex or over-long length prefix. Everything after is `Raw`.
+    Invalid { message: String, data: Bytes },
+}
+
+#[derive(Default)]
+pub struct Inspector {
+    buf: BytesMut,
+    serial: Option<String>,
+    raw: bool,
+}
+
+impl Inspector {
+    pub fn feed(&mut self, data: Bytes) -> Vec<Chunk> {
+        if self.raw {
+            return vec![Chunk::Raw(data)];
+        }
+
+        self.buf.extend_from_slice(&data);
+        let mut chunks = Vec::new();
+
+        while !self.raw {
+            let len = match self.request_len() {
+                Ok(len) => len,
+                Err(message) => {
+                    self.raw = true;
+                    chunks.push(Chunk::Invalid {
+                        message,
+                        data: self.buf.split().freeze(),
+                    });
+                    return chunks;
+                }
+            };
+            let Some(len) = len else {
+                return chunks;
+            };
+            if self.buf.len() < 4 + len {
+                return chunks;
+            }
+
+            let encoded = self.buf.split_to(4 + len).freeze();
+            let service = String::from_utf8_lossy(&encoded[4..]).into_owned();
+
+            match transport_serial(&service) {
+                Some(serial) => self.serial = serial,
+                // Anything but a transport switch is the last request on
+                // this connection, the rest is service data.
+                None => self.raw = true,
+            }
+            if let Some(serial) = host_serial(&service) {
+                self.serial = Some(serial.to_string());
+            }
+
+            chunks.push(Chunk::Request(
+                HostRequest {
+                    serial: self.serial.clone(),
+                    service,
+                },
+                encoded,
+            ));
+        }
+
+        if !self.buf.is_empty() {
+            chunks.push(Chunk::Raw(self.buf.split().freeze()));
+        }
+        chunks
+    }
+
+    /// The length of the next request, `None` while its prefix is
+    /// incomplete.
+    fn request_len(&self) -> Result<Option<usize>, String> {
+        let prefix = &self.buf[..self.buf.len().min(4)];
+        if !prefix.iter().all(u8::is_ascii_hexdigit) {
+            return Err("Tango bridge: malformed ADB request".to_string());
+        }
+        if prefix.len() < 4 {
+            return Ok(None);
+        }
+        // Only hex digits, so this can't fail.
+        let len = usize::from_str_radix(std::str::from_utf8(prefix).unwrap(), 16).unwrap();
+        if len > MAX_REQUEST_LEN {
+            return Err(format!(
+                "Tango bridge: ADB requests over {} bytes are not allowed",
+                MAX_REQUEST_LEN
+            ));
+        }
+        Ok(Some(len))
+    }
+}
+
+/// For `host:transport*` requests returns the device they select, `Some(None)`
+/// when it's chosen by the server (`-usb`, `-local`, `-any`, `-id`).
+fn transport_serial(service: &str) -> Option<Option<String>> {
+    let request = service.strip_prefix("host:")?;
+
+    if let Some(serial) = request.strip_prefix("transport:") {
+        return Some(Some(serial.to_string()));
+    }
+    if matches!(
+        request,
+        "transport-usb" | "transport-local" | "transport-any"
+    ) || request.starts_with("transport-id:")
+    {
+        return Some(None);
+    }
+
+    // Newer clients use `host:tport:` to also get the transport id back.
+    let request = request.strip_prefix("tport:")?;
+    if let Some(serial) = request.strip_prefix("serial:") {
+        return Some(Some(serial.to_string()));
+    }
+    matches!(request, "usb" | "local" | "any").then_some(None)
+}
+
+/// Returns the serial of a `host-serial:<serial>:<request>` request. Serials
+/// can be `<host>:<port>` themselves, so a numeric segment is kept.
+fn host_serial(service: &str) -> Option<&str> {
+    let rest = service.strip_prefix("host-serial:")?;
+    let mut serial_len = rest.find(':')?;
+
+    let after = &rest[serial_len + 1..];
+    if let Some(port_len) = after.find(':') {
+        if port_len > 0 && after[..port_len].bytes().all(|b| b.is_ascii_digit()) {
+            serial_len += 1 + port_len;
+        }
+    }
+
+    Some(&rest[..serial_len])
+}
+
+/// Encodes a host-protocol failure reply.
+pub fn fail_reply(message: &str) -> Bytes {
+    Bytes::from(format!("FAIL{:04x}{}", message.len(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(service: &str) -> Bytes {
+        Bytes::from(format!("{:04x}{}", service.len(), service))
+    }
+
+    fn services(chunks: &[Chunk]) -> Vec<&str> {
+        chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                Chunk::Request(request, _) => Some(request.service.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_transport_then_service() {
+        let mut inspector = Inspector::default();
+        let mut data = BytesMut::new();
+        data.extend_from_slice(&request("host:transport:R58M123ABC"));
+        data.extend_from_slice(&request("shell:ls"));
+        data.extend_from_slice(b"trailing");
+        let chunks = inspector.feed(data.freeze());
+
+        assert_eq!(services(&chunks), ["host:transport:R58M123ABC", "shell:ls"]);
+        let Chunk::Request(shell, _) = &chunks[1] else {
+            panic!("expected a request");
+        };
+        assert_eq!(shell.serial.as_deref(), Some("R58M123ABC"));
+        assert!(matches!(&chunks[2], Chunk::Raw(data) if &data[..] == b"trailing"));
+    }
+
+    #[test]
+    fn waits_for_a_split_prefix() {
+        let mut inspector = Inspector::default();
+        let data = request("shell:id");
+        assert!(inspector.feed(data.slice(..2)).is_empty());
+        assert!(inspector.feed(data.slice(2..6)).is_empty());
+        let chunks = inspector.feed(data.slice(6..));
+        assert_eq!(services(&chunks), ["shell:id"]);
+    }
+
+    #[test]
+    fn refuses_a_split_non_hex_prefix() {
+        let mut inspector = Inspector::default();
+        assert!(inspector.feed(Bytes::from_static(b"0")).is_empty());
+        let chunks = inspector.feed(Bytes::from_static(b"0zzshell:id"));
+        assert!(matches!(
+            &chunks[..],
+            [Chunk::Invalid { data, .. }] if &data[..] == b"00zzshell:id"
+        ));
+    }
+
+    #[test]
+    fn refuses_a_non_hex_prefix() {
+        let mut inspector = Inspector::default();
+        let chunks = inspector.feed(Bytes::from_static(b"GET / HTTP/1.1\r\n"));
+        assert!(matches!(&chunks[..], [Chunk::Invalid { .. }]));
+        // Nothing after it is parsed as a request again.
+        let chunks = inspector.feed(request("shell:id"));
+        assert!(matches!(&chunks[..], [Chunk::Raw(_)]));
+    }
+
+    #[test]
+    fn refuses_an_over_long_request() {
+        let mut inspector = Inspector::default();
+        let service = format!("shell:{}", " ".repeat(MAX_REQUEST_LEN));
+        let chunks = inspector.feed(request(&service));
+        assert!(matches!(&chunks[..], [Chunk::Invalid { .. }]));
+    }
+
+    #[test]
+    fn reads_host_serial() {
+        assert_eq!(
+            host_serial("host-serial:192.168.1.2:5555:get-state"),
+            Some("192.168.1.2:5555")
+        );
+        assert_eq!(host_serial("host-serial:R58M:get-state"), Some("R58M"));
+    }
+}
@@ -0,0 +1,260 @@
+//! Per-client access rules for the `/bridge` ADB socket, read from
+//! `adb-policy.json` in the data directory:
+//!
+//! ```json
+//! {
+//!   "default": "deny",
+//!   "rules": [
+//!     {
+//!       "origins": ["https://app.tangoapp.dev"],
+//!       "serials": ["R58M123ABC"],
+//!       "allow": ["host:", "shell:", "sync:"],
+//!       "deny": ["reboot:", "root:", "exec:cmd package install"]
+//!     }
+//!   ]
+//! }
+//! ```
+
+use std::{io, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    adb_inspector::{ClientInfo, HostRequest, RequestPolicy, Verdict},
+    paths,
+};
+
+const POLICY_FILE: &str = "adb-policy.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Rule {
+    /// Origins this rule applies to, `*` matches any.
+    #[serde(default)]
+    pub origins: Vec<String>,
+    /// Tokens this rule applies to.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// Device serials this rule applies to, empty matches any.
+    #[serde(default)]
+    pub serials: Vec<String>,
+    /// Service prefixes that are allowed, empty allows all not denied.
+    /// `shell:` also covers `exec:`, `abb:` and shell options.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Service prefixes that are denied, checked before `allow`.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl Rule {
+    fn matches(&self, client: &ClientInfo, request: &HostRequest) -> bool {
+        let origin = client.origin.as_deref();
+        let token = client.token.as_deref();
+        let client_matches = self
+            .origins
+            .iter()
+            .any(|allowed| allowed == "*" || Some(allowed.as_str()) == origin)
+            || token.is_some_and(|token| self.tokens.iter().any(|allowed| allowed == token));
+
+        let serial_matches = self.serials.is_empty()
+            || request
+                .serial
+                .as_ref()
+                .is_some_and(|serial| self.serials.contains(serial));
+
+        client_matches && serial_matches
+    }
+
+    fn action(&self, request: &HostRequest) -> Action {
+        // Switching to a device is covered by `serials`, the services used
+        // on it are what the prefixes are for.
+        if request.is_transport() {
+            return Action::Allow;
+        }
+
+        let service = normalize(&request.service);
+        let matches = |prefix: &String| service.starts_with(&normalize(prefix));
+        if self.deny.iter().any(matches) {
+            return Action::Deny;
+        }
+        if self.allow.is_empty() || self.allow.iter().any(matches) {
+            Action::Allow
+        } else {
+            Action::Deny
+        }
+    }
+}
+
+/// The form services and prefixes are compared in. Shell options, as in
+/// `shell,v2,raw:ls`, are dropped, and `exec:` and `abb:` run the same
+/// commands as `shell:` and `shell:cmd`, so one rule covers every form.
+fn normalize(service: &str) -> String {
+    let Some((name, command)) = service.split_once(':') else {
+        return service.to_string();
+    };
+    match name.split(',').next().unwrap_or(name) {
+        "shell" | "exec" => format!("shell:{}", command),
+        // Arguments are separated by NULs.
+        "abb" | "abb_exec" => format!("shell:cmd {}", command.replace('\0', " ")),
+        _ => service.to_string(),
+    }
+}
+
+/// First matching rule decides, `default` applies when none matches.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AccessPolicy {
+    #[serde(default)]
+    pub default: Action,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+impl AccessPolicy {
+    pub fn path() -> PathBuf {
+        paths::data_dir().join(POLICY_FILE)
+    }
+
+    /// Reads the policy file, `Ok(None)` if there is none.
+    pub fn load() -> Result<Option<Self>, String> {
+        let path = Self::path();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("{}: {}", path.display(), err)),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    /// Used when the policy file can't be read, so a typo doesn't open
+    /// the ADB server to everyone.
+    pub fn deny_all() -> Self {
+        Self {
+            default: Action::Deny,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl RequestPolicy for AccessPolicy {
+    fn check(&self, client: &ClientInfo, request: &HostRequest) -> Verdict {
+        let action = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(client, request))
+            .map_or(self.default, |rule| rule.action(request));
+
+        match action {
+            Action::Allow => Verdict::Allow,
+            Action::Deny => Verdict::Deny(match &request.serial {
+                Some(serial) => format!(
+                    "Tango bridge: `{}` on device {} is not allowed for this client",
+                    request.service, serial
+                ),
+                None => format!(
+                    "Tango bridge: `{}` is not allowed for this client",
+                    request.service
+                ),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> AccessPolicy {
+        AccessPolicy {
+            default: Action::Deny,
+            rules: vec![Rule {
+                origins: vec!["https://app.tangoapp.dev".to_string()],
+                allow: vec!["host:".to_string(), "shell:".to_string()],
+                deny: vec![
+                    "reboot:".to_string(),
+                    "exec:cmd package install".to_string(),
+                ],
+                ..Rule::default()
+            }],
+        }
+    }
+
+    fn allowed(policy: &AccessPolicy, origin: &str, service: &str) -> bool {
+        let client = ClientInfo {
+            origin: Some(origin.to_string()),
+            token: None,
+        };
+        let request = HostRequest {
+            serial: Some("R58M123ABC".to_string()),
+            service: service.to_string(),
+        };
+        matches!(policy.check(&client, &request), Verdict::Allow)
+    }
+
+    #[test]
+    fn applies_the_first_matching_rule() {
+        let policy = policy();
+        assert!(allowed(&policy, "https://app.tangoapp.dev", "shell:ls"));
+        assert!(!allowed(&policy, "https://app.tangoapp.dev", "reboot:"));
+        assert!(!allowed(&policy, "https://app.tangoapp.dev", "sync:"));
+        assert!(!allowed(&policy, "https://evil.example", "shell:ls"));
+    }
+
+    #[test]
+    fn matches_every_form_of_a_shell_command() {
+        let policy = AccessPolicy {
+            default: Action::Allow,
+            rules: vec![Rule {
+                origins: vec!["*".to_string()],
+                deny: vec!["shell:".to_string()],
+                ..Rule::default()
+            }],
+        };
+        for service in [
+            "shell:ls",
+            "shell,v2,raw:ls",
+            "shell,v2,TERM=xterm-256color,pty:",
+            "exec:ls",
+            "abb_exec:package\0list",
+        ] {
+            assert!(
+                !allowed(&policy, "https://app.tangoapp.dev", service),
+                "{}",
+                service
+            );
+        }
+        assert!(allowed(&policy, "https://app.tangoapp.dev", "sync:"));
+        assert!(allowed(&policy, "https://app.tangoapp.dev", "shellfish:"));
+    }
+
+    #[test]
+    fn matches_commands_behind_any_service() {
+        let policy = policy();
+        for service in [
+            "shell:cmd package install -r /data/local/tmp/app.apk",
+            "shell,v2,raw:cmd package install app.apk",
+            "exec:cmd package install app.apk",
+            "abb_exec:package\0install\0app.apk",
+        ] {
+            assert!(
+                !allowed(&policy, "https://app.tangoapp.dev", service),
+                "{}",
+                service
+            );
+        }
+        assert!(allowed(
+            &policy,
+            "https://app.tangoapp.dev",
+            "shell,v2,raw:cmd package list"
+        ));
+    }
+}
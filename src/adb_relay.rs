@@ -0,0 +1,540 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::extract::ws::{Message, WebSocket};
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream::SplitSink, SinkExt, Stream, StreamExt};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::tcp::OwnedReadHalf,
+    sync::{mpsc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::{
+    adb,
+    adb_inspector::{
+        fail_reply, Chunk, ClientInfo, HostRequest, Inspector, RequestPolicy, Verdict,
+    },
+    config::ConfigStore,
+    metrics::metrics,
+    registry::{DeviceEvent, DeviceRegistry},
+    shutdown::Shutdown,
+};
+
+/// Size of the single read buffer each session keeps for ADB output.
+const READ_BUFFER: usize = 64 * 1024;
+
+/// Closed sessions kept for the listing.
+const CLOSED_HISTORY: usize = 32;
+
+/// Why a relay session ended.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CloseReason {
+    AdbUnavailable {
+        message: String,
+    },
+    ClientClosed,
+    ClientError {
+        message: String,
+    },
+    AdbClosed,
+    AdbError {
+        message: String,
+    },
+    Denied {
+        service: String,
+        message: String,
+    },
+    /// The client sent something the inspector couldn't check.
+    Malformed {
+        message: String,
+    },
+    Shutdown,
+}
+
+#[derive(Default)]
+struct Counters {
+    bytes: AtomicU64,
+    packets: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> DirectionStats {
+        DirectionStats {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            packets: self.packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirectionStats {
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    /// Unix time in milliseconds.
+    pub started_at: u64,
+    pub duration_ms: u64,
+    #[serde(flatten)]
+    pub client: ClientInfo,
+    /// Device the session was switched to, if inspected.
+    pub serial: Option<String>,
+    /// Host-protocol requests seen on the session, if inspected.
+    pub services: Vec<String>,
+    pub ws_to_adb: DirectionStats,
+    pub adb_to_ws: DirectionStats,
+    /// `None` while the session is still open.
+    pub close_reason: Option<CloseReason>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionList {
+    pub active: Vec<SessionInfo>,
+    pub closed: Vec<SessionInfo>,
+}
+
+#[derive(Default)]
+struct Inspected {
+    serial: Option<String>,
+    services: Vec<String>,
+}
+
+struct Session {
+    id: u64,
+    started_at: u64,
+    started: Instant,
+    client: ClientInfo,
+    inspected: StdMutex<Inspected>,
+    ws_to_adb: Counters,
+    adb_to_ws: Counters,
+}
+
+impl Session {
+    fn info(&self, close_reason: Option<CloseReason>) -> SessionInfo {
+        let inspected = self.inspected.lock().unwrap();
+        SessionInfo {
+            id: self.id,
+            started_at: self.started_at,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            client: self.client.clone(),
+            serial: inspected.serial.clone(),
+            services: inspected.services.clone(),
+            ws_to_adb: self.ws_to_adb.snapshot(),
+            adb_to_ws: self.adb_to_ws.snapshot(),
+            close_reason,
+        }
+    }
+}
+
+/// Tracks the WebSocket sessions relayed to the ADB server.
+#[derive(Clone)]
+pub struct AdbSessions {
+    registry: Arc<DeviceRegistry>,
+    config: ConfigStore,
+    /// Requests are only parsed when a policy is set.
+    policy: Option<Arc<dyn RequestPolicy>>,
+    shutdown: Shutdown,
+    next_id: Arc<AtomicU64>,
+    active: Arc<Mutex<HashMap<u64, Arc<Session>>>>,
+    closed: Arc<Mutex<VecDeque<SessionInfo>>>,
+}
+
+impl AdbSessions {
+    pub fn new(
+        registry: Arc<DeviceRegistry>,
+        config: ConfigStore,
+        policy: Option<Arc<dyn RequestPolicy>>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            registry,
+            config,
+            policy,
+            shutdown,
+            next_id: Arc::default(),
+            active: Arc::default(),
+            closed: Arc::default(),
+        }
+    }
+
+    pub async fn list(&self) -> SessionList {
+        let mut active = self
+            .active
+            .lock()
+            .await
+            .values()
+            .map(|session| session.info(None))
+            .collect::<Vec<_>>();
+        active.sort_by_key(|session| session.id);
+
+        SessionList {
+            active,
+            closed: self.closed.lock().await.iter().cloned().collect(),
+        }
+    }
+
+    async fn open(&self, client: ClientInfo) -> Arc<Session> {
+        let session = Arc::new(Session {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or_default(),
+            started: Instant::now(),
+            client,
+            inspected: StdMutex::default(),
+            ws_to_adb: Counters::default(),
+            adb_to_ws: Counters::default(),
+        });
+        self.active.lock().await.insert(session.id, session.clone());
+        metrics().adb_sessions.inc();
+        metrics().adb_sessions_active.inc();
+        session
+    }
+
+    async fn close(&self, session: &Session, reason: CloseReason) {
+        self.active.lock().await.remove(&session.id);
+        metrics().adb_sessions_active.dec();
+
+        let info = session.info(Some(reason));
+        tracing::info!(
+            id = info.id,
+            duration_ms = info.duration_ms,
+            ws_to_adb_bytes = info.ws_to_adb.bytes,
+            adb_to_ws_bytes = info.adb_to_ws.bytes,
+            serial = info.serial,
+            reason = ?info.close_reason,
+            "adb session closed"
+        );
+
+        let mut closed = self.closed.lock().await;
+        if closed.len() == CLOSED_HISTORY {
+            closed.pop_front();
+        }
+        closed.push_back(info);
+    }
+
+    /// Records a request and asks the policy whether to forward it.
+    fn check(&self, session: &Session, request: HostRequest) -> Verdict {
+        let verdict = match &self.policy {
+            Some(policy) => policy.check(&session.client, &request),
+            None => Verdict::Allow,
+        };
+        let allowed = matches!(verdict, Verdict::Allow);
+        tracing::debug!(id = session.id, ?request, allowed, "adb request");
+
+        {
+            let mut inspected = session.inspected.lock().unwrap();
+            inspected.serial = request.serial.clone();
+            inspected.services.push(request.service.clone());
+        }
+        self.registry.publish(DeviceEvent::AdbRequest {
+            session: session.id,
+            request,
+            allowed,
+        });
+
+        verdict
+    }
+
+    pub async fn handle_client(self, ws: WebSocket, client: ClientInfo) {
+        let session = self.open(client).await;
+        let span = tracing::info_span!("adb_session", id = session.id);
+        async {
+            tracing::debug!(origin = ?session.client.origin, "adb session opened");
+            let reason = self.relay(ws, &session).await;
+            self.close(&session, reason).await;
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Writes each binary frame straight to ADB, so a slow ADB server stops
+    /// the WebSocket reads instead of queueing frames in memory.
+    async fn pump_ws_to_adb(
+        &self,
+        mut ws_reader: impl Stream<Item = Result<Message, axum::Error>> + Unpin,
+        mut adb_writer: impl AsyncWrite + Unpin,
+        session: &Session,
+        refusal: mpsc::Sender<Bytes>,
+        cancel: CancellationToken,
+    ) -> Option<CloseReason> {
+        let mut inspector = self.policy.as_ref().map(|_| Inspector::default());
+
+        let reason = 'pump: loop {
+            let message = tokio::select! {
+                _ = cancel.cancelled() => break None,
+                message = ws_reader.next() => message,
+            };
+
+            let packet = match message {
+                Some(Ok(Message::Binary(packet))) => packet,
+                Some(Ok(Message::Close(_))) | None => break Some(CloseReason::ClientClosed),
+                Some(Err(err)) => {
+                    break Some(CloseReason::ClientError {
+                        message: err.to_string(),
+                    })
+                }
+                // Ignore other message types
+                Some(Ok(_)) => continue,
+            };
+
+            let chunks = match &mut inspector {
+                Some(inspector) => inspector.feed(packet),
+                None => vec![Chunk::Raw(packet)],
+            };
+            for chunk in chunks {
+                let data = match chunk {
+                    Chunk::Raw(data) => data,
+                    Chunk::Invalid { message, data } => {
+                        if self
+                            .policy
+                            .as_ref()
+                            .is_some_and(|policy| policy.fails_closed())
+                        {
+                            let _ = refusal.try_send(fail_reply(&message));
+                            return Some(CloseReason::Malformed { message });
+                        }
+                        data
+                    }
+                    Chunk::Request(request, data) => {
+                        let service = request.service.clone();
+                        if let Verdict::Deny(message) = self.check(session, request) {
+                            // Answer in place of the server, the ADB side is
+                            // closed once the reply has gone out.
+                            let _ = refusal.try_send(fail_reply(&message));
+                            return Some(CloseReason::Denied { service, message });
+                        }
+                        data
+                    }
+                };
+
+                let result = tokio::select! {
+                    _ = cancel.cancelled() => break 'pump None,
+                    result = adb_writer.write_all(&data) => result,
+                };
+                if let Err(err) = result {
+                    break 'pump Some(CloseReason::AdbError {
+                        message: err.to_string(),
+                    });
+                }
+                session.ws_to_adb.record(data.len());
+                metrics().relayed("adb", "to_device", data.len());
+            }
+        };
+
+        cancel.cancel();
+        let _ = adb_writer.shutdown().await;
+        reason
+    }
+
+    async fn relay(&self, ws: WebSocket, session: &Session) -> CloseReason {
+        let (mut ws_writer, ws_reader) = ws.split();
+        let adb_stream = match adb::connect_or_start(&self.config.get().adb).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                self.shutdown.close(&mut ws_writer).await;
+                return CloseReason::AdbUnavailable {
+                    message: err.to_string(),
+                };
+            }
+        };
+        // Reduce latency for small writes.
+        let _ = adb_stream.set_nodelay(true);
+        let (adb_reader, adb_writer) = adb_stream.into_split();
+
+        // Whichever direction stops first takes the other one down with it.
+        let cancel = self.shutdown.child_token();
+        // Carries the FAIL for a refused request to the WebSocket writer.
+        let (refusal, refusal_receiver) = mpsc::channel(1);
+        let (ws_to_adb, adb_to_ws) = tokio::join!(
+            self.pump_ws_to_adb(ws_reader, adb_writer, session, refusal, cancel.clone()),
+            pump_adb_to_ws(
+                adb_reader,
+                ws_writer,
+                session,
+                refusal_receiver,
+                cancel,
+                &self.shutdown
+            ),
+        );
+
+        match ws_to_adb.or(adb_to_ws) {
+            Some(reason) => reason,
+            None if self.shutdown.is_shutting_down() => CloseReason::Shutdown,
+            None => CloseReason::ClientClosed,
+        }
+    }
+}
+
+/// Forwards ADB output one read at a time through a reused buffer.
+async fn pump_adb_to_ws(
+    mut adb_reader: OwnedReadHalf,
+    mut ws_writer: SplitSink<WebSocket, Message>,
+    session: &Session,
+    mut refusal: mpsc::Receiver<Bytes>,
+    cancel: CancellationToken,
+    shutdown: &Shutdown,
+) -> Option<CloseReason> {
+    let mut buf = BytesMut::with_capacity(READ_BUFFER);
+
+    let reason = loop {
+        // Reclaims the previous allocation once the last frame has been sent.
+        buf.reserve(READ_BUFFER);
+        let result = tokio::select! {
+            biased;
+            Some(reply) = refusal.recv() => {
+                let _ = ws_writer.send(Message::binary(reply)).await;
+                break None;
+            }
+            _ = cancel.cancelled() => break None,
+            result = adb_reader.read_buf(&mut buf) => result,
+        };
+
+        let n = match result {
+            Ok(0) => break Some(CloseReason::AdbClosed),
+            Ok(n) => n,
+            Err(err) => {
+                break Some(CloseReason::AdbError {
+                    message: err.to_string(),
+                })
+            }
+        };
+
+        let packet = buf.split().freeze();
+        let result = tokio::select! {
+            _ = cancel.cancelled() => break None,
+            result = ws_writer.send(Message::binary(packet)) => result,
+        };
+        if let Err(err) = result {
+            break Some(CloseReason::ClientError {
+                message: err.to_string(),
+            });
+        }
+        session.adb_to_ws.record(n);
+        metrics().relayed("adb", "to_client", n);
+    };
+
+    cancel.cancel();
+    shutdown.close(&mut ws_writer).await;
+    reason
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+    use crate::adb_policy::{AccessPolicy, Action, Rule};
+
+    fn sessions(deny: &[&str]) -> AdbSessions {
+        let missing = std::env::temp_dir().join("tango-adb-relay-test-missing.toml");
+        let config =
+            ConfigStore::load(["--config".to_string(), missing.display().to_string()]).unwrap();
+        let policy = AccessPolicy {
+            default: Action::Deny,
+            rules: vec![Rule {
+                origins: vec!["https://app.tangoapp.dev".to_string()],
+                deny: deny.iter().map(|prefix| prefix.to_string()).collect(),
+                ..Rule::default()
+            }],
+        };
+        AdbSessions::new(
+            DeviceRegistry::new(),
+            config,
+            Some(Arc::new(policy)),
+            Shutdown::new(),
+        )
+    }
+
+    fn request(service: &str) -> Result<Message, axum::Error> {
+        Ok(Message::binary(format!("{:04x}{}", service.len(), service)))
+    }
+
+    /// Pumps `frames` through the relay, returning what reached ADB, the
+    /// reply sent in its place and why the session ended.
+    async fn pump(
+        sessions: &AdbSessions,
+        frames: Vec<Result<Message, axum::Error>>,
+    ) -> (Vec<u8>, Option<Bytes>, Option<CloseReason>) {
+        let session = sessions
+            .open(ClientInfo {
+                origin: Some("https://app.tangoapp.dev".to_string()),
+                token: None,
+            })
+            .await;
+        let mut adb = Vec::new();
+        let (refusal, mut refused) = mpsc::channel(1);
+        let reason = sessions
+            .pump_ws_to_adb(
+                stream::iter(frames),
+                &mut adb,
+                &session,
+                refusal,
+                CancellationToken::new(),
+            )
+            .await;
+        (adb, refused.try_recv().ok(), reason)
+    }
+
+    #[tokio::test]
+    async fn forwards_allowed_requests() {
+        let sessions = sessions(&["shell:"]);
+        let (adb, refused, reason) = pump(
+            &sessions,
+            vec![request("host:transport:R58M123ABC"), request("sync:")],
+        )
+        .await;
+        assert_eq!(adb, b"0019host:transport:R58M123ABC0005sync:");
+        assert!(refused.is_none());
+        assert!(matches!(reason, Some(CloseReason::ClientClosed)));
+    }
+
+    #[tokio::test]
+    async fn refuses_every_form_of_a_denied_service() {
+        let sessions = sessions(&["shell:"]);
+        for service in ["shell:ls", "shell,v2,raw:ls", "exec:ls"] {
+            let (adb, refused, reason) = pump(
+                &sessions,
+                vec![request("host:transport:R58M123ABC"), request(service)],
+            )
+            .await;
+            assert_eq!(adb, b"0019host:transport:R58M123ABC", "{}", service);
+            assert!(refused.is_some_and(|reply| reply.starts_with(b"FAIL")));
+            assert!(
+                matches!(&reason, Some(CloseReason::Denied { service: denied, .. }) if denied == service),
+                "{:?}",
+                reason
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_an_over_long_request() {
+        let sessions = sessions(&[]);
+        let service = format!("shell:echo {}", "a".repeat(1024));
+        let (adb, refused, reason) = pump(&sessions, vec![request(&service)]).await;
+        assert!(adb.is_empty());
+        assert!(refused.is_some());
+        assert!(matches!(reason, Some(CloseReason::Malformed { .. })));
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+/// Exponential reconnect delay, doubling from `min` up to `max`.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Counts a failed attempt and returns how long to wait before the next.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .min
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt += 1;
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
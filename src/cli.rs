@@ -0,0 +1,825 @@
+use std::{
+    env,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream, SinkExt, StreamExt};
+use ipnet::Ipv4Net;
+use reqwest::Url;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::{header, HeaderValue},
+    Error as WsError, Message,
+};
+
+use crate::{
+    config::ConfigStore,
+    ios_lan_scanner::{local_ipv4, IosLanScanner},
+    ios_stream::Quality,
+    registry::DeviceRegistry,
+    zxtouch,
+};
+
+const USAGE: &str = "\
+Usage: tango-bridge [options] [command] [options]
+
+Runs the bridge when no command is given. Commands use the bridge running
+on this machine, or work on their own when there is none.
+
+Commands:
+  devices                       List devices
+  scan [--cidr <net>] [--all]   Probe a network for the iOS agent and show why
+                                each address did or didn't answer
+  stream <id> -o <file>         Save the MPEG-TS stream of a device, `-o -` for
+        [--quality full|eco]    stdout, until interrupted
+  tap <id> <x> <y>              Tap a point on the screen of a device
+  status                        Show the running bridge
+
+Options:
+  --json                        Print JSON instead of a table
+  --url <url>                   Bridge to use, http://127.0.0.1:<server.port>
+                                by default
+  --token <token>               Pairing token for a bridge on another machine,
+                                TANGO_BRIDGE_TOKEN by default
+  --local                       Don't use a running bridge
+  --config <path>, --set <key>=<value>
+                                As for the bridge
+";
+
+/// How long to wait for a running bridge to answer.
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Time between the down and up events of a tap.
+const TAP_HOLD: Duration = Duration::from_millis(50);
+
+/// Largest network `scan` sweeps, a /16.
+const MAX_SCAN_HOSTS: u64 = 1 << 16;
+
+const ENV_TOKEN: &str = "TANGO_BRIDGE_TOKEN";
+
+/// Options that may come before the command. Any other option there means
+/// the bridge itself should run.
+const GLOBAL_FLAGS: [&str; 2] = ["--json", "--local"];
+const GLOBAL_OPTIONS: [&str; 4] = ["--url", "--token", "--config", "--set"];
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Devices,
+    Scan {
+        cidr: Option<Ipv4Net>,
+        all: bool,
+    },
+    Stream {
+        id: String,
+        output: String,
+        quality: Quality,
+    },
+    Tap {
+        id: String,
+        x: f64,
+        y: f64,
+    },
+    Status,
+    Help,
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    command: Command,
+    json: bool,
+    url: Option<Url>,
+    token: Option<String>,
+    local: bool,
+}
+
+impl Cli {
+    /// `None` when the arguments don't name a command, so the bridge itself
+    /// should run.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Option<Result<Self, String>> {
+        let mut args = args.into_iter();
+        let mut global = Vec::new();
+        let name = loop {
+            let arg = args.next()?;
+            if !arg.starts_with('-') {
+                break arg;
+            }
+            let option = arg
+                .split_once('=')
+                .map_or(arg.as_str(), |(option, _)| option);
+            if GLOBAL_OPTIONS.contains(&option) {
+                let inline = option.len() < arg.len();
+                global.push(arg);
+                if !inline {
+                    global.extend(args.next());
+                }
+            } else if GLOBAL_FLAGS.contains(&option) {
+                global.push(arg);
+            } else {
+                return None;
+            }
+        };
+        Some(Self::parse_command(&name, global.into_iter().chain(args)))
+    }
+
+    fn parse_command(name: &str, args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut cli = Cli {
+            command: Command::Help,
+            json: false,
+            url: None,
+            token: env::var(ENV_TOKEN).ok().filter(|token| !token.is_empty()),
+            local: false,
+        };
+        let mut positional = Vec::new();
+        let mut cidr = None;
+        let mut all = false;
+        let mut output = None;
+        let mut quality = Quality::Full;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (option, inline) = match arg.split_once('=') {
+                Some((option, value)) if option.starts_with("--") => {
+                    (option.to_string(), Some(value.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", option))
+            };
+
+            match option.as_str() {
+                "--json" => cli.json = true,
+                "--local" => cli.local = true,
+                "--all" => all = true,
+                "--url" => {
+                    let value = value()?;
+                    cli.url = Some(
+                        Url::parse(&value).map_err(|err| format!("--url `{}`: {}", value, err))?,
+                    );
+                }
+                "--token" => cli.token = Some(value()?),
+                "--cidr" => {
+                    let value = value()?;
+                    cidr = Some(value.parse().map_err(|_| {
+                        format!(
+                            "--cidr expects a network like 192.168.1.0/24, got `{}`",
+                            value
+                        )
+                    })?);
+                }
+                "-o" | "--output" => output = Some(value()?),
+                "--quality" => {
+                    quality = match value()?.as_str() {
+                        "full" => Quality::Full,
+                        "eco" => Quality::Eco,
+                        other => {
+                            return Err(format!("--quality expects full or eco, got `{}`", other))
+                        }
+                    }
+                }
+                // Read by the config loader.
+                "--config" | "--set" => {
+                    value()?;
+                }
+                "-h" | "--help" => return Ok(cli),
+                _ if option.starts_with('-') => return Err(format!("unknown option {}", option)),
+                _ => positional.push(arg),
+            }
+        }
+
+        let expect = |count: usize, usage: &str| {
+            if positional.len() == count {
+                Ok(())
+            } else {
+                Err(format!("usage: tango-bridge {}", usage))
+            }
+        };
+        let coordinate = |value: &String| {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite() && *value >= 0.0)
+                .ok_or_else(|| format!("`{}` is not a screen coordinate", value))
+        };
+
+        cli.command = match name {
+            "devices" => expect(0, "devices").map(|_| Command::Devices)?,
+            "scan" => expect(0, "scan [--cidr <net>]").map(|_| Command::Scan { cidr, all })?,
+            "stream" => {
+                expect(1, "stream <id> -o <file>")?;
+                Command::Stream {
+                    id: positional[0].clone(),
+                    output: output.ok_or("stream needs -o <file>, or -o - for stdout")?,
+                    quality,
+                }
+            }
+            "tap" => {
+                expect(3, "tap <id> <x> <y>")?;
+                Command::Tap {
+                    id: positional[0].clone(),
+                    x: coordinate(&positional[1])?,
+                    y: coordinate(&positional[2])?,
+                }
+            }
+            "status" => expect(0, "status").map(|_| Command::Status)?,
+            "help" => Command::Help,
+            other => {
+                return Err(format!(
+                    "unknown command `{}`, see tango-bridge help",
+                    other
+                ))
+            }
+        };
+        Ok(cli)
+    }
+}
+
+/// Runs a command and returns the exit code.
+pub async fn run(cli: Cli) -> i32 {
+    if let Command::Help = cli.command {
+        print!("{}", USAGE);
+        return 0;
+    }
+
+    let config = match ConfigStore::load(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid configuration:\n{}", err);
+            return 2;
+        }
+    };
+    let url = cli.url.clone().unwrap_or_else(|| {
+        let port = config.get().server.port;
+        Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap()
+    });
+    let bridge = match cli.local {
+        true => None,
+        false => ping(&url)
+            .await
+            .then(|| Bridge::new(url.clone(), cli.token.clone())),
+    };
+
+    let result = match (&cli.command, &bridge) {
+        (Command::Status, Some(bridge)) => status(bridge, cli.json).await,
+        (Command::Status, None) => Err(format!("no bridge is running at {}", url)),
+        (Command::Devices, _) => devices(bridge.as_ref(), &config, cli.json).await,
+        (Command::Scan { cidr, all }, _) => {
+            scan(bridge.as_ref(), &config, *cidr, *all, cli.json).await
+        }
+        (
+            Command::Stream {
+                id,
+                output,
+                quality,
+            },
+            bridge,
+        ) => match open_output(output).await {
+            Ok(mut output) => match bridge {
+                Some(bridge) => bridge.stream(id, *quality, &mut output).await,
+                None => stream_local(&config, id, *quality, &mut output).await,
+            },
+            Err(err) => Err(err),
+        },
+        (Command::Tap { id, x, y }, Some(bridge)) => bridge.tap(id, *x, *y).await,
+        (Command::Tap { id, x, y }, None) => tap_local(&config, id, *x, *y).await,
+        (Command::Help, _) => unreachable!(),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            1
+        }
+    }
+}
+
+async fn ping(url: &Url) -> bool {
+    let Ok(url) = url.join("/bridge/ping") else {
+        return false;
+    };
+    let request = reqwest::Client::new().get(url).timeout(PING_TIMEOUT).send();
+    matches!(request.await, Ok(response) if response.status().is_success())
+}
+
+/// A running bridge, reached over its HTTP API. Loopback clients without an
+/// `Origin` don't need a pairing token, others send `--token`.
+struct Bridge {
+    url: Url,
+    token: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Bridge {
+    fn new(url: Url, token: Option<String>) -> Self {
+        Self {
+            url,
+            token,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn endpoint(&self, segments: &[&str]) -> Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("bridge URL can't be a base")
+            .clear()
+            .extend(segments);
+        url
+    }
+
+    async fn get(&self, segments: &[&str]) -> Result<Value, String> {
+        let mut request = self.client.get(self.endpoint(segments));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        let status = response.status();
+        let text = response.text().await.map_err(|err| err.to_string())?;
+        if !status.is_success() {
+            return Err(format!("{}: {}", status, text));
+        }
+        serde_json::from_str(&text).map_err(|err| err.to_string())
+    }
+
+    async fn websocket(
+        &self,
+        segments: &[&str],
+        query: Option<&str>,
+    ) -> Result<
+        tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
+        String,
+    > {
+        let mut url = self.endpoint(segments);
+        url.set_query(query);
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        let _ = url.set_scheme(scheme);
+        let mut request = url
+            .as_str()
+            .into_client_request()
+            .map_err(|err| err.to_string())?;
+        if let Some(token) = &self.token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| "--token isn't a valid header value".to_string())?;
+            request.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+        let (socket, _) =
+            tokio_tungstenite::connect_async(request)
+                .await
+                .map_err(|err| match err {
+                    WsError::Http(response) if response.status() == 404 => {
+                        format!("the bridge has no device `{}`", segments[1])
+                    }
+                    err => err.to_string(),
+                })?;
+        Ok(socket)
+    }
+
+    async fn stream(
+        &self,
+        id: &str,
+        quality: Quality,
+        output: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<(), String> {
+        let query = match quality {
+            Quality::Full => "quality=full",
+            Quality::Eco => "quality=eco",
+        };
+        let mut socket = self.websocket(&["ios", id, "stream"], Some(query)).await?;
+        let started = Instant::now();
+        let mut bytes = 0;
+
+        loop {
+            let message = tokio::select! {
+                _ = tokio::signal::ctrl_c() => break,
+                message = socket.next() => message,
+            };
+            match message {
+                Some(Ok(Message::Binary(data))) => {
+                    output
+                        .write_all(&data)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                    bytes += data.len();
+                }
+                // Reconnects and quality switches.
+                Some(Ok(Message::Text(text))) => eprintln!("{}", text),
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.to_string()),
+            }
+        }
+
+        let _ = socket.close(None).await;
+        output.flush().await.map_err(|err| err.to_string())?;
+        eprintln!(
+            "{} bytes in {:.1} s",
+            bytes,
+            started.elapsed().as_secs_f64()
+        );
+        Ok(())
+    }
+
+    async fn tap(&self, id: &str, x: f64, y: f64) -> Result<(), String> {
+        let mut socket = self.websocket(&["ios", id, "zxtouch"], None).await?;
+
+        // Commands sent before the upstream is connected are queued, but the
+        // session ends when this client leaves, so wait for it.
+        let connected = async {
+            while let Some(message) = socket.next().await {
+                match message.map_err(|err| err.to_string())? {
+                    Message::Text(text) => {
+                        let frame: Value = serde_json::from_str(&text).unwrap_or_default();
+                        if frame["state"] == "connected" {
+                            return Ok(());
+                        }
+                    }
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            Err("the bridge closed the connection".to_string())
+        };
+        tokio::time::timeout(Duration::from_secs(10), connected)
+            .await
+            .map_err(|_| "timed out connecting to the device".to_string())??;
+
+        let [down, up] = zxtouch::tap(x, y);
+        socket
+            .send(Message::text(down))
+            .await
+            .map_err(|err| err.to_string())?;
+        tokio::time::sleep(TAP_HOLD).await;
+        socket
+            .send(Message::text(up))
+            .await
+            .map_err(|err| err.to_string())?;
+        tokio::time::sleep(TAP_HOLD).await;
+        let _ = socket.close(None).await;
+        Ok(())
+    }
+}
+
+async fn status(bridge: &Bridge, json: bool) -> Result<(), String> {
+    let status = bridge.get(&["bridge", "status"]).await?;
+    if json {
+        return print_json(&status);
+    }
+
+    println!("running at {}", bridge.url);
+    println!("version    {}", status["version"].as_str().unwrap_or("?"));
+    match status["tls"].as_object() {
+        Some(tls) => println!(
+            "tls        port {}, certificate expires in {} days",
+            tls["port"], tls["expires_in_days"]
+        ),
+        None => println!("tls        off"),
+    }
+    Ok(())
+}
+
+async fn devices(bridge: Option<&Bridge>, config: &ConfigStore, json: bool) -> Result<(), String> {
+    let devices = match bridge {
+        Some(bridge) => bridge.get(&["devices"]).await?,
+        None => {
+            let base = local_ipv4().map_err(|err| err.to_string())?;
+            eprintln!("no bridge running, scanning {}/24", base);
+            let registry = DeviceRegistry::new();
+            let scanner = IosLanScanner::new(config.clone());
+            let octets = base.octets();
+            let devices = scanner
+                .scan_subnet(Ipv4Addr::new(octets[0], octets[1], octets[2], 0))
+                .await;
+            registry.update_ios_devices(devices).await;
+            serde_json::to_value(registry.list_unified_devices().await).unwrap()
+        }
+    };
+    if json {
+        return print_json(&devices);
+    }
+
+    let rows = devices
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|device| ["id", "platform", "status", "display_name"].map(|key| text(&device[key])))
+        .collect::<Vec<_>>();
+    print_table(["ID", "PLATFORM", "STATUS", "NAME"], rows);
+    Ok(())
+}
+
+async fn scan(
+    bridge: Option<&Bridge>,
+    config: &ConfigStore,
+    cidr: Option<Ipv4Net>,
+    all: bool,
+    json: bool,
+) -> Result<(), String> {
+    let cidr = match cidr {
+        Some(cidr) => cidr,
+        None => {
+            let ip = local_ipv4().map_err(|err| err.to_string())?;
+            Ipv4Net::new(ip, 24).unwrap().trunc()
+        }
+    };
+    let count = cidr.hosts().count() as u64;
+    if count > MAX_SCAN_HOSTS {
+        return Err(format!(
+            "{} has {} addresses, scan at most a /16",
+            cidr, count
+        ));
+    }
+
+    let concurrency = config.get().ios.scan_concurrency;
+    let scanner = IosLanScanner::new(config.clone());
+    eprintln!(
+        "probing {} addresses in {}{}",
+        count,
+        cidr,
+        if bridge.is_some() {
+            " through the bridge"
+        } else {
+            ""
+        }
+    );
+
+    let mut reports = stream::iter(cidr.hosts())
+        .map(|ip| {
+            let scanner = &scanner;
+            async move {
+                match bridge {
+                    Some(bridge) => bridge
+                        .get(&["ios", "probe", &ip.to_string()])
+                        .await
+                        .unwrap_or_else(|err| {
+                            serde_json::json!({ "ip": ip, "error": { "kind": "bridge", "message": err } })
+                        }),
+                    None => serde_json::to_value(scanner.probe(ip).await).unwrap(),
+                }
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    reports.sort_by_key(|report| {
+        report["ip"]
+            .as_str()
+            .and_then(|ip| ip.parse::<Ipv4Addr>().ok())
+    });
+
+    let found = reports
+        .iter()
+        .filter(|report| report["device"].is_object())
+        .count();
+    if !all {
+        // Nothing listening isn't worth a line.
+        reports.retain(|report| {
+            !matches!(
+                report["error"]["kind"].as_str(),
+                Some("connect_refused" | "connect_timeout")
+            )
+        });
+    }
+    if json {
+        return print_json(&Value::Array(reports));
+    }
+
+    let ms = |value: &Value| value.as_u64().map(|ms| ms.to_string()).unwrap_or_default();
+    let rows = reports
+        .iter()
+        .map(|report| {
+            let result = match report["device"].as_object() {
+                Some(device) => text(&device["display_name"]),
+                None => {
+                    let error = &report["error"];
+                    match error["message"].as_str() {
+                        Some(message) => format!("{}: {}", text(&error["kind"]), message),
+                        None => text(&error["kind"]),
+                    }
+                }
+            };
+            [
+                text(&report["ip"]),
+                result,
+                ms(&report["connect_ms"]),
+                ms(&report["first_byte_ms"]),
+                ms(&report["total_ms"]),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(
+        ["IP", "RESULT", "CONNECT MS", "FIRST BYTE MS", "TOTAL MS"],
+        rows,
+    );
+    eprintln!("{} devices found", found);
+    Ok(())
+}
+
+/// Without a bridge ids can't be looked up, but iOS ids are `ios:<ip>`.
+fn device_ip(id: &str) -> Result<Ipv4Addr, String> {
+    id.strip_prefix("ios:")
+        .unwrap_or(id)
+        .parse()
+        .map_err(|_| format!("no bridge running, `{}` needs to be an ios:<ip> id", id))
+}
+
+async fn stream_local(
+    config: &ConfigStore,
+    id: &str,
+    quality: Quality,
+    output: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), String> {
+    let ip = device_ip(id)?;
+    let port = config.get().ios.stream_port(quality);
+    let mut upstream = TcpStream::connect((ip, port))
+        .await
+        .map_err(|err| format!("{}:{}: {}", ip, port, err))?;
+    let started = Instant::now();
+    let mut bytes = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            read = upstream.read(&mut buf) => read.map_err(|err| err.to_string())?,
+        };
+        if read == 0 {
+            break;
+        }
+        output
+            .write_all(&buf[..read])
+            .await
+            .map_err(|err| err.to_string())?;
+        bytes += read;
+    }
+
+    output.flush().await.map_err(|err| err.to_string())?;
+    eprintln!(
+        "{} bytes in {:.1} s",
+        bytes,
+        started.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+async fn tap_local(config: &ConfigStore, id: &str, x: f64, y: f64) -> Result<(), String> {
+    let ip = device_ip(id)?;
+    let report = IosLanScanner::new(config.clone()).probe(ip).await;
+    let device = match (report.device, report.error) {
+        (Some(device), _) => device,
+        (None, error) => return Err(format!("{} didn't answer: {:?}", ip, error)),
+    };
+
+    let port = device.status.zxtouch.port;
+    let mut stream = TcpStream::connect((ip, port))
+        .await
+        .map_err(|err| format!("{}:{}: {}", ip, port, err))?;
+    let [down, up] = zxtouch::tap(x, y);
+    for line in [down, up] {
+        stream
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|err| err.to_string())?;
+        tokio::time::sleep(TAP_HOLD).await;
+    }
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+async fn open_output(path: &str) -> Result<Box<dyn AsyncWrite + Unpin + Send>, String> {
+    if path == "-" {
+        return Ok(Box::new(tokio::io::stdout()));
+    }
+    let file = tokio::fs::File::create(path)
+        .await
+        .map_err(|err| format!("{}: {}", path, err))?;
+    Ok(Box::new(file))
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn print_json(value: &Value) -> Result<(), String> {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+    Ok(())
+}
+
+fn print_table<const N: usize>(headers: [&str; N], rows: Vec<[String; N]>) {
+    let mut widths = headers.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: [&str; N]| {
+        let line = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    line(headers);
+    for row in &rows {
+        line(row.each_ref().map(String::as_str));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Option<Result<Cli, String>> {
+        Cli::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn runs_the_bridge_without_a_command() {
+        assert!(parse(&[]).is_none());
+        assert!(parse(&["--headless"]).is_none());
+        assert!(parse(&["--config", "bridge.toml", "--lan"]).is_none());
+        assert!(parse(&["--set", "server.port=15137"]).is_none());
+        // Bridge options before a word are the bridge's.
+        assert!(parse(&["--allow", "10.0.0.0/8", "devices"]).is_none());
+    }
+
+    #[test]
+    fn takes_global_options_before_the_command() {
+        let cli = parse(&["--config", "bridge.toml", "devices"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(cli.command, Command::Devices);
+
+        let cli = parse(&[
+            "--json",
+            "--url=http://10.0.0.2:15037",
+            "--token",
+            "abc",
+            "--set",
+            "server.port=1",
+            "status",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(cli.command, Command::Status);
+        assert!(cli.json);
+        assert_eq!(cli.url.unwrap().as_str(), "http://10.0.0.2:15037/");
+        assert_eq!(cli.token.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn parses_command_options() {
+        let cli = parse(&["scan", "--cidr", "192.168.1.0/24", "--all", "--local"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Scan {
+                cidr: Some("192.168.1.0/24".parse().unwrap()),
+                all: true
+            }
+        );
+        assert!(cli.local);
+
+        let cli = parse(&["tap", "ios:192.168.1.20", "100", "200.5", "--token=abc"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Tap {
+                id: "ios:192.168.1.20".to_string(),
+                x: 100.0,
+                y: 200.5
+            }
+        );
+        assert_eq!(cli.token.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert!(parse(&["frobnicate"]).unwrap().is_err());
+        assert!(parse(&["tap", "ios:192.168.1.20", "100"]).unwrap().is_err());
+        assert!(parse(&["tap", "ios:192.168.1.20", "-1", "2"])
+            .unwrap()
+            .is_err());
+        assert!(parse(&["stream", "ios:192.168.1.20"]).unwrap().is_err());
+        assert!(parse(&["devices", "--frobnicate"]).unwrap().is_err());
+        assert!(parse(&["--json", "devices", "--url"]).unwrap().is_err());
+    }
+}
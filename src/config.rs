@@ -0,0 +1,1110 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime},
+};
+
+use ipnet::IpNet;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::EnvFilter;
+
+use crate::{ios_stream::Quality, listen, origins, paths, proxy::ForwardedPolicy, tls, web_ui};
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Environment variables are `TANGO_BRIDGE_<SECTION>_<KEY>`, e.g.
+/// `TANGO_BRIDGE_IOS_SCAN_INTERVAL_MS=5000`.
+const ENV_PREFIX: &str = "TANGO_BRIDGE_";
+const ENV_CONFIG: &str = "TANGO_BRIDGE_CONFIG";
+
+const ARG_CONFIG: &str = "--config";
+const ARG_SET: &str = "--set";
+const ARG_LISTEN: &str = "--listen";
+const ARG_LAN: &str = "--lan";
+const ARG_ALLOW: &str = "--allow";
+const ARG_ALLOW_ORIGIN: &str = "--allow-origin";
+const ARG_TLS: &str = "--tls";
+const ARG_TLS_PORT: &str = "--tls-port";
+const ARG_TLS_CERT: &str = "--tls-cert";
+const ARG_TLS_KEY: &str = "--tls-key";
+const ARG_NO_ADB_INSPECT: &str = "--no-adb-inspect";
+
+/// How often the file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+const MAX_SCAN_CONCURRENCY: usize = 1024;
+
+/// Sections and keys `PUT /bridge/config` can't change. They pick who may
+/// connect, which binary is run, and which files are served, written or
+/// trusted, so a paired page could use them to read any file.
+const FILE_ONLY: [&str; 9] = [
+    "server",
+    "adb",
+    "tls.cert",
+    "tls.key",
+    "web.offline",
+    "web.bundle",
+    "log.dir",
+    "proxy.cache_dir",
+    "proxy.upstream",
+];
+
+/// Everything read from `config.toml`. Missing keys take their defaults.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub proxy: ProxyConfig,
+    pub ios: IosConfig,
+    pub adb: AdbConfig,
+    pub log: LogConfig,
+    pub web: WebConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Addresses to listen on, loopback (or every address with `lan`) when empty.
+    pub listen: Vec<IpAddr>,
+    /// Allows listening on and connecting from non-loopback addresses.
+    pub lan: bool,
+    /// Networks LAN clients may connect from, private networks when empty.
+    pub allow: Vec<IpNet>,
+    /// Extra browser origins, e.g. `https://*.tangoapp.dev` or `*` for any.
+    pub allow_origins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: listen::DEFAULT_PORT,
+            listen: Vec::new(),
+            lan: false,
+            allow: Vec::new(),
+            allow_origins: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// A certificate chain and key in PEM, issued by the local CA when unset.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: tls::DEFAULT_TLS_PORT,
+            cert: None,
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Where requests that don't match a bridge route are forwarded to.
+    pub upstream: String,
+    /// Keeps upstream responses on disk, so the web UI loads offline.
+    pub cache: bool,
+    /// Cache directory, the platform one when unset.
+    pub cache_dir: Option<PathBuf>,
+    /// The least recently stored responses are dropped past this size.
+    pub cache_max_mb: u64,
+    /// What the upstream is told about the client in `X-Forwarded-*`.
+    pub forwarded: ForwardedPolicy,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            upstream: "https://tangoapp.dev".to_string(),
+            cache: true,
+            cache_dir: None,
+            cache_max_mb: 256,
+            forwarded: ForwardedPolicy::Set,
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub fn cache_dir(&self) -> PathBuf {
+        self.cache_dir
+            .clone()
+            .unwrap_or_else(|| paths::cache_dir().join("web"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IosConfig {
+    pub scan_interval_ms: u64,
+    pub scan_concurrency: usize,
+    pub connect_timeout_ms: u64,
+    pub first_byte_timeout_ms: u64,
+    pub probe_timeout_ms: u64,
+    /// Port of the ZXTouch agent the scanner probes.
+    pub zxtouch_port: u16,
+    pub stream_port: u16,
+    pub eco_stream_port: u16,
+}
+
+impl Default for IosConfig {
+    fn default() -> Self {
+        Self {
+            scan_interval_ms: 10_000,
+            scan_concurrency: 64,
+            connect_timeout_ms: 600,
+            first_byte_timeout_ms: 600,
+            probe_timeout_ms: 3_000,
+            zxtouch_port: 6000,
+            stream_port: 7001,
+            eco_stream_port: 7002,
+        }
+    }
+}
+
+impl IosConfig {
+    pub fn scan_interval(&self) -> Duration {
+        Duration::from_millis(self.scan_interval_ms)
+    }
+
+    pub fn stream_port(&self, quality: Quality) -> u16 {
+        match quality {
+            Quality::Full => self.stream_port,
+            Quality::Eco => self.eco_stream_port,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdbConfig {
+    /// Address of the ADB server, started on its port when nothing answers.
+    pub server: SocketAddr,
+    /// `adb` executable to start instead of the system or bundled one.
+    pub executable: Option<PathBuf>,
+    /// Parses the host protocol of sessions to show and police what they use.
+    pub inspect: bool,
+}
+
+impl Default for AdbConfig {
+    fn default() -> Self {
+        Self {
+            server: SocketAddr::from((Ipv4Addr::LOCALHOST, 5037)),
+            executable: None,
+            inspect: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// A filter like `RUST_LOG`, e.g. `info,tango_bridge::adb_relay=debug`.
+    pub level: String,
+    /// Log directory, the platform one when unset.
+    pub dir: Option<PathBuf>,
+    /// Daily files kept before the oldest is deleted.
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            dir: None,
+            max_files: 7,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn dir(&self) -> PathBuf {
+        self.dir.clone().unwrap_or_else(paths::log_dir)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    /// Serves the web UI from `bundle` instead of `proxy.upstream`.
+    pub offline: bool,
+    /// A directory or `.zip` of the built web UI, the embedded one when unset.
+    pub bundle: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConfigError {
+    Io { message: String },
+    Parse { message: String },
+    Invalid { errors: Vec<String> },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { message } | ConfigError::Parse { message } => f.write_str(message),
+            ConfigError::Invalid { errors } => f.write_str(&errors.join("\n")),
+        }
+    }
+}
+
+impl Config {
+    /// Checks what the types alone don't, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        let server = &self.server;
+        if server.port == 0 {
+            errors.push("server.port must not be 0".to_string());
+        }
+        if !server.lan {
+            for ip in server.listen.iter().filter(|ip| !ip.is_loopback()) {
+                errors.push(format!(
+                    "server.listen: {} is not a loopback address, set server.lan = true to listen on the LAN",
+                    ip
+                ));
+            }
+        }
+        for origin in &server.allow_origins {
+            if !origins::is_valid_pattern(origin) {
+                errors.push(format!(
+                    "server.allow_origins: `{}` is not an origin like https://app.example.com",
+                    origin
+                ));
+            }
+        }
+
+        let tls = &self.tls;
+        if tls.enabled && (tls.port == 0 || tls.port == server.port) {
+            errors.push("tls.port must not be 0 or the same as server.port".to_string());
+        }
+        if tls.cert.is_some() != tls.key.is_some() {
+            errors.push("tls.cert and tls.key must be set together".to_string());
+        }
+
+        match Url::parse(&self.proxy.upstream) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+            _ => errors.push(format!(
+                "proxy.upstream: `{}` is not an http(s) URL",
+                self.proxy.upstream
+            )),
+        }
+        if self.proxy.cache_max_mb == 0 {
+            errors.push("proxy.cache_max_mb must be greater than 0".to_string());
+        }
+
+        let ios = &self.ios;
+        for (key, value) in [
+            ("scan_interval_ms", ios.scan_interval_ms),
+            ("connect_timeout_ms", ios.connect_timeout_ms),
+            ("first_byte_timeout_ms", ios.first_byte_timeout_ms),
+            ("probe_timeout_ms", ios.probe_timeout_ms),
+        ] {
+            if value == 0 {
+                errors.push(format!("ios.{} must be greater than 0", key));
+            }
+        }
+        if ios.connect_timeout_ms > ios.probe_timeout_ms
+            || ios.first_byte_timeout_ms > ios.probe_timeout_ms
+        {
+            errors.push(
+                "ios.probe_timeout_ms must not be shorter than the connect and first byte timeouts"
+                    .to_string(),
+            );
+        }
+        if !(1..=MAX_SCAN_CONCURRENCY).contains(&ios.scan_concurrency) {
+            errors.push(format!(
+                "ios.scan_concurrency must be between 1 and {}",
+                MAX_SCAN_CONCURRENCY
+            ));
+        }
+        for (key, value) in [
+            ("zxtouch_port", ios.zxtouch_port),
+            ("stream_port", ios.stream_port),
+            ("eco_stream_port", ios.eco_stream_port),
+        ] {
+            if value == 0 {
+                errors.push(format!("ios.{} must not be 0", key));
+            }
+        }
+
+        if self.adb.server.port() == 0 {
+            errors.push("adb.server must have a port".to_string());
+        }
+
+        if let Err(err) = EnvFilter::try_new(&self.log.level) {
+            errors.push(format!("log.level: {}", err));
+        }
+        if self.log.max_files == 0 {
+            errors.push("log.max_files must be greater than 0".to_string());
+        }
+
+        let web = &self.web;
+        match &web.bundle {
+            Some(bundle) if !bundle.exists() => {
+                errors.push(format!("web.bundle: {} does not exist", bundle.display()))
+            }
+            None if web.offline && !web_ui::has_embedded() => errors.push(
+                "web.offline needs web.bundle, this build has no embedded web UI".to_string(),
+            ),
+            _ => {}
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid { errors })
+        }
+    }
+
+    /// Settings that differ from `running` but only apply after a restart.
+    pub fn restart_required(&self, running: &Config) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.server.port != running.server.port {
+            keys.push("server.port");
+        }
+        if self.server.listen != running.server.listen {
+            keys.push("server.listen");
+        }
+        if self.server.lan != running.server.lan {
+            keys.push("server.lan");
+        }
+        if self.tls != running.tls {
+            keys.push("tls");
+        }
+        if self.adb.inspect != running.adb.inspect {
+            keys.push("adb.inspect");
+        }
+        if self.log.dir != running.log.dir {
+            keys.push("log.dir");
+        }
+        if self.log.max_files != running.log.max_files {
+            keys.push("log.max_files");
+        }
+        if self.web != running.web {
+            keys.push("web");
+        }
+        keys
+    }
+}
+
+/// A setting given on the command line or in the environment, which wins
+/// over the file.
+#[derive(Debug, Clone)]
+struct Override {
+    key: String,
+    value: String,
+    /// Adds to a list instead of replacing it.
+    append: bool,
+}
+
+impl Override {
+    fn new(key: &str, value: impl Into<String>) -> Self {
+        Self {
+            key: key.to_string(),
+            value: value.into(),
+            append: false,
+        }
+    }
+
+    fn append(key: &str, value: impl Into<String>) -> Self {
+        Self {
+            append: true,
+            ..Self::new(key, value)
+        }
+    }
+
+    /// Converts the value to the type of the default at the same key, a
+    /// comma separated list for lists.
+    fn apply(&self, table: &mut toml::Table, defaults: &toml::Table) -> Result<(), String> {
+        let invalid =
+            |expected: &str| format!("{}: expected {}, got `{}`", self.key, expected, self.value);
+        let Some((section, key)) = self.key.split_once('.') else {
+            return Err(format!("unknown setting `{}`", self.key));
+        };
+        let Some(toml::Value::Table(default_section)) = defaults.get(section) else {
+            return Err(format!("unknown setting `{}`", self.key));
+        };
+
+        let value = match default_section.get(key) {
+            Some(toml::Value::Boolean(_)) => match self.value.as_str() {
+                "" | "true" | "1" => toml::Value::Boolean(true),
+                "false" | "0" => toml::Value::Boolean(false),
+                _ => return Err(invalid("true or false")),
+            },
+            Some(toml::Value::Integer(_)) => self
+                .value
+                .parse()
+                .map(toml::Value::Integer)
+                .map_err(|_| invalid("a number"))?,
+            Some(toml::Value::Array(_)) => toml::Value::Array(
+                self.value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| toml::Value::String(item.to_string()))
+                    .collect(),
+            ),
+            // Strings and unset optional paths, unknown keys are reported
+            // when the result is deserialized.
+            _ => toml::Value::String(self.value.clone()),
+        };
+
+        let section = table
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        let toml::Value::Table(section) = section else {
+            return Err(format!("{} is not a table", self.key));
+        };
+        match (section.get_mut(key), value) {
+            (Some(toml::Value::Array(existing)), toml::Value::Array(items)) if self.append => {
+                existing.extend(items)
+            }
+            (_, value) => {
+                section.insert(key.to_string(), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads `--config` and the setting overrides, including the older flags
+/// that predate the config file.
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+    path: &mut Option<PathBuf>,
+    overrides: &mut Vec<Override>,
+) -> Result<(), String> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} needs a value", name))
+        };
+
+        match name.as_str() {
+            ARG_CONFIG => *path = Some(value()?.into()),
+            ARG_SET => {
+                let value = value()?;
+                let (key, value) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("{} expects <key>=<value>, got `{}`", ARG_SET, value))?;
+                overrides.push(Override::new(key.trim(), value.trim()));
+            }
+            ARG_LAN => overrides.push(Override::new("server.lan", "true")),
+            ARG_LISTEN => {
+                // Also accepts `<ip>:<port>` as before.
+                let value = value()?;
+                match value.parse::<SocketAddr>() {
+                    Ok(addr) => {
+                        overrides.push(Override::append("server.listen", addr.ip().to_string()));
+                        overrides.push(Override::new("server.port", addr.port().to_string()));
+                    }
+                    Err(_) => overrides.push(Override::append("server.listen", value)),
+                }
+            }
+            ARG_ALLOW => {
+                // A bare address is a single host.
+                let value = value()?;
+                let net = match value.parse::<IpAddr>() {
+                    Ok(ip) => IpNet::from(ip).to_string(),
+                    Err(_) => value,
+                };
+                overrides.push(Override::append("server.allow", net));
+            }
+            ARG_ALLOW_ORIGIN => overrides.push(Override::append("server.allow_origins", value()?)),
+            ARG_TLS => overrides.push(Override::new("tls.enabled", "true")),
+            ARG_TLS_PORT | ARG_TLS_CERT | ARG_TLS_KEY => {
+                let key = match name.as_str() {
+                    ARG_TLS_PORT => "tls.port",
+                    ARG_TLS_CERT => "tls.cert",
+                    _ => "tls.key",
+                };
+                overrides.push(Override::new(key, value()?));
+                overrides.push(Override::new("tls.enabled", "true"));
+            }
+            ARG_NO_ADB_INSPECT => overrides.push(Override::new("adb.inspect", "false")),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_env(
+    vars: impl IntoIterator<Item = (String, String)>,
+    path: &mut Option<PathBuf>,
+    overrides: &mut Vec<Override>,
+) {
+    for (name, value) in vars {
+        if name == ENV_CONFIG {
+            *path = Some(value.into());
+            continue;
+        }
+        let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        // Section names have no underscores, so the first one ends it.
+        if let Some((section, key)) = rest.to_ascii_lowercase().split_once('_') {
+            overrides.push(Override::new(&format!("{}.{}", section, key), value));
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// The live configuration, shared by everything that reads settings. The
+/// file is watched and changes are validated before they're published.
+#[derive(Clone)]
+pub struct ConfigStore {
+    path: Arc<PathBuf>,
+    overrides: Arc<Vec<Override>>,
+    current: Arc<watch::Sender<Arc<Config>>>,
+    /// What the listeners were set up with.
+    started: Arc<Config>,
+    /// Why the last change to the file wasn't applied, until one is.
+    reload_error: Arc<StdMutex<Option<ConfigError>>>,
+}
+
+impl ConfigStore {
+    /// File settings are overridden by the environment, then the command line.
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut path = None;
+        let mut overrides = Vec::new();
+        parse_env(std::env::vars(), &mut path, &mut overrides);
+        parse_args(args, &mut path, &mut overrides)
+            .map_err(|err| ConfigError::Invalid { errors: vec![err] })?;
+
+        let path = path.unwrap_or_else(|| paths::config_dir().join(CONFIG_FILE));
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(ConfigError::Io {
+                    message: format!("{}: {}", path.display(), err),
+                })
+            }
+        };
+        let file = parse_file(&text)?;
+        let config = Arc::new(resolve(file, &overrides)?);
+
+        Ok(Self {
+            path: Arc::new(path),
+            overrides: Arc::new(overrides),
+            current: Arc::new(watch::channel(config.clone()).0),
+            started: config,
+            reload_error: Arc::default(),
+        })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.borrow().clone()
+    }
+
+    /// Sees every configuration that gets applied.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.current.subscribe()
+    }
+
+    pub fn reload_error(&self) -> Option<ConfigError> {
+        self.reload_error.lock().unwrap().clone()
+    }
+
+    pub fn started(&self) -> &Config {
+        &self.started
+    }
+
+    /// Keys set on the command line or in the environment.
+    pub fn overridden(&self) -> Vec<String> {
+        let mut keys = self
+            .overrides
+            .iter()
+            .map(|item| item.key.clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    /// Saves `file` as the new config file and applies it with the overrides.
+    /// `sent` lists the sections the client gave. File-only and overridden
+    /// keys must match the live settings, and keep what the file has either
+    /// way, so overrides aren't saved by sending back what `get` returned.
+    pub fn update(&self, file: Config, sent: &[String]) -> Result<Arc<Config>, ConfigError> {
+        let to_table = |config: &Config| toml::Table::try_from(config).expect("config serializes");
+        let mut table = to_table(&file);
+        let current = to_table(&self.get());
+        let overridden = self
+            .overridden()
+            .into_iter()
+            .filter(|key| !FILE_ONLY.iter().any(|file_only| contains(file_only, key)));
+        let protected = FILE_ONLY
+            .iter()
+            .map(|key| (key.to_string(), "can only be changed in the config file"))
+            .chain(overridden.map(|key| (key, "is set on the command line or in the environment")))
+            .collect::<Vec<_>>();
+
+        let errors = protected
+            .iter()
+            .filter(|(key, _)| sent.iter().any(|section| contains(section, key)))
+            .filter(|(key, _)| lookup(&table, key) != lookup(&current, key))
+            .map(|(key, reason)| format!("{} {}", key, reason))
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            return Err(ConfigError::Invalid { errors });
+        }
+
+        let on_disk = self.read()?;
+        for (key, _) in &protected {
+            replace(&mut table, key, lookup(&on_disk, key).cloned());
+        }
+        // Back through `Config`, so the file keeps the usual order of keys.
+        let text =
+            toml::to_string_pretty(&deserialize(table)?).map_err(|err| ConfigError::Parse {
+                message: err.to_string(),
+            })?;
+        let config = resolve(parse_file(&text)?, &self.overrides)?;
+
+        let io_error = |err: std::io::Error| ConfigError::Io {
+            message: format!("{}: {}", self.path.display(), err),
+        };
+        if let Some(dir) = self.path.parent() {
+            paths::ensure_dir(dir.to_path_buf()).map_err(io_error)?;
+        }
+        std::fs::write(&*self.path, text).map_err(io_error)?;
+
+        Ok(self.publish(config))
+    }
+
+    fn reload(&self) -> Result<Arc<Config>, ConfigError> {
+        let config = resolve(self.read()?, &self.overrides)?;
+        Ok(self.publish(config))
+    }
+
+    /// The file as written, a missing one is empty.
+    fn read(&self) -> Result<toml::Table, ConfigError> {
+        let text = match std::fs::read_to_string(&*self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(ConfigError::Io {
+                    message: err.to_string(),
+                })
+            }
+        };
+        parse_file(&text)
+    }
+
+    fn publish(&self, config: Config) -> Arc<Config> {
+        let config = Arc::new(config);
+        let changed = self.current.send_if_modified(|current| {
+            if **current == *config {
+                return false;
+            }
+            *current = config.clone();
+            true
+        });
+        if changed {
+            tracing::info!(path = %self.path.display(), "configuration applied");
+            let pending = config.restart_required(&self.started);
+            if !pending.is_empty() {
+                tracing::warn!(?pending, "restart the bridge to apply these settings");
+            }
+        }
+        config
+    }
+
+    /// Reloads the file whenever it changes. An invalid file is reported
+    /// and the previous settings stay in place.
+    pub fn watch(&self, cancel: CancellationToken) -> JoinHandle<()> {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = modified(&store.path);
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep(WATCH_INTERVAL) => {}
+                }
+                let modified = modified(&store.path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                let result = store.reload();
+                if let Err(err) = &result {
+                    tracing::error!(path = %store.path.display(), %err, "invalid configuration, keeping the previous one");
+                }
+                *store.reload_error.lock().unwrap() = result.err();
+            }
+        })
+    }
+}
+
+/// Whether `key` is `parent` or inside it, e.g. `server.port` in `server`.
+fn contains(parent: &str, key: &str) -> bool {
+    key.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// The value at a `section` or `section.key`.
+fn lookup<'a>(table: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+    match key.split_once('.') {
+        Some((section, key)) => table.get(section)?.as_table()?.get(key),
+        None => table.get(key),
+    }
+}
+
+/// Sets or, for `None`, removes the value at a `section` or `section.key`.
+fn replace(table: &mut toml::Table, key: &str, value: Option<toml::Value>) {
+    let (table, key) = match key.split_once('.') {
+        Some((section, key)) => {
+            let section = table
+                .entry(section)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            let Some(section) = section.as_table_mut() else {
+                return;
+            };
+            (section, key)
+        }
+        None => (table, key),
+    };
+    match value {
+        Some(value) => table.insert(key.to_string(), value),
+        None => table.remove(key),
+    };
+}
+
+fn parse_file(text: &str) -> Result<toml::Table, ConfigError> {
+    text.parse::<toml::Table>()
+        .map_err(|err| ConfigError::Parse {
+            message: err.to_string(),
+        })
+}
+
+fn resolve(mut table: toml::Table, overrides: &[Override]) -> Result<Config, ConfigError> {
+    let defaults = toml::Table::try_from(Config::default()).expect("default config serializes");
+    let errors = overrides
+        .iter()
+        .filter_map(|item| item.apply(&mut table, &defaults).err())
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        return Err(ConfigError::Invalid { errors });
+    }
+
+    let config = deserialize(table)?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Reports the key that failed, e.g. `ios.scan_interval_ms: invalid type`.
+fn deserialize(table: toml::Table) -> Result<Config, ConfigError> {
+    serde_path_to_error::deserialize(toml::Value::Table(table)).map_err(|err| {
+        ConfigError::Invalid {
+            errors: vec![format!("{}: {}", err.path(), err.inner().message())],
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// A store on a new file holding `text`.
+    fn store(text: &str, extra: &[&str]) -> ConfigStore {
+        static NEXT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "tango-config-{}-{}.toml",
+            std::process::id(),
+            NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        std::fs::write(&path, text).unwrap();
+        let mut all = args(&["--config", path.to_str().unwrap()]);
+        all.extend(args(extra));
+        ConfigStore::load(all).unwrap()
+    }
+
+    fn saved(store: &ConfigStore) -> toml::Table {
+        let table = store.read().unwrap();
+        let _ = std::fs::remove_file(store.path());
+        table
+    }
+
+    #[test]
+    fn keeps_overrides_out_of_the_file() {
+        let store = store(
+            "[ios]\nscan_interval_ms = 20000\n",
+            &["--tls", "--set", "ios.scan_concurrency=8"],
+        );
+        let mut config = (*store.get()).clone();
+        assert!(config.tls.enabled);
+        config.log.level = "debug".to_string();
+
+        let sent = args(&["tls", "ios", "log"]);
+        let applied = store.update(config, &sent).unwrap();
+        assert!(applied.tls.enabled);
+        assert_eq!(applied.ios.scan_concurrency, 8);
+        assert_eq!(applied.log.level, "debug");
+
+        let file = saved(&store);
+        assert_eq!(
+            lookup(&file, "tls.enabled"),
+            Some(&toml::Value::Boolean(false))
+        );
+        assert_eq!(
+            lookup(&file, "ios.scan_concurrency"),
+            Some(&toml::Value::Integer(64))
+        );
+        assert_eq!(
+            lookup(&file, "ios.scan_interval_ms"),
+            Some(&toml::Value::Integer(20000))
+        );
+        assert_eq!(
+            lookup(&file, "log.level").and_then(toml::Value::as_str),
+            Some("debug")
+        );
+    }
+
+    #[test]
+    fn rejects_changes_to_overridden_and_file_only_keys() {
+        let store = store("", &["--set", "ios.scan_concurrency=8"]);
+        let mut config = (*store.get()).clone();
+        config.ios.scan_concurrency = 16;
+        config.server.port = 1;
+        let err = store.update(config, &args(&["ios", "server"])).unwrap_err();
+        let ConfigError::Invalid { errors } = err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(
+            errors,
+            [
+                "server can only be changed in the config file",
+                "ios.scan_concurrency is set on the command line or in the environment",
+            ]
+        );
+        let _ = std::fs::remove_file(store.path());
+    }
+
+    #[test]
+    fn keeps_paths_the_bridge_serves_file_only() {
+        let store = store(
+            "[web]
+offline = false
+",
+            &[],
+        );
+        let mut config = (*store.get()).clone();
+        config.web.offline = true;
+        config.web.bundle = Some("/".into());
+        config.log.dir = Some("/tmp".into());
+        config.proxy.upstream = "https://evil.example".to_string();
+        let err = store
+            .update(config, &args(&["web", "log", "proxy"]))
+            .unwrap_err();
+        let ConfigError::Invalid { errors } = err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(
+            errors,
+            [
+                "web.offline can only be changed in the config file",
+                "web.bundle can only be changed in the config file",
+                "log.dir can only be changed in the config file",
+                "proxy.upstream can only be changed in the config file",
+            ]
+        );
+
+        // Leaving them out keeps what the file has.
+        let mut config = Config::default();
+        config.ios.scan_concurrency = 8;
+        store.update(config, &args(&["ios"])).unwrap();
+        let file = saved(&store);
+        assert_eq!(
+            lookup(&file, "web.offline"),
+            Some(&toml::Value::Boolean(false))
+        );
+        assert_eq!(lookup(&file, "web.bundle"), None);
+    }
+
+    fn overrides(args: &[&str]) -> Vec<(String, String)> {
+        let mut path = None;
+        let mut overrides = Vec::new();
+        parse_args(self::args(args), &mut path, &mut overrides).unwrap();
+        overrides
+            .into_iter()
+            .map(|item| (item.key, item.value))
+            .collect()
+    }
+
+    #[test]
+    fn converts_overrides_to_the_type_of_the_default() {
+        let defaults = toml::Table::try_from(Config::default()).unwrap();
+        let mut table = toml::Table::new();
+        for item in [
+            Override::new("server.lan", ""),
+            Override::new("tls.enabled", "0"),
+            Override::new("tls.port", "8443"),
+            Override::new("log.dir", "/var/log/tango"),
+            Override::new(
+                "server.allow_origins",
+                " https://a.example, ,https://b.example",
+            ),
+            Override::append("server.allow_origins", "https://c.example"),
+        ] {
+            item.apply(&mut table, &defaults).unwrap();
+        }
+        assert_eq!(
+            lookup(&table, "server.lan"),
+            Some(&toml::Value::Boolean(true))
+        );
+        assert_eq!(
+            lookup(&table, "tls.enabled"),
+            Some(&toml::Value::Boolean(false))
+        );
+        assert_eq!(
+            lookup(&table, "tls.port"),
+            Some(&toml::Value::Integer(8443))
+        );
+        assert_eq!(
+            lookup(&table, "log.dir").and_then(toml::Value::as_str),
+            Some("/var/log/tango")
+        );
+        let origins = lookup(&table, "server.allow_origins")
+            .and_then(toml::Value::as_array)
+            .unwrap();
+        assert_eq!(origins.len(), 3);
+
+        for (key, value, error) in [
+            (
+                "ios.scan_interval_ms",
+                "soon",
+                "ios.scan_interval_ms: expected a number, got `soon`",
+            ),
+            (
+                "server.lan",
+                "yes",
+                "server.lan: expected true or false, got `yes`",
+            ),
+            ("lan", "true", "unknown setting `lan`"),
+            ("nope.key", "1", "unknown setting `nope.key`"),
+        ] {
+            let err = Override::new(key, value)
+                .apply(&mut table, &defaults)
+                .unwrap_err();
+            assert_eq!(err, error);
+        }
+    }
+
+    #[test]
+    fn reads_the_older_flags() {
+        let parsed = overrides(&[
+            "--listen",
+            "0.0.0.0:8080",
+            "--listen=192.168.1.2",
+            "--allow",
+            "192.168.1.5",
+            "--allow",
+            "10.0.0.0/8",
+            "--tls-port",
+            "8443",
+            "--set",
+            "log.level = debug",
+            "--headless",
+        ]);
+        let expected = [
+            ("server.listen", "0.0.0.0"),
+            ("server.port", "8080"),
+            ("server.listen", "192.168.1.2"),
+            ("server.allow", "192.168.1.5/32"),
+            ("server.allow", "10.0.0.0/8"),
+            ("tls.port", "8443"),
+            ("tls.enabled", "true"),
+            ("log.level", "debug"),
+        ];
+        let expected = expected
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(parsed, expected);
+
+        let mut path = None;
+        let mut overrides = Vec::new();
+        for bad in [&["--set", "log.level"][..], &["--listen"]] {
+            assert!(parse_args(args(bad), &mut path, &mut overrides).is_err());
+        }
+    }
+
+    #[test]
+    fn splits_environment_variables_at_the_section() {
+        let vars = [
+            ("TANGO_BRIDGE_IOS_SCAN_INTERVAL_MS", "5000"),
+            ("TANGO_BRIDGE_CONFIG", "/etc/tango.toml"),
+            ("TANGO_BRIDGE_TOKEN", "abc"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let mut path = None;
+        let mut overrides = Vec::new();
+        parse_env(vars, &mut path, &mut overrides);
+
+        assert_eq!(path, Some(PathBuf::from("/etc/tango.toml")));
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(overrides[0].key, "ios.scan_interval_ms");
+        assert_eq!(overrides[0].value, "5000");
+    }
+
+    #[test]
+    fn reports_every_invalid_setting() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.server.port = 0;
+        config.server.listen = vec!["0.0.0.0".parse().unwrap()];
+        config.tls.cert = Some("cert.pem".into());
+        config.proxy.upstream = "ftp://example.com".to_string();
+        config.ios.connect_timeout_ms = config.ios.probe_timeout_ms + 1;
+        let Err(ConfigError::Invalid { errors }) = config.validate() else {
+            panic!("expected errors");
+        };
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        assert!(errors[1].starts_with("server.listen: 0.0.0.0 is not a loopback address"));
+
+        config.server.lan = true;
+        let Err(ConfigError::Invalid { errors }) = config.validate() else {
+            panic!("expected errors");
+        };
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+    }
+}
@@ -0,0 +1,90 @@
+use std::io;
+
+use tokio::task::JoinHandle;
+
+use crate::shutdown::{report, Shutdown};
+
+/// Runs until SIGINT or SIGTERM, or until the server stops on its own, for
+/// machines without a desktop session. `false` when the server failed, so
+/// the process exits non-zero and a supervisor restarts it.
+pub async fn run(shutdown: Shutdown, mut server: JoinHandle<io::Result<()>>) -> bool {
+    systemd::ready(&shutdown);
+    tracing::info!("running headless");
+
+    tokio::select! {
+        _ = shutdown_signal() => {}
+        result = &mut server => {
+            systemd::stopping();
+            return report(result);
+        }
+    }
+
+    systemd::stopping();
+    shutdown.stop(server).await
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(err) => tracing::warn!(%err, "can't listen for SIGTERM"),
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// `sd_notify` for `Type=notify` units, a no-op when not started by systemd.
+#[cfg(target_os = "linux")]
+mod systemd {
+    use std::time::Duration;
+
+    use sd_notify::NotifyState;
+
+    use crate::shutdown::Shutdown;
+
+    pub fn ready(shutdown: &Shutdown) {
+        if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+            tracing::warn!(%err, "sd_notify failed");
+        }
+
+        // Pings at half the interval the unit asks for with `WatchdogSec=`.
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) {
+            let token = shutdown.token();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+                loop {
+                    tokio::select! {
+                        _ = token.cancelled() => break,
+                        _ = interval.tick() => {
+                            let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn stopping() {
+        let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod systemd {
+    use crate::shutdown::Shutdown;
+
+    pub fn ready(_shutdown: &Shutdown) {}
+
+    pub fn stopping() {}
+}
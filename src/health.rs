@@ -0,0 +1,301 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    adb,
+    config::ConfigStore,
+    ios_provider::{ScanReport, ScanStatus},
+};
+
+/// How long each live check may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Sweeps older than this many scan intervals mean the provider is stuck.
+const STALE_INTERVALS: u32 = 3;
+
+/// Never call a sweep stale sooner than this, slow subnets take a while.
+const MIN_STALE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Ok,
+    /// Not checked yet, e.g. before the first sweep.
+    Starting,
+    /// Working, but not as configured.
+    Degraded,
+    Down,
+}
+
+/// `/bridge/health`. The ADB server and the iOS provider are critical, the
+/// bridge isn't ready unless both are `ok`. An ADB server that was never
+/// found isn't, so machines that only serve iOS devices can be ready.
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub state: State,
+    pub version: &'static str,
+    pub adb: AdbHealth,
+    pub ios: IosHealth,
+    pub proxy: ProxyHealth,
+    pub config: ConfigHealth,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdbHealth {
+    pub state: State,
+    pub server: SocketAddr,
+    /// Protocol version reported by the server.
+    pub version: Option<u32>,
+    /// Whether the bridge started the server, rather than finding one.
+    pub spawned: bool,
+    /// Whether the server has answered since the bridge started.
+    pub found: bool,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IosHealth {
+    pub state: State,
+    #[serde(flatten)]
+    pub scan: ScanReport,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyHealth {
+    pub state: State,
+    pub upstream: String,
+    /// The web UI is served from a bundle, the upstream isn't checked.
+    pub offline: bool,
+    /// HTTP status of the check, any status means the upstream is reachable.
+    pub status: Option<u16>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigHealth {
+    pub state: State,
+    pub path: PathBuf,
+    pub exists: bool,
+    /// Why the last edit wasn't applied, the previous settings stay in use.
+    pub last_error: Option<String>,
+}
+
+impl HealthReport {
+    pub fn is_ready(&self) -> bool {
+        self.critical().iter().all(|state| *state == State::Ok)
+    }
+
+    fn critical(&self) -> Vec<State> {
+        let mut critical = vec![self.ios.state];
+        if self.adb.found {
+            critical.push(self.adb.state);
+        }
+        critical
+    }
+}
+
+/// Runs the checks, reusing the last ADB and upstream checks for a scan
+/// interval so unauthenticated requests can't make the bridge flood them.
+#[derive(Clone)]
+pub struct Health {
+    client: reqwest::Client,
+    adb: Arc<Mutex<Option<(Instant, AdbHealth)>>>,
+    proxy: Arc<Mutex<Option<(Instant, ProxyHealth)>>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            adb: Arc::default(),
+            proxy: Arc::default(),
+        }
+    }
+
+    pub async fn check(&self, config: &ConfigStore, scans: &ScanStatus) -> HealthReport {
+        let settings = config.get();
+        let (adb, proxy) = tokio::join!(
+            self.check_adb(settings.adb.server, settings.ios.scan_interval()),
+            self.check_proxy(
+                &settings.proxy.upstream,
+                config.started().web.offline,
+                settings.ios.scan_interval()
+            )
+        );
+        let ios = check_ios(scans.get(), settings.ios.scan_interval());
+        let config = check_config(config);
+
+        let mut report = HealthReport {
+            state: State::Ok,
+            version: env!("CARGO_PKG_VERSION"),
+            adb,
+            ios,
+            proxy,
+            config,
+        };
+        let critical = report.critical();
+        report.state = if critical.contains(&State::Down) {
+            State::Down
+        } else if critical.contains(&State::Starting) {
+            State::Starting
+        } else if report.adb.state != State::Ok
+            || report.proxy.state != State::Ok
+            || report.config.state != State::Ok
+        {
+            State::Degraded
+        } else {
+            State::Ok
+        };
+        report
+    }
+
+    async fn check_adb(&self, server: SocketAddr, max_age: Duration) -> AdbHealth {
+        // Held during the check, so concurrent requests wait for its result.
+        let mut cached = self.adb.lock().await;
+        if let Some((checked, health)) = &*cached {
+            if checked.elapsed() < max_age && health.server == server {
+                return AdbHealth {
+                    spawned: adb::spawned(),
+                    found: adb::found(),
+                    ..health.clone()
+                };
+            }
+        }
+
+        let health = check_adb(server).await;
+        *cached = Some((Instant::now(), health.clone()));
+        health
+    }
+
+    async fn check_proxy(&self, upstream: &str, offline: bool, max_age: Duration) -> ProxyHealth {
+        if offline {
+            return ProxyHealth {
+                state: State::Ok,
+                upstream: upstream.to_string(),
+                offline,
+                status: None,
+                last_error: None,
+            };
+        }
+        // Held during the check, so concurrent requests wait for its result.
+        let mut cached = self.proxy.lock().await;
+        if let Some((checked, health)) = &*cached {
+            if checked.elapsed() < max_age && health.upstream == upstream {
+                return health.clone();
+            }
+        }
+
+        let response = self
+            .client
+            .head(upstream)
+            .timeout(CHECK_TIMEOUT)
+            .send()
+            .await;
+        let (state, status, last_error) = match response {
+            Ok(response) => (State::Ok, Some(response.status().as_u16()), None),
+            Err(err) => (State::Down, None, Some(err.to_string())),
+        };
+        let health = ProxyHealth {
+            state,
+            upstream: upstream.to_string(),
+            offline,
+            status,
+            last_error,
+        };
+        *cached = Some((Instant::now(), health.clone()));
+        health
+    }
+}
+
+async fn check_adb(server: SocketAddr) -> AdbHealth {
+    let version = match tokio::time::timeout(CHECK_TIMEOUT, adb::version(server)).await {
+        Ok(Ok(version)) => Ok(version),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+    AdbHealth {
+        state: match version {
+            Ok(_) => State::Ok,
+            Err(_) => State::Down,
+        },
+        server,
+        version: version.as_ref().ok().copied(),
+        spawned: adb::spawned(),
+        found: adb::found(),
+        last_error: version.err(),
+    }
+}
+
+fn check_ios(mut scan: ScanReport, interval: Duration) -> IosHealth {
+    let stale_after = (interval * STALE_INTERVALS).max(MIN_STALE);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default();
+    let state = match scan.last_scan_at {
+        _ if scan.last_error.is_some() => State::Down,
+        None => State::Starting,
+        Some(at) if now.saturating_sub(at) > stale_after.as_millis() as u64 => State::Down,
+        Some(_) => State::Ok,
+    };
+    if state == State::Down && scan.last_error.is_none() {
+        scan.last_error = Some(format!("no sweep finished in {:?}", stale_after));
+    }
+    IosHealth { state, scan }
+}
+
+fn check_config(config: &ConfigStore) -> ConfigHealth {
+    let last_error = config.reload_error().map(|err| err.to_string());
+    ConfigHealth {
+        state: match last_error {
+            Some(_) => State::Degraded,
+            None => State::Ok,
+        },
+        path: config.path().clone(),
+        exists: config.path().exists(),
+        last_error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn checks_adb_once_per_interval() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    drop(stream);
+                }
+            }
+        });
+
+        let health = Health::new();
+        let interval = Duration::from_secs(60);
+        for _ in 0..3 {
+            let adb = health.check_adb(server, interval).await;
+            assert_eq!(adb.state, State::Down);
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        health.check_adb(server, Duration::ZERO).await;
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{net::Ipv4Addr, time::Instant};
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::de::DeserializeOwned;
+use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream};
+
+use crate::ios_lan_scanner::{ProbeError, ProbeTimeouts, ProbeTrace};
+
+/// Maximum size of a reply line.
+const MAX_REPLY_LEN: usize = 64 * 1024;
+
+/// Task ids of ZXTouch's line protocol, from `zxtouch/tasktypes.py` in
+/// xuan32546/IOS13-SimulateTouch: `TASK_PLAY_SCRIPT` takes the absolute
+/// path of a bundle, `TASK_PLAY_SCRIPT_FORCE_STOP` nothing.
+const TASK_PLAY_SCRIPT: &str = "19";
+const TASK_PLAY_SCRIPT_FORCE_STOP: &str = "20";
+
+/// The agent's status request, which stock ZXTouch doesn't have.
+const TASK_STATUS: &str = "60";
+
+/// Requests understood by the agent's line protocol on the ZXTouch port.
+#[derive(Debug, Clone)]
+pub enum AgentCommand {
+    Status,
+    PlayScript { path: String },
+    StopScript,
+}
+
+impl AgentCommand {
+    /// `None` for paths that would end the line early and smuggle in
+    /// another command.
+    pub fn play_script(path: String) -> Option<Self> {
+        (!path.contains(['\r', '\n', '\0'])).then_some(AgentCommand::PlayScript { path })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            AgentCommand::Status => format!("{}\r\n", TASK_STATUS).into_bytes(),
+            AgentCommand::PlayScript { path } => {
+                format!("{}{}\r\n", TASK_PLAY_SCRIPT, path).into_bytes()
+            }
+            AgentCommand::StopScript => format!("{}\r\n", TASK_PLAY_SCRIPT_FORCE_STOP).into_bytes(),
+        }
+    }
+}
+
+/// Decoded `code;;base64` reply line.
+#[derive(Debug, Clone)]
+pub struct AgentReply {
+    pub code: i32,
+    pub body: Vec<u8>,
+}
+
+impl AgentReply {
+    /// Turns a non-zero reply code into an error carrying the agent's message.
+    pub fn ok(self) -> Result<Vec<u8>, ProbeError> {
+        if self.code == 0 {
+            Ok(self.body)
+        } else {
+            Err(ProbeError::Agent {
+                code: self.code,
+                message: String::from_utf8_lossy(&self.body).into_owned(),
+            })
+        }
+    }
+
+    pub fn json<T: DeserializeOwned>(self) -> Result<T, ProbeError> {
+        let body = self.ok()?;
+        let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+        serde_path_to_error::deserialize(deserializer).map_err(|err| ProbeError::Json {
+            field: err.path().to_string(),
+            message: err.into_inner().to_string(),
+        })
+    }
+}
+
+/// Sends one command bounded by the total deadline.
+pub async fn send(
+    ip: Ipv4Addr,
+    port: u16,
+    command: &AgentCommand,
+    timeouts: ProbeTimeouts,
+) -> Result<AgentReply, ProbeError> {
+    tokio::time::timeout(
+        timeouts.total,
+        request(ip, port, command, timeouts, &mut ProbeTrace::default()),
+    )
+    .await
+    .map_err(|_| ProbeError::TotalTimeout)?
+}
+
+/// Sends one command and reads back a single reply line.
+///
+/// Only the connect and the first byte have their own deadlines, callers
+/// bound the whole exchange with `timeouts.total`.
+pub async fn request(
+    ip: Ipv4Addr,
+    port: u16,
+    command: &AgentCommand,
+    timeouts: ProbeTimeouts,
+    trace: &mut ProbeTrace,
+) -> Result<AgentReply, ProbeError> {
+    let started = Instant::now();
+
+    let mut stream = tokio::time::timeout(timeouts.connect, TcpStream::connect((ip, port)))
+        .await
+        .map_err(|_| ProbeError::ConnectTimeout)??;
+    trace.connect = Some(started.elapsed());
+
+    stream.write_all(&command.encode()).await?;
+    let sent = Instant::now();
+
+    let mut buf = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    let newline_pos = loop {
+        let n = if buf.is_empty() {
+            tokio::time::timeout(timeouts.first_byte, stream.read(&mut chunk))
+                .await
+                .map_err(|_| ProbeError::FirstByteTimeout)??
+        } else {
+            stream.read(&mut chunk).await?
+        };
+        if n == 0 {
+            return Err(ProbeError::Closed {
+                received: buf.len(),
+            });
+        }
+        if buf.is_empty() {
+            trace.first_byte = Some(sent.elapsed());
+        }
+
+        let searched = buf.len();
+        buf.extend_from_slice(&chunk[..n]);
+        trace.bytes = buf.len();
+        if let Some(pos) = buf[searched..].iter().position(|b| *b == b'\n') {
+            break searched + pos;
+        }
+
+        if buf.len() > MAX_REPLY_LEN {
+            return Err(ProbeError::Oversize {
+                limit: MAX_REPLY_LEN,
+            });
+        }
+    };
+
+    decode_line(&buf[..=newline_pos])
+}
+
+pub fn decode_line(line: &[u8]) -> Result<AgentReply, ProbeError> {
+    let text = String::from_utf8_lossy(line).trim().to_string();
+    let bad_prefix = || ProbeError::BadPrefix {
+        line: text.chars().take(64).collect(),
+    };
+
+    // Acknowledgements without a body may come back as a bare code.
+    let (code, b64) = text.split_once(";;").unwrap_or((text.as_str(), ""));
+    let code = code.trim().parse::<i32>().map_err(|_| bad_prefix())?;
+
+    let body = match general_purpose::STANDARD
+        .decode(b64)
+        .or_else(|_| general_purpose::STANDARD_NO_PAD.decode(b64))
+    {
+        Ok(body) => body,
+        // Some agent builds send error messages as plain text.
+        Err(_) if code != 0 => b64.as_bytes().to_vec(),
+        Err(err) => {
+            return Err(ProbeError::Base64 {
+                message: err.to_string(),
+            })
+        }
+    };
+
+    Ok(AgentReply { code, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_one_line_per_command() {
+        let play = AgentCommand::play_script("/var/mobile/Library/ZXTouch/scripts/a.bdl".into());
+        assert_eq!(
+            play.unwrap().encode(),
+            b"19/var/mobile/Library/ZXTouch/scripts/a.bdl\r\n"
+        );
+        assert_eq!(AgentCommand::Status.encode(), b"60\r\n");
+        assert_eq!(AgentCommand::StopScript.encode(), b"20\r\n");
+    }
+
+    #[test]
+    fn rejects_paths_that_break_the_line() {
+        for path in ["a.bdl\r\n20", "a.bdl\n", "a.bdl\r", "a\0.bdl"] {
+            assert!(
+                AgentCommand::play_script(path.to_string()).is_none(),
+                "{:?}",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use axum::{
+    body::Bytes,
+    extract::ws::{Message, WebSocket},
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    ios_provider::IosDevice,
+    ios_zxtouch::{LinkState, ZxTouchLink, ZxTouchSessions},
+    shutdown::Shutdown,
+    zxtouch,
+};
+
+/// Which devices a broadcast session drives.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeviceSelector {
+    /// Comma separated device ids.
+    #[serde(default)]
+    pub ids: String,
+    /// Matches any device that has this tag, see `IosDevice::tags`.
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+impl DeviceSelector {
+    pub fn select(&self, devices: Vec<IosDevice>) -> Vec<IosDevice> {
+        let ids: Vec<&str> = self
+            .ids
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .collect();
+
+        devices
+            .into_iter()
+            .filter(|device| {
+                ids.contains(&device.id.as_str())
+                    || self
+                        .tag
+                        .as_ref()
+                        .is_some_and(|tag| device.tags().iter().any(|t| t == tag))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BroadcastQuery {
+    #[serde(flatten)]
+    pub selector: DeviceSelector,
+    /// Screen size the inbound coordinates refer to, as `<width>x<height>`.
+    /// When set, touch commands are scaled to each device's screen.
+    #[serde(default)]
+    pub reference: Option<String>,
+}
+
+impl BroadcastQuery {
+    fn reference_size(&self) -> Option<(u32, u32)> {
+        let (width, height) = self.reference.as_deref()?.split_once('x')?;
+        Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+    }
+}
+
+/// Text frames sent back to the broadcast client. Devices share their
+/// ZXTouch session, which reconnects, so a device may come back after
+/// `disconnected`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum BroadcastFrame {
+    Connected { id: String },
+    Reply { id: String, data: String },
+    Dropped { id: String },
+    Disconnected { id: String, reason: String },
+}
+
+struct DeviceLink {
+    id: String,
+    screen: (u32, u32),
+    link: ZxTouchLink,
+}
+
+#[tracing::instrument(name = "broadcast", skip_all, fields(devices = devices.len()))]
+pub async fn handle_broadcast(
+    ws: WebSocket,
+    sessions: ZxTouchSessions,
+    devices: Vec<IosDevice>,
+    query: BroadcastQuery,
+    shutdown: Shutdown,
+) {
+    let (mut ws_writer, mut ws_reader) = ws.split();
+    let (frames, mut frames_receiver) = mpsc::channel::<BroadcastFrame>(256);
+    let cancel = shutdown.child_token();
+
+    let ws_write_task = tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            loop {
+                let frame = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    frame = frames_receiver.recv() => match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                };
+                let Ok(text) = serde_json::to_string(&frame) else {
+                    continue;
+                };
+                if ws_writer.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            cancel.cancel();
+            shutdown.close(&mut ws_writer).await;
+        }
+    });
+
+    let links = devices
+        .into_iter()
+        .map(|device| connect_device(&sessions, device, frames.clone(), cancel.clone()))
+        .collect::<Vec<_>>();
+
+    let reference = query.reference_size();
+
+    loop {
+        let message = tokio::select! {
+            _ = cancel.cancelled() => break,
+            message = ws_reader.next() => message,
+        };
+
+        let (line, binary) = match message {
+            Some(Ok(Message::Text(text))) => (text.to_string(), None),
+            Some(Ok(Message::Binary(buf))) => (String::new(), Some(buf)),
+            Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+            Some(Ok(_)) => continue,
+        };
+
+        for link in &links {
+            let payload = match (&binary, reference) {
+                (Some(buf), _) => buf.clone(),
+                (None, Some(reference)) => {
+                    Bytes::from(zxtouch::scale_lines(&line, reference, link.screen))
+                }
+                (None, None) => Bytes::from(line.clone()),
+            };
+
+            // A slow phone must not hold back the others.
+            // Nor may a slow client, so the notice is dropped too when its
+            // queue is full.
+            if !link.link.try_send(payload) {
+                let _ = frames.try_send(BroadcastFrame::Dropped {
+                    id: link.id.clone(),
+                });
+            }
+        }
+    }
+
+    cancel.cancel();
+    drop(frames);
+    let _ = ws_write_task.await;
+}
+
+fn connect_device(
+    sessions: &ZxTouchSessions,
+    device: IosDevice,
+    frames: mpsc::Sender<BroadcastFrame>,
+    cancel: CancellationToken,
+) -> DeviceLink {
+    let id = device.id.clone();
+    let link = sessions.link(&id);
+    let mut replies = link.replies();
+    let mut state = link.state();
+    state.mark_changed();
+
+    tokio::spawn({
+        let id = id.clone();
+        async move {
+            let mut connected = None;
+            loop {
+                let frame = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    reply = replies.recv() => match reply {
+                        Ok(buf) => {
+                            let data = String::from_utf8_lossy(&buf).into_owned();
+                            BroadcastFrame::Reply { id: id.clone(), data }
+                        }
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    },
+                    changed = state.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                        // Only report changes, not every reconnect attempt.
+                        let frame = match &*state.borrow_and_update() {
+                            LinkState::Connected if connected != Some(true) => {
+                                BroadcastFrame::Connected { id: id.clone() }
+                            }
+                            LinkState::Reconnecting { error, .. } if connected != Some(false) => {
+                                BroadcastFrame::Disconnected { id: id.clone(), reason: error.clone() }
+                            }
+                            _ => continue,
+                        };
+                        connected = Some(matches!(frame, BroadcastFrame::Connected { .. }));
+                        frame
+                    }
+                };
+                if frames.send(frame).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    DeviceLink {
+        id,
+        screen: device.screen_size(),
+        link,
+    }
+}
@@ -2,32 +2,156 @@ use std::{
     io,
     net::{Ipv4Addr, UdpSocket},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use base64::{engine::general_purpose, Engine as _};
-use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream};
+use serde::Serialize;
 use tokio::sync::Semaphore;
 
-use crate::ios_provider::{HelloStatusPayload, IosDevice};
+use crate::{
+    config::{ConfigStore, IosConfig},
+    ios_agent::{self, AgentCommand},
+    ios_provider::{HelloStatusPayload, IosDevice},
+    metrics::{metrics, OutcomeLabels},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeTimeouts {
+    /// Deadline for the TCP connect.
+    pub connect: Duration,
+    /// Deadline for the first byte of the reply after the request was sent.
+    pub first_byte: Duration,
+    /// Deadline for the whole exchange, connect included.
+    pub total: Duration,
+}
 
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProbeError {
+    ConnectRefused,
+    ConnectTimeout,
+    Io {
+        message: String,
+    },
+    FirstByteTimeout,
+    TotalTimeout,
+    /// The agent closed the connection before a full line was received.
+    Closed {
+        received: usize,
+    },
+    BadPrefix {
+        line: String,
+    },
+    /// The agent answered with a non-zero reply code.
+    Agent {
+        code: i32,
+        message: String,
+    },
+    Base64 {
+        message: String,
+    },
+    /// The reply isn't a JSON object. Fields of the wrong type fall back to
+    /// their defaults rather than ending up here.
+    Json {
+        field: String,
+        message: String,
+    },
+    Oversize {
+        limit: usize,
+    },
+}
+
+impl ProbeError {
+    /// The serialized `kind`, used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProbeError::ConnectRefused => "connect_refused",
+            ProbeError::ConnectTimeout => "connect_timeout",
+            ProbeError::Io { .. } => "io",
+            ProbeError::FirstByteTimeout => "first_byte_timeout",
+            ProbeError::TotalTimeout => "total_timeout",
+            ProbeError::Closed { .. } => "closed",
+            ProbeError::BadPrefix { .. } => "bad_prefix",
+            ProbeError::Agent { .. } => "agent",
+            ProbeError::Base64 { .. } => "base64",
+            ProbeError::Json { .. } => "json",
+            ProbeError::Oversize { .. } => "oversize",
+        }
+    }
+
+    /// Whether the error only means "nothing is listening there".
+    pub fn is_unreachable(&self) -> bool {
+        matches!(
+            self,
+            ProbeError::ConnectRefused | ProbeError::ConnectTimeout
+        )
+    }
+}
+
+impl From<io::Error> for ProbeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => ProbeError::ConnectRefused,
+            _ => ProbeError::Io {
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+/// Full diagnosis of a single probe, returned by `GET /ios/probe/{ip}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeReport {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub connect_ms: Option<u64>,
+    pub first_byte_ms: Option<u64>,
+    pub total_ms: u64,
+    pub bytes: usize,
+    pub device: Option<IosDevice>,
+    pub error: Option<ProbeError>,
+}
+
+#[derive(Default)]
+pub struct ProbeTrace {
+    pub connect: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub bytes: usize,
+}
+
+impl ProbeTimeouts {
+    pub fn from_config(ios: &IosConfig) -> Self {
+        Self {
+            connect: Duration::from_millis(ios.connect_timeout_ms),
+            first_byte: Duration::from_millis(ios.first_byte_timeout_ms),
+            total: Duration::from_millis(ios.probe_timeout_ms),
+        }
+    }
+}
+
+/// Reads its timeouts, concurrency and port from the config on every scan,
+/// so changes apply to the next one.
 #[derive(Clone)]
 pub struct IosLanScanner {
-    timeout: Duration,
-    concurrency: usize,
+    config: ConfigStore,
 }
 
 impl IosLanScanner {
-    pub fn new(timeout: Duration, concurrency: usize) -> Self {
-        Self {
-            timeout,
-            concurrency,
-        }
+    pub fn new(config: ConfigStore) -> Self {
+        Self { config }
+    }
+
+    pub fn timeouts(&self) -> ProbeTimeouts {
+        ProbeTimeouts::from_config(&self.config.get().ios)
     }
 
     pub async fn scan_subnet(&self, subnet_base: Ipv4Addr) -> Vec<IosDevice> {
-        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let config = self.config.get();
+        let timeouts = ProbeTimeouts::from_config(&config.ios);
+        let port = config.ios.zxtouch_port;
+        let semaphore = Arc::new(Semaphore::new(config.ios.scan_concurrency));
         let mut tasks = Vec::new();
+        let started = Instant::now();
 
         for host in 2u8..=254u8 {
             let ip = Ipv4Addr::new(
@@ -37,22 +161,71 @@ impl IosLanScanner {
                 host,
             );
             let permit = semaphore.clone().acquire_owned().await.unwrap();
-            let timeout = self.timeout;
-            tasks.push(tokio::spawn(async move {
+            let task = tokio::spawn(async move {
                 let _permit = permit;
-                probe_device(ip, timeout).await
-            }));
+                probe_device(ip, port, timeouts, &mut ProbeTrace::default()).await
+            });
+            tasks.push((ip, task));
         }
 
+        let metrics = metrics();
         let mut devices = Vec::new();
-        for task in tasks {
-            if let Ok(Some(device)) = task.await {
-                devices.push(device);
-            }
+        for (ip, task) in tasks {
+            let outcome = match task.await {
+                Ok(Ok(device)) => {
+                    devices.push(device);
+                    "found"
+                }
+                Ok(Err(err)) => {
+                    if !err.is_unreachable() {
+                        tracing::debug!(%ip, ?err, "ios probe failed");
+                    }
+                    err.kind()
+                }
+                Err(_) => "panicked",
+            };
+            metrics
+                .probes
+                .get_or_create(&OutcomeLabels { outcome })
+                .inc();
         }
+        metrics
+            .scan_duration
+            .observe(started.elapsed().as_secs_f64());
 
         devices
     }
+
+    /// Probes a single address and records where the exchange stopped.
+    pub async fn probe(&self, ip: Ipv4Addr) -> ProbeReport {
+        let started = Instant::now();
+        let config = self.config.get();
+        let port = config.ios.zxtouch_port;
+        let mut trace = ProbeTrace::default();
+        let result = probe_device(
+            ip,
+            port,
+            ProbeTimeouts::from_config(&config.ios),
+            &mut trace,
+        )
+        .await;
+
+        let (device, error) = match result {
+            Ok(device) => (Some(device), None),
+            Err(err) => (None, Some(err)),
+        };
+
+        ProbeReport {
+            ip,
+            port,
+            connect_ms: trace.connect.map(|d| d.as_millis() as u64),
+            first_byte_ms: trace.first_byte.map(|d| d.as_millis() as u64),
+            total_ms: started.elapsed().as_millis() as u64,
+            bytes: trace.bytes,
+            device,
+            error,
+        }
+    }
 }
 
 pub fn local_ipv4() -> io::Result<Ipv4Addr> {
@@ -66,9 +239,13 @@ pub fn local_ipv4() -> io::Result<Ipv4Addr> {
 
 async fn probe_device(
     ip: Ipv4Addr,
-    timeout: Duration,
-) -> Option<IosDevice> {
-    let payload = hello_status(ip, 6000, timeout).await?;
+    port: u16,
+    timeouts: ProbeTimeouts,
+    trace: &mut ProbeTrace,
+) -> Result<IosDevice, ProbeError> {
+    let payload = tokio::time::timeout(timeouts.total, hello_status(ip, port, timeouts, trace))
+        .await
+        .map_err(|_| ProbeError::TotalTimeout)??;
 
     let ip_string = ip.to_string();
     let display_name = if payload.device.name.is_empty() {
@@ -77,7 +254,7 @@ async fn probe_device(
         payload.device.name.clone()
     };
 
-    Some(IosDevice {
+    Ok(IosDevice {
         id: format!("ios:{}", ip_string),
         display_name,
         ip: ip_string,
@@ -85,54 +262,14 @@ async fn probe_device(
     })
 }
 
-async fn hello_status(ip: Ipv4Addr, port: u16, timeout: Duration) -> Option<HelloStatusPayload> {
-    let addr = (ip, port);
-
-    let mut stream = tokio::time::timeout(timeout, TcpStream::connect(addr))
-        .await
-        .ok()?
-        .ok()?;
-
-    // Match python: send b"60\r\n"
-    tokio::time::timeout(timeout, stream.write_all(b"60\r\n"))
-        .await
-        .ok()?
-        .ok()?;
-
-    // Read until newline (max 64 KiB)
-    let mut buf = Vec::with_capacity(4096);
-    let mut chunk = [0u8; 4096];
-    loop {
-        if buf.len() > 64 * 1024 {
-            return None;
-        }
-
-        let n = tokio::time::timeout(timeout, stream.read(&mut chunk))
-            .await
-            .ok()?
-            .ok()?;
-        if n == 0 {
-            return None;
-        }
-
-        buf.extend_from_slice(&chunk[..n]);
-        if buf.iter().any(|b| *b == b'\n') {
-            break;
-        }
-    }
-
-    let newline_pos = buf.iter().position(|b| *b == b'\n')?;
-    let line = &buf[..=newline_pos];
-    let text = String::from_utf8_lossy(line).trim().to_string();
-    if !text.starts_with("0;;") {
-        return None;
-    }
-
-    let b64 = text.splitn(2, ";;").nth(1)?;
-    let decoded = general_purpose::STANDARD
-        .decode(b64)
-        .or_else(|_| general_purpose::STANDARD_NO_PAD.decode(b64))
-        .ok()?;
-
-    serde_json::from_slice::<HelloStatusPayload>(&decoded).ok()
+async fn hello_status(
+    ip: Ipv4Addr,
+    port: u16,
+    timeouts: ProbeTimeouts,
+    trace: &mut ProbeTrace,
+) -> Result<HelloStatusPayload, ProbeError> {
+    ios_agent::request(ip, port, &AgentCommand::Status, timeouts, trace)
+        .await?
+        .json()
+        .map(|payload: HelloStatusPayload| payload.reached_on(port))
 }
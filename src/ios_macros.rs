@@ -0,0 +1,488 @@
+use std::{
+    io,
+    ops::ControlFlow,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::broadcast,
+};
+
+use crate::{
+    ios_agent,
+    ios_broadcast::DeviceSelector,
+    ios_provider::IosDevice,
+    ios_zxtouch::{LinkState, ZxTouchLink, ZxTouchSessions},
+    paths,
+    shutdown::{Shutdown, CLOSE_REASON},
+};
+
+const SESSION_CLOSED: &str = "session closed";
+
+/// How long errors for the last steps are waited for.
+const REPLY_WAIT: Duration = Duration::from_millis(500);
+
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// One recorded ZXTouch command, stored as a JSON line.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MacroStep {
+    /// Offset from the start of the recording.
+    pub at_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Binary frames, stored as base64.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "base64_binary"
+    )]
+    pub binary: Option<Vec<u8>>,
+}
+
+impl MacroStep {
+    fn payload(&self) -> Vec<u8> {
+        match (&self.text, &self.binary) {
+            (Some(text), _) => text.as_bytes().to_vec(),
+            (None, Some(binary)) => binary.clone(),
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+/// Invalid base64 fails the line, so a broken macro isn't played at all.
+mod base64_binary {
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        binary: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match binary {
+            Some(binary) => serializer.serialize_str(&general_purpose::STANDARD.encode(binary)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|text| {
+                general_purpose::STANDARD
+                    .decode(text)
+                    .map_err(D::Error::custom)
+            })
+            .transpose()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MacroError {
+    InvalidName,
+    NotFound,
+    Io { message: String },
+    Parse { line: usize, message: String },
+}
+
+impl From<io::Error> for MacroError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => MacroError::NotFound,
+            _ => MacroError::Io {
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MacroInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlayOptions {
+    /// Playback speed, `2.0` halves every delay.
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    #[serde(default = "default_loops")]
+    pub loops: u32,
+    /// Stop at the first error: the connection dropping or the device
+    /// replying with a non-zero code. The device's replies go to everyone
+    /// using it, so errors for another client's commands count too.
+    #[serde(default = "default_abort_on_error")]
+    pub abort_on_error: bool,
+    /// Additional devices to play on besides the one in the path.
+    #[serde(default, flatten)]
+    pub selector: DeviceSelector,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+fn default_loops() -> u32 {
+    1
+}
+
+fn default_abort_on_error() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayResult {
+    pub id: String,
+    pub steps_sent: usize,
+    pub loops_completed: u32,
+    pub error: Option<String>,
+}
+
+/// Macros live in the data directory as `<name>.jsonl`.
+#[derive(Clone)]
+pub struct MacroStore {
+    dir: PathBuf,
+}
+
+impl MacroStore {
+    pub fn new() -> Self {
+        Self {
+            dir: paths::data_dir().join("macros"),
+        }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, MacroError> {
+        if !paths::is_plain_file_name(name) {
+            return Err(MacroError::InvalidName);
+        }
+        Ok(self.dir.join(format!("{}.jsonl", name)))
+    }
+
+    pub async fn list(&self) -> Result<Vec<MacroInfo>, MacroError> {
+        let mut macros = Vec::new();
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(macros),
+            Err(err) => return Err(err.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(name) = file_name.strip_suffix(".jsonl") {
+                macros.push(MacroInfo {
+                    name: name.to_string(),
+                    size: entry.metadata().await?.len(),
+                });
+            }
+        }
+        macros.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(macros)
+    }
+
+    pub async fn load(&self, name: &str) -> Result<Vec<MacroStep>, MacroError> {
+        let file = File::open(self.path(name)?).await?;
+        let mut lines = BufReader::new(file).lines();
+        let mut steps = Vec::new();
+        let mut line_number = 0;
+
+        while let Some(line) = lines.next_line().await? {
+            line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let step = serde_json::from_str(&line).map_err(|err| MacroError::Parse {
+                line: line_number,
+                message: err.to_string(),
+            })?;
+            steps.push(step);
+        }
+
+        Ok(steps)
+    }
+
+    /// Starts a new recording. It replaces any macro with the same name
+    /// once finished, until then the old one stays playable.
+    pub async fn record(&self, name: &str) -> Result<MacroRecorder, MacroError> {
+        let path = self.path(name)?;
+        let dir = paths::ensure_dir(self.dir.clone())?;
+        // Not `.jsonl`, so `list` skips it.
+        let temp = dir.join(format!(
+            "{}.jsonl.{}.tmp",
+            name,
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        Ok(MacroRecorder {
+            file: File::create(&temp).await?,
+            temp,
+            path,
+            started: Instant::now(),
+        })
+    }
+}
+
+pub struct MacroRecorder {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    started: Instant,
+}
+
+impl MacroRecorder {
+    /// Moves the recording over the macro's file.
+    pub async fn finish(self) {
+        let Self {
+            mut file,
+            temp,
+            path,
+            ..
+        } = self;
+        let flushed = file.flush().await;
+        drop(file);
+        let result = match flushed {
+            Ok(()) => fs::rename(&temp, &path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            tracing::warn!(?err, "failed to save macro");
+            let _ = fs::remove_file(&temp).await;
+        }
+    }
+
+    pub async fn text(&mut self, text: &str) {
+        self.write(MacroStep {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            text: Some(text.to_string()),
+            binary: None,
+        })
+        .await;
+    }
+
+    pub async fn binary(&mut self, buf: &[u8]) {
+        self.write(MacroStep {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            text: None,
+            binary: Some(buf.to_vec()),
+        })
+        .await;
+    }
+
+    async fn write(&mut self, step: MacroStep) {
+        let Ok(mut line) = serde_json::to_string(&step) else {
+            return;
+        };
+        line.push('\n');
+        if let Err(err) = self.file.write_all(line.as_bytes()).await {
+            tracing::warn!(?err, "failed to write macro step");
+        }
+    }
+}
+
+/// Replays `steps` on every device in parallel, stopping early when the
+/// bridge shuts down.
+pub async fn play(
+    sessions: &ZxTouchSessions,
+    devices: Vec<IosDevice>,
+    steps: Vec<MacroStep>,
+    options: &PlayOptions,
+    shutdown: &Shutdown,
+) -> Vec<PlayResult> {
+    join_all(devices.into_iter().map(|device| {
+        play_device(
+            sessions.link(&device.id),
+            device.id,
+            &steps,
+            options,
+            shutdown,
+        )
+    }))
+    .await
+}
+
+async fn play_device(
+    link: ZxTouchLink,
+    id: String,
+    steps: &[MacroStep],
+    options: &PlayOptions,
+    shutdown: &Shutdown,
+) -> PlayResult {
+    let mut result = PlayResult {
+        id,
+        steps_sent: 0,
+        loops_completed: 0,
+        error: None,
+    };
+
+    // Wait for the session's first connection attempt.
+    let mut replies = link.replies();
+    let mut state = link.state();
+    let connected = tokio::select! {
+        _ = shutdown.wait() => Err(CLOSE_REASON.to_string()),
+        state = state.wait_for(|state| *state != LinkState::Connecting) => match state {
+            Ok(state) => match &*state {
+                LinkState::Reconnecting { error, .. } => Err(error.clone()),
+                _ => Ok(()),
+            },
+            Err(_) => Err(SESSION_CLOSED.to_string()),
+        },
+    };
+    if let Err(error) = connected {
+        result.error = Some(error);
+        return result;
+    }
+
+    let speed = if options.speed > 0.0 {
+        options.speed
+    } else {
+        1.0
+    };
+
+    'play: for _ in 0..options.loops {
+        let started = Instant::now();
+        for step in steps {
+            let due = Duration::from_secs_f64(step.at_ms as f64 / 1000.0 / speed);
+            let waited = wait_until(started + due, &mut replies, &mut result, options, shutdown);
+            if waited.await.is_break() {
+                break 'play;
+            }
+
+            // Commands queued while the session reconnects are sent once it's
+            // back, but the device may have missed some of them.
+            if let LinkState::Reconnecting { error, .. } = &*state.borrow() {
+                result.error = Some(error.clone());
+                if options.abort_on_error {
+                    return result;
+                }
+            }
+            if !link.send(step.payload().into()).await {
+                result.error = Some(SESSION_CLOSED.to_string());
+                return result;
+            }
+            result.steps_sent += 1;
+        }
+        result.loops_completed += 1;
+    }
+
+    if result.loops_completed == options.loops {
+        let deadline = Instant::now() + REPLY_WAIT;
+        let _ = wait_until(deadline, &mut replies, &mut result, options, shutdown).await;
+    }
+    result
+}
+
+/// Waits for the next step, watching the device's replies meanwhile.
+/// Breaks on shutdown, or on a device error when errors abort.
+async fn wait_until(
+    deadline: Instant,
+    replies: &mut broadcast::Receiver<Bytes>,
+    result: &mut PlayResult,
+    options: &PlayOptions,
+    shutdown: &Shutdown,
+) -> ControlFlow<()> {
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => {
+                result.error = Some(CLOSE_REASON.to_string());
+                return ControlFlow::Break(());
+            }
+            _ = tokio::time::sleep_until(deadline.into()) => return ControlFlow::Continue(()),
+            Ok(data) = replies.recv() => {
+                if let Some(error) = device_error(&data) {
+                    result.error = Some(error);
+                    if options.abort_on_error {
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The first non-zero `code;;message` line in a reply.
+fn device_error(data: &[u8]) -> Option<String> {
+    data.split(|byte| *byte == b'\n')
+        .filter_map(|line| ios_agent::decode_line(line).ok())
+        .find(|reply| reply.code != 0)
+        .map(|reply| {
+            let message = String::from_utf8_lossy(&reply.body);
+            format!("device error {}: {}", reply.code, message.trim())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_the_old_macro_until_the_recording_finishes() {
+        let store = MacroStore {
+            dir: std::env::temp_dir().join(format!("tango-macros-{}", std::process::id())),
+        };
+        let mut first = store.record("tap").await.unwrap();
+        first.text("first").await;
+        first.finish().await;
+
+        let mut second = store.record("tap").await.unwrap();
+        second.text("second").await;
+        let listed = store.list().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            store.load("tap").await.unwrap()[0].text.as_deref(),
+            Some("first")
+        );
+
+        second.finish().await;
+        assert_eq!(
+            store.load("tap").await.unwrap()[0].text.as_deref(),
+            Some("second")
+        );
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+
+    #[tokio::test]
+    async fn rejects_binary_steps_that_are_not_base64() {
+        let store = MacroStore {
+            dir: std::env::temp_dir().join(format!("tango-macros-b64-{}", std::process::id())),
+        };
+        let mut recorder = store.record("binary").await.unwrap();
+        recorder.binary(&[0, 1, 255]).await;
+        recorder.finish().await;
+        assert_eq!(
+            store.load("binary").await.unwrap()[0].payload(),
+            [0, 1, 255]
+        );
+
+        std::fs::write(
+            store.path("broken").unwrap(),
+            "{\"at_ms\":0,\"text\":\"tap\"}\n{\"at_ms\":5,\"binary\":\"not base64!\"}\n",
+        )
+        .unwrap();
+        let err = store.load("broken").await.unwrap_err();
+        assert!(
+            matches!(err, MacroError::Parse { line: 2, .. }),
+            "{:?}",
+            err
+        );
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+
+    #[test]
+    fn finds_errors_in_device_replies() {
+        assert_eq!(device_error(b"0;;\r\n"), None);
+        assert_eq!(device_error(b""), None);
+        assert_eq!(
+            device_error(b"0;;\r\n-1;;no such script\r\n").as_deref(),
+            Some("device error -1: no such script")
+        );
+    }
+}
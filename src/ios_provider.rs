@@ -1,39 +1,193 @@
 use serde::{Deserialize, Serialize};
-use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use serde_json::{Map, Value};
+use std::{
+    net::Ipv4Addr,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::{task::JoinHandle, time::sleep};
+use tracing::Instrument;
 
 use crate::{
+    config::ConfigStore,
     ios_lan_scanner::{local_ipv4, IosLanScanner},
     registry::DeviceRegistry,
+    shutdown::Shutdown,
 };
 
+/// Hello protocol version assumed for agents that don't report one.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// Status reply of the phone agent.
+///
+/// Agents in the field differ in which fields they send and in the JSON type
+/// of some of them, so every field falls back to its default when missing or
+/// malformed, and unknown fields are kept in `extra`.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct HelloStatusPayload {
+    #[serde(
+        default = "legacy_protocol_version",
+        alias = "protocol",
+        deserialize_with = "lenient::u32_or_legacy"
+    )]
+    pub protocol_version: u32,
+    #[serde(default, deserialize_with = "lenient::object")]
     pub zxtouch: ZxTouch,
+    #[serde(default, deserialize_with = "lenient::object")]
     pub device: IosDeviceInfo,
+    #[serde(default, deserialize_with = "lenient::object")]
     pub script: ScriptStatus,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct ZxTouch {
+    /// `0` until `HelloStatusPayload::reached_on` fills in the port the agent
+    /// answered on, for agents that don't say.
+    #[serde(default, deserialize_with = "lenient::port")]
     pub port: u16,
+    #[serde(default, deserialize_with = "lenient::strings")]
     pub protocols: Vec<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct IosDeviceInfo {
+    #[serde(default, deserialize_with = "lenient::string")]
     pub system_name: String,
+    #[serde(default, deserialize_with = "lenient::string")]
     pub model: String,
+    #[serde(default, deserialize_with = "lenient::string")]
     pub name: String,
+    #[serde(default, deserialize_with = "lenient::string")]
     pub system_version: String,
+    /// Screen size in ZXTouch points, zero when the agent doesn't report it.
+    #[serde(default, deserialize_with = "lenient::u32")]
+    pub screen_width: u32,
+    #[serde(default, deserialize_with = "lenient::u32")]
+    pub screen_height: u32,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct ScriptStatus {
+    #[serde(default, deserialize_with = "lenient::bool")]
     pub is_playing: bool,
+    #[serde(default, deserialize_with = "lenient::string")]
     pub last_error: String,
+    #[serde(default, deserialize_with = "lenient::i64")]
     pub last_error_ts: i64,
+    #[serde(default, deserialize_with = "lenient::string")]
     pub bundle_path: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+fn legacy_protocol_version() -> u32 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+impl HelloStatusPayload {
+    /// Takes `port`, where the agent was reached, as its ZXTouch port unless
+    /// it reported one.
+    pub fn reached_on(mut self, port: u16) -> Self {
+        if self.zxtouch.port == 0 {
+            self.zxtouch.port = port;
+        }
+        self
+    }
+}
+
+/// Field deserializers that accept any JSON shape and fall back to the
+/// default instead of failing the whole payload.
+mod lenient {
+    use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+    use serde_json::Value;
+
+    pub fn object<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: DeserializeOwned + Default,
+    {
+        match Value::deserialize(deserializer)? {
+            value @ Value::Object(_) => Ok(serde_json::from_value(value).unwrap_or_default()),
+            _ => Ok(T::default()),
+        }
+    }
+
+    pub fn string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        Ok(match Value::deserialize(deserializer)? {
+            Value::String(value) => value,
+            Value::Number(value) => value.to_string(),
+            Value::Bool(value) => value.to_string(),
+            _ => String::new(),
+        })
+    }
+
+    pub fn strings<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+        Ok(match Value::deserialize(deserializer)? {
+            Value::Array(values) => values
+                .into_iter()
+                .filter_map(|value| match value {
+                    Value::String(value) => Some(value),
+                    _ => None,
+                })
+                .collect(),
+            Value::String(value) => value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    pub fn bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        Ok(match Value::deserialize(deserializer)? {
+            Value::Bool(value) => value,
+            Value::Number(value) => value.as_f64().is_some_and(|value| value != 0.0),
+            Value::String(value) => matches!(value.trim(), "1" | "true" | "yes"),
+            _ => false,
+        })
+    }
+
+    fn number(value: Value) -> Option<f64> {
+        match value {
+            Value::Number(value) => value.as_f64(),
+            Value::String(value) => value.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn i64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+        Ok(number(Value::deserialize(deserializer)?)
+            .map(|value| value as i64)
+            .unwrap_or_default())
+    }
+
+    pub fn u32<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        Ok(number(Value::deserialize(deserializer)?)
+            .filter(|value| *value >= 0.0)
+            .map(|value| value as u32)
+            .unwrap_or_default())
+    }
+
+    pub fn port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+        Ok(number(Value::deserialize(deserializer)?)
+            .filter(|value| (1.0..=65535.0).contains(value))
+            .map(|value| value as u16)
+            .unwrap_or_default())
+    }
+
+    pub fn u32_or_legacy<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        Ok(number(Value::deserialize(deserializer)?)
+            .filter(|value| *value >= 0.0)
+            .map(|value| value as u32)
+            .unwrap_or_else(super::legacy_protocol_version))
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -44,32 +198,140 @@ pub struct IosDevice {
     pub status: HelloStatusPayload,
 }
 
+impl IosDevice {
+    /// Tags usable in device selectors: the model, the iOS version and
+    /// whatever the agent lists in its `tags` field.
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags = vec![
+            format!("model:{}", self.status.device.model),
+            format!("ios:{}", self.status.device.system_version),
+        ];
+        if let Some(Value::Array(extra)) = self.status.extra.get("tags") {
+            tags.extend(
+                extra
+                    .iter()
+                    .filter_map(|tag| tag.as_str().map(str::to_string)),
+            );
+        }
+        tags
+    }
+
+    /// `UnifiedDevice.meta`, with top-level fields this bridge doesn't know
+    /// about yet passed through.
+    pub fn meta(&self) -> Value {
+        let mut meta = serde_json::json!({
+            "ip": self.ip,
+            "protocol_version": self.status.protocol_version,
+            "device": self.status.device,
+            "zxtouch": self.status.zxtouch,
+            "script": self.status.script,
+        });
+        if let Some(meta) = meta.as_object_mut() {
+            for (key, value) in &self.status.extra {
+                meta.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        meta
+    }
+
+    pub fn screen_size(&self) -> (u32, u32) {
+        (
+            self.status.device.screen_width,
+            self.status.device.screen_height,
+        )
+    }
+}
+
+/// How the last sweep went, for `/bridge/health`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanReport {
+    /// Unix time in milliseconds of the last finished sweep.
+    pub last_scan_at: Option<u64>,
+    pub duration_ms: u64,
+    /// The local address the subnet is taken from.
+    pub interface: Option<Ipv4Addr>,
+    pub devices_found: usize,
+    /// Why the last attempt couldn't sweep, cleared by the next sweep.
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct ScanStatus(Arc<StdMutex<ScanReport>>);
+
+impl ScanStatus {
+    pub fn get(&self) -> ScanReport {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn scanned(&self, interface: Ipv4Addr, duration: Duration, devices_found: usize) {
+        *self.0.lock().unwrap() = ScanReport {
+            last_scan_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .ok(),
+            duration_ms: duration.as_millis() as u64,
+            interface: Some(interface),
+            devices_found,
+            last_error: None,
+        };
+    }
+
+    fn failed(&self, err: String) {
+        self.0.lock().unwrap().last_error = Some(err);
+    }
+}
+
 pub struct IosProvider {
     registry: Arc<DeviceRegistry>,
     scanner: IosLanScanner,
-    interval: Duration,
+    config: ConfigStore,
+    status: ScanStatus,
 }
 
 impl IosProvider {
-    pub fn new(registry: Arc<DeviceRegistry>, scanner: IosLanScanner, interval: Duration) -> Self {
+    pub fn new(registry: Arc<DeviceRegistry>, scanner: IosLanScanner, config: ConfigStore) -> Self {
         Self {
             registry,
             scanner,
-            interval,
+            config,
+            status: ScanStatus::default(),
         }
     }
 
-    pub fn start(self) -> JoinHandle<()> {
-        tokio::spawn(async move {
-            loop {
-                if let Ok(local_ip) = local_ipv4() {
-                    let subnet = subnet_base(local_ip);
-                    let devices = self.scanner.scan_subnet(subnet).await;
-                    self.registry.update_ios_devices(devices).await;
+    pub fn status(&self) -> ScanStatus {
+        self.status.clone()
+    }
+
+    pub fn start(self, shutdown: &Shutdown) -> JoinHandle<()> {
+        let token = shutdown.token();
+        shutdown.spawn(
+            async move {
+                loop {
+                    match local_ipv4() {
+                        Ok(local_ip) => {
+                            let subnet = subnet_base(local_ip);
+                            let started = Instant::now();
+                            let devices = tokio::select! {
+                                _ = token.cancelled() => break,
+                                devices = self.scanner.scan_subnet(subnet) => devices,
+                            };
+                            self.status
+                                .scanned(local_ip, started.elapsed(), devices.len());
+                            self.registry.update_ios_devices(devices).await;
+                        }
+                        Err(err) => {
+                            self.status
+                                .failed(format!("no local IPv4 address: {}", err));
+                        }
+                    }
+                    tokio::select! {
+                        _ = token.cancelled() => break,
+                        _ = sleep(self.config.get().ios.scan_interval()) => {}
+                    }
                 }
-                sleep(self.interval).await;
             }
-        })
+            .instrument(tracing::info_span!("ios_provider")),
+        )
     }
 }
 
@@ -77,3 +339,129 @@ fn subnet_base(ip: Ipv4Addr) -> Ipv4Addr {
     let octets = ip.octets();
     Ipv4Addr::new(octets[0], octets[1], octets[2], 0)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{ios_agent::AgentReply, ios_lan_scanner::ProbeError};
+
+    /// The `synthetic_*` fixtures are written by hand after what each
+    /// generation of agents sends, not captured from devices.
+    fn parse(fixture: &str) -> HelloStatusPayload {
+        let reply = AgentReply {
+            code: 0,
+            body: fixture.as_bytes().to_vec(),
+        };
+        reply.json().unwrap()
+    }
+
+    fn device(status: HelloStatusPayload) -> IosDevice {
+        IosDevice {
+            id: "ios:192.168.1.20".to_string(),
+            display_name: status.device.name.clone(),
+            ip: "192.168.1.20".to_string(),
+            status,
+        }
+    }
+
+    #[test]
+    fn parses_synthetic_legacy_agents() {
+        let status = parse(include_str!(
+            "../tests/fixtures/hello/synthetic_legacy.json"
+        ))
+        .reached_on(6100);
+        assert_eq!(status.protocol_version, LEGACY_PROTOCOL_VERSION);
+        // Reported as a string, and it wins over the port probed.
+        assert_eq!(status.zxtouch.port, 6000);
+        assert!(status.zxtouch.protocols.is_empty());
+        assert_eq!(status.device.model, "iPhone10,4");
+        assert_eq!(
+            (status.device.screen_width, status.device.screen_height),
+            (375, 667)
+        );
+        assert!(status.script.is_playing);
+        assert_eq!(status.script.last_error, "");
+        assert_eq!(status.script.last_error_ts, 0);
+        assert!(status.extra.is_empty());
+    }
+
+    #[test]
+    fn parses_synthetic_current_agents() {
+        let status = parse(include_str!(
+            "../tests/fixtures/hello/synthetic_current.json"
+        ));
+        assert_eq!(status.protocol_version, 2);
+        assert_eq!(status.zxtouch.protocols, ["touch", "text", "screenshot"]);
+        assert_eq!(status.device.system_version, "16.7.2");
+        assert!(!status.script.is_playing);
+        assert!(status.extra.is_empty());
+    }
+
+    #[test]
+    fn parses_synthetic_future_agents() {
+        let status = parse(include_str!(
+            "../tests/fixtures/hello/synthetic_future.json"
+        ))
+        .reached_on(6000);
+        // `protocol` is an alias of `protocol_version`.
+        assert_eq!(status.protocol_version, 3);
+        assert_eq!(status.zxtouch.port, 6001);
+        assert_eq!(
+            status.zxtouch.protocols,
+            ["touch", "text", "screenshot", "record"]
+        );
+        assert_eq!(status.zxtouch.extra["tls"], json!(true));
+        assert_eq!(status.device.screen_width, 375);
+        assert_eq!(status.device.extra["scale"], json!(2));
+        assert!(status.script.is_playing);
+        // Not a string, so it falls back instead of failing the payload.
+        assert_eq!(status.script.last_error, "");
+        assert_eq!(status.script.last_error_ts, 1760812345);
+        assert_eq!(status.script.extra["queue"], json!(["a.bdl", "b.bdl"]));
+    }
+
+    #[test]
+    fn passes_unknown_fields_through_to_meta() {
+        let device = device(parse(include_str!(
+            "../tests/fixtures/hello/synthetic_future.json"
+        )));
+        let meta = device.meta();
+        assert_eq!(meta["ip"], "192.168.1.20");
+        assert_eq!(meta["protocol_version"], 3);
+        assert_eq!(meta["battery"], json!({ "level": 0.81, "charging": true }));
+        assert_eq!(meta["device"]["scale"], json!(2));
+        assert!(device.tags().contains(&"rack:2".to_string()));
+    }
+
+    #[test]
+    fn known_fields_win_over_extra() {
+        let mut status = parse(include_str!(
+            "../tests/fixtures/hello/synthetic_current.json"
+        ));
+        status.extra.insert("ip".to_string(), json!("10.0.0.1"));
+        assert_eq!(device(status).meta()["ip"], "192.168.1.20");
+    }
+
+    #[test]
+    fn defaults_a_bare_object() {
+        let status = parse("{}");
+        assert_eq!(status.protocol_version, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(status.zxtouch.port, 0);
+        assert_eq!(status.device.model, "");
+        assert_eq!(status.reached_on(6100).zxtouch.port, 6100);
+    }
+
+    #[test]
+    fn fails_only_on_a_body_that_is_not_an_object() {
+        for body in ["", "not json", "{\"device\": {", "[]", "\"ok\""] {
+            let reply = AgentReply {
+                code: 0,
+                body: body.as_bytes().to_vec(),
+            };
+            let result = reply.json::<HelloStatusPayload>();
+            assert!(matches!(result, Err(ProbeError::Json { .. })), "{:?}", body);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::{io, sync::Arc, time::Duration};
+
+use axum::body::Bytes;
+use futures_util::{future::join_all, stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ios_provider::IosDevice,
+    paths,
+    registry::{DeviceEvent, DeviceRegistry},
+};
+
+/// Port of the HTTP service the phone agent uses for file transfer.
+const AGENT_HTTP_PORT: u16 = 80;
+
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InstalledBundle {
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ScriptError {
+    InvalidBundle { message: String },
+    Request { message: String },
+    Status { code: u16, message: String },
+    ChecksumMismatch { expected: String, actual: String },
+}
+
+impl From<reqwest::Error> for ScriptError {
+    fn from(err: reqwest::Error) -> Self {
+        ScriptError::Request {
+            message: err.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadProgress {
+    pub id: String,
+    pub name: String,
+    pub sent: u64,
+    pub total: u64,
+    pub done: bool,
+    pub error: Option<ScriptError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadResult {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub error: Option<ScriptError>,
+}
+
+#[derive(Deserialize)]
+struct UploadReply {
+    #[serde(default)]
+    sha256: String,
+}
+
+/// Pushes script bundles to the phone agent and lists what is installed.
+#[derive(Clone)]
+pub struct ScriptUploader {
+    client: reqwest::Client,
+    registry: Arc<DeviceRegistry>,
+}
+
+impl ScriptUploader {
+    pub fn new(registry: Arc<DeviceRegistry>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            registry,
+        }
+    }
+
+    pub async fn list(&self, device: &IosDevice) -> Result<Vec<InstalledBundle>, ScriptError> {
+        let url = format!("http://{}:{}/scripts", device.ip, AGENT_HTTP_PORT);
+        let response = self
+            .client
+            .get(url)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?;
+        Ok(check_status(response).await?.json().await?)
+    }
+
+    /// Uploads one bundle to every device in parallel.
+    pub async fn upload_many(
+        &self,
+        devices: &[IosDevice],
+        name: &str,
+        bundle: Bytes,
+    ) -> Result<Vec<UploadResult>, ScriptError> {
+        let sha256 = validate_bundle(name, &bundle)?;
+        Ok(join_all(
+            devices
+                .iter()
+                .map(|device| self.upload_checked(device, name, bundle.clone(), &sha256)),
+        )
+        .await)
+    }
+
+    pub async fn upload(
+        &self,
+        device: &IosDevice,
+        name: &str,
+        bundle: Bytes,
+    ) -> Result<UploadResult, ScriptError> {
+        let sha256 = validate_bundle(name, &bundle)?;
+        Ok(self.upload_checked(device, name, bundle, &sha256).await)
+    }
+
+    async fn upload_checked(
+        &self,
+        device: &IosDevice,
+        name: &str,
+        bundle: Bytes,
+        sha256: &str,
+    ) -> UploadResult {
+        let size = bundle.len() as u64;
+        let error = self.send_bundle(device, name, bundle, sha256).await.err();
+
+        self.registry
+            .publish(DeviceEvent::ScriptUpload(UploadProgress {
+                id: device.id.clone(),
+                name: name.to_string(),
+                sent: if error.is_none() { size } else { 0 },
+                total: size,
+                done: true,
+                error: error.clone(),
+            }));
+
+        UploadResult {
+            id: device.id.clone(),
+            name: name.to_string(),
+            size,
+            sha256: sha256.to_string(),
+            error,
+        }
+    }
+
+    async fn send_bundle(
+        &self,
+        device: &IosDevice,
+        name: &str,
+        bundle: Bytes,
+        sha256: &str,
+    ) -> Result<(), ScriptError> {
+        let total = bundle.len() as u64;
+        let registry = self.registry.clone();
+        let id = device.id.clone();
+        let bundle_name = name.to_string();
+
+        // Report progress as the HTTP client pulls chunks from the body.
+        let chunks = stream::iter((0..bundle.len()).step_by(UPLOAD_CHUNK_SIZE)).map(move |start| {
+            let end = (start + UPLOAD_CHUNK_SIZE).min(bundle.len());
+            registry.publish(DeviceEvent::ScriptUpload(UploadProgress {
+                id: id.clone(),
+                name: bundle_name.clone(),
+                sent: end as u64,
+                total,
+                done: false,
+                error: None,
+            }));
+            Ok::<_, io::Error>(bundle.slice(start..end))
+        });
+
+        let url = format!("http://{}:{}/scripts/{}", device.ip, AGENT_HTTP_PORT, name);
+        let response = self
+            .client
+            .put(url)
+            .timeout(UPLOAD_TIMEOUT)
+            .header(http::header::CONTENT_TYPE, "application/zip")
+            .header(http::header::CONTENT_LENGTH, total)
+            .header("X-Content-Sha256", sha256)
+            .body(reqwest::Body::wrap_stream(chunks))
+            .send()
+            .await?;
+
+        let reply: UploadReply = check_status(response).await?.json().await?;
+        if !reply.sha256.eq_ignore_ascii_case(sha256) {
+            return Err(ScriptError::ChecksumMismatch {
+                expected: sha256.to_string(),
+                actual: reply.sha256,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, ScriptError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    Err(ScriptError::Status {
+        code: status.as_u16(),
+        message: response.text().await.unwrap_or_default(),
+    })
+}
+
+/// Checks the bundle name and zip signature, and returns its SHA-256.
+fn validate_bundle(name: &str, bundle: &[u8]) -> Result<String, ScriptError> {
+    let invalid = |message: &str| ScriptError::InvalidBundle {
+        message: message.to_string(),
+    };
+
+    if !name.ends_with(".zip") || !paths::is_plain_file_name(name) {
+        return Err(invalid("bundle name must be a plain `.zip` file name"));
+    }
+
+    if !bundle.starts_with(b"PK\x03\x04") {
+        return Err(invalid("bundle is not a zip archive"));
+    }
+
+    Ok(format!("{:x}", Sha256::digest(bundle)))
+}
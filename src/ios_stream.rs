@@ -0,0 +1,465 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpStream,
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    backoff::Backoff,
+    config::ConfigStore,
+    metrics::{metrics, DeviceGauge},
+    mpegts::KeyframeGate,
+    registry::DeviceRegistry,
+    shutdown::Shutdown,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Frames queued for the viewer before the relay waits on it.
+const SEND_QUEUE: usize = 32;
+
+/// How long the send queue may stay mostly full before the relay falls back
+/// to the eco stream on its own.
+const CONGESTION_GRACE: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    Full,
+    Eco,
+}
+
+/// What the viewer asked for, updated by JSON text messages such as
+/// `{"quality":"eco"}` or `{"auto_eco":false}`.
+#[derive(Debug, Clone, Copy)]
+struct ViewerSettings {
+    quality: Quality,
+    auto_eco: bool,
+}
+
+#[derive(Deserialize)]
+struct ViewerControl {
+    #[serde(default)]
+    quality: Option<Quality>,
+    #[serde(default)]
+    auto_eco: Option<bool>,
+}
+
+/// Control messages sent to the viewer as text frames, next to the binary
+/// MPEG-TS frames.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamControl {
+    Reconnecting {
+        attempt: u32,
+        retry_in_ms: u64,
+        error: String,
+    },
+    Resumed,
+    Quality {
+        quality: Quality,
+        reason: &'static str,
+    },
+}
+
+impl StreamControl {
+    fn message(&self) -> Message {
+        Message::text(serde_json::to_string(self).unwrap())
+    }
+}
+
+struct Upstream {
+    stream: TcpStream,
+    quality: Quality,
+    /// Holds data back until a keyframe, `None` once the stream flows freely.
+    gate: Option<KeyframeGate>,
+    _open: DeviceGauge,
+}
+
+impl Upstream {
+    fn new(stream: TcpStream, quality: Quality, gate: Option<KeyframeGate>, id: &str) -> Self {
+        Self {
+            stream,
+            quality,
+            gate,
+            _open: DeviceGauge::new(&metrics().stream_upstreams, id),
+        }
+    }
+
+    /// Returns the bytes that may be forwarded, empty while still gated.
+    fn accept(&mut self, data: &[u8]) -> Vec<u8> {
+        match &mut self.gate {
+            Some(gate) => {
+                let data = gate.push(data);
+                if gate.is_open() {
+                    self.gate = None;
+                }
+                data
+            }
+            None => data.to_vec(),
+        }
+    }
+}
+
+enum Event {
+    Cancelled,
+    Current(std::io::Result<usize>),
+    Pending(std::io::Result<usize>),
+    Connected(Quality, Result<TcpStream, String>),
+    Settings,
+}
+
+/// Relays the MPEG-TS stream of a device to a viewer, reconnecting the
+/// upstream for as long as the viewer stays connected and switching between
+/// the full and eco streams on request.
+#[tracing::instrument(name = "ios_stream", skip_all, fields(device = %id))]
+pub async fn handle_ios_stream(
+    ws: WebSocket,
+    registry: Arc<DeviceRegistry>,
+    config: ConfigStore,
+    shutdown: Shutdown,
+    id: String,
+    quality: Quality,
+) {
+    let _viewer = DeviceGauge::new(&metrics().stream_viewers, &id);
+    let (mut ws_writer, mut ws_reader) = ws.split();
+    let cancel = shutdown.child_token();
+    let (settings_sender, mut settings) = watch::channel(ViewerSettings {
+        quality,
+        auto_eco: true,
+    });
+    let settings_sender = Arc::new(settings_sender);
+    let (queue, mut queue_receiver) = mpsc::channel::<Message>(SEND_QUEUE);
+
+    let ws_read_task = tokio::spawn({
+        let cancel = cancel.clone();
+        let settings_sender = settings_sender.clone();
+        async move {
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    message = ws_reader.next() => {
+                        match message {
+                            Some(Ok(Message::Text(text))) => {
+                                let Ok(control) = serde_json::from_str::<ViewerControl>(&text) else {
+                                    continue;
+                                };
+                                settings_sender.send_modify(|settings| {
+                                    if let Some(quality) = control.quality {
+                                        settings.quality = quality;
+                                    }
+                                    if let Some(auto_eco) = control.auto_eco {
+                                        settings.auto_eco = auto_eco;
+                                    }
+                                });
+                            }
+                            Some(Ok(Message::Close(_))) | None => break,
+                            Some(Ok(_)) => {}
+                            Some(Err(_)) => break,
+                        }
+                    }
+                }
+            }
+            cancel.cancel();
+        }
+    });
+
+    let ws_write_task = tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            loop {
+                let message = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    message = queue_receiver.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                };
+                if ws_writer.send(message).await.is_err() {
+                    break;
+                }
+            }
+            cancel.cancel();
+            shutdown.close(&mut ws_writer).await;
+        }
+    });
+
+    let mut backoff = Backoff::new(Duration::from_millis(250), Duration::from_secs(5));
+    let mut current: Option<Upstream> = None;
+    let mut pending: Option<Upstream> = None;
+    let mut connecting: Option<(Quality, JoinHandle<Result<TcpStream, String>>)> = None;
+    let mut congested_since: Option<Instant> = None;
+    // The first connection is forwarded as is, later ones wait for a keyframe.
+    let mut resumed = false;
+    let mut buf = vec![0u8; 8 * 1024];
+    let mut pending_buf = vec![0u8; 8 * 1024];
+
+    loop {
+        if current.is_none() {
+            let quality = settings.borrow().quality;
+            let result = tokio::select! {
+                _ = cancel.cancelled() => break,
+                result = connect(&registry, &config, &id, quality) => result,
+            };
+
+            match result {
+                Ok(stream) => {
+                    let gate = resumed.then(KeyframeGate::default);
+                    current = Some(Upstream::new(stream, quality, gate, &id));
+                }
+                Err(error) => {
+                    if !reconnect_delay(&queue, &cancel, &mut backoff, error).await {
+                        break;
+                    }
+                    continue;
+                }
+            }
+        }
+        let upstream = current.as_mut().unwrap();
+
+        // Open the other stream in the background once the viewer asks for
+        // it, the switch happens at its first keyframe.
+        let wanted = settings.borrow_and_update().quality;
+        let replacement = pending
+            .as_ref()
+            .map(|pending| pending.quality)
+            .or(connecting.as_ref().map(|(quality, _)| *quality));
+        if wanted == upstream.quality {
+            pending = None;
+            if let Some((_, handle)) = connecting.take() {
+                handle.abort();
+            }
+        } else if replacement != Some(wanted) {
+            pending = None;
+            if let Some((_, handle)) = connecting.take() {
+                handle.abort();
+            }
+            let registry = registry.clone();
+            let config = config.clone();
+            let id = id.clone();
+            connecting = Some((
+                wanted,
+                tokio::spawn(async move { connect(&registry, &config, &id, wanted).await }),
+            ));
+        }
+
+        let event = tokio::select! {
+            _ = cancel.cancelled() => Event::Cancelled,
+            result = upstream.stream.read(&mut buf) => Event::Current(result),
+            result = read_pending(&mut pending, &mut pending_buf), if pending.is_some() => {
+                Event::Pending(result)
+            }
+            (quality, result) = join_connecting(&mut connecting), if connecting.is_some() => {
+                Event::Connected(quality, result)
+            }
+            changed = settings.changed() => match changed {
+                Ok(()) => Event::Settings,
+                Err(_) => Event::Cancelled,
+            },
+        };
+
+        match event {
+            Event::Cancelled => break,
+            Event::Settings => {}
+            Event::Connected(quality, Ok(stream)) => {
+                connecting = None;
+                let gate = Some(KeyframeGate::default());
+                pending = Some(Upstream::new(stream, quality, gate, &id));
+            }
+            Event::Connected(quality, Err(error)) => {
+                connecting = None;
+                pending = None;
+                tracing::debug!(id, ?quality, error, "ios stream switch failed");
+                // Stay on the current stream and let the viewer retry.
+                let current_quality = upstream.quality;
+                settings_sender.send_modify(|settings| settings.quality = current_quality);
+                let control = StreamControl::Quality {
+                    quality: current_quality,
+                    reason: "switch_failed",
+                };
+                if queue.send(control.message()).await.is_err() {
+                    break;
+                }
+            }
+            Event::Pending(Ok(n)) if n > 0 => {
+                let next = pending.as_mut().unwrap();
+                let data = next.accept(&pending_buf[..n]);
+                if data.is_empty() {
+                    continue;
+                }
+
+                let next = pending.take().unwrap();
+                let quality = next.quality;
+                current = Some(next);
+                congested_since = None;
+                metrics().relayed("ios_stream", "to_client", data.len());
+                let control = StreamControl::Quality {
+                    quality,
+                    reason: "switched",
+                };
+                if queue.send(control.message()).await.is_err()
+                    || queue.send(Message::binary(data)).await.is_err()
+                {
+                    break;
+                }
+            }
+            Event::Pending(result) => {
+                // The replacement died before its first keyframe.
+                let error = match result {
+                    Ok(_) => "connection closed by device".to_string(),
+                    Err(err) => err.to_string(),
+                };
+                let quality = pending.take().map(|pending| pending.quality);
+                tracing::debug!(id, ?quality, error, "ios stream switch failed");
+                let current_quality = upstream.quality;
+                settings_sender.send_modify(|settings| settings.quality = current_quality);
+                let control = StreamControl::Quality {
+                    quality: current_quality,
+                    reason: "switch_failed",
+                };
+                if queue.send(control.message()).await.is_err() {
+                    break;
+                }
+            }
+            Event::Current(result) => {
+                let n = match result {
+                    Ok(0) => Err("connection closed by device".to_string()),
+                    Ok(n) => Ok(n),
+                    Err(err) => Err(err.to_string()),
+                };
+                let n = match n {
+                    Ok(n) => n,
+                    Err(error) => {
+                        tracing::debug!(id, error, "ios stream upstream lost");
+                        current = None;
+                        pending = None;
+                        resumed = true;
+                        if !reconnect_delay(&queue, &cancel, &mut backoff, error).await {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                let was_gated = upstream.gate.is_some();
+                let data = upstream.accept(&buf[..n]);
+                if data.is_empty() {
+                    continue;
+                }
+                backoff.reset();
+                metrics().relayed("ios_stream", "to_client", data.len());
+                if was_gated && queue.send(StreamControl::Resumed.message()).await.is_err() {
+                    break;
+                }
+                if queue.send(Message::binary(data)).await.is_err() {
+                    break;
+                }
+
+                // Fall back to eco when the viewer can't keep up.
+                if queue.capacity() < SEND_QUEUE / 4 {
+                    let since = *congested_since.get_or_insert_with(Instant::now);
+                    let current_settings = *settings.borrow();
+                    if since.elapsed() > CONGESTION_GRACE
+                        && current_settings.auto_eco
+                        && current_settings.quality == Quality::Full
+                    {
+                        tracing::debug!(id, "ios stream congested, switching to eco");
+                        congested_since = None;
+                        settings_sender.send_modify(|settings| settings.quality = Quality::Eco);
+                        let control = StreamControl::Quality {
+                            quality: Quality::Eco,
+                            reason: "congested",
+                        };
+                        if queue.send(control.message()).await.is_err() {
+                            break;
+                        }
+                    }
+                } else {
+                    congested_since = None;
+                }
+            }
+        }
+    }
+
+    cancel.cancel();
+    if let Some((_, handle)) = connecting {
+        handle.abort();
+    }
+    drop(queue);
+    let _ = tokio::join!(ws_read_task, ws_write_task);
+}
+
+async fn read_pending(pending: &mut Option<Upstream>, buf: &mut [u8]) -> std::io::Result<usize> {
+    match pending {
+        Some(pending) => pending.stream.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn join_connecting(
+    connecting: &mut Option<(Quality, JoinHandle<Result<TcpStream, String>>)>,
+) -> (Quality, Result<TcpStream, String>) {
+    match connecting {
+        Some((quality, handle)) => {
+            let result = handle.await.unwrap_or_else(|err| Err(err.to_string()));
+            (*quality, result)
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Tells the viewer about the reconnect and waits before the next attempt.
+/// Returns `false` when the session should end instead.
+async fn reconnect_delay(
+    queue: &mpsc::Sender<Message>,
+    cancel: &CancellationToken,
+    backoff: &mut Backoff,
+    error: String,
+) -> bool {
+    let delay = backoff.next_delay();
+    let control = StreamControl::Reconnecting {
+        attempt: backoff.attempt(),
+        retry_in_ms: delay.as_millis() as u64,
+        error,
+    };
+    if queue.send(control.message()).await.is_err() {
+        return false;
+    }
+
+    tokio::select! {
+        _ = cancel.cancelled() => false,
+        _ = tokio::time::sleep(delay) => true,
+    }
+}
+
+async fn connect(
+    registry: &DeviceRegistry,
+    config: &ConfigStore,
+    id: &str,
+    quality: Quality,
+) -> Result<TcpStream, String> {
+    // Look the device up on every attempt, its address may have changed.
+    let device = registry
+        .get_ios_device(id)
+        .await
+        .ok_or_else(|| "device offline".to_string())?;
+
+    let addr = (device.ip, config.get().ios.stream_port(quality));
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| "connect timeout".to_string())?
+        .map_err(|err| err.to_string())?;
+    // Reduce latency for small writes.
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
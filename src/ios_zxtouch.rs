@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    extract::ws::{Message, WebSocket},
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    backoff::Backoff, ios_macros::MacroRecorder, metrics::metrics, registry::DeviceRegistry,
+    shutdown::Shutdown,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Commands buffered while the upstream is reconnecting.
+const COMMAND_QUEUE: usize = 256;
+
+/// State of the upstream connection, sent to clients as text frames.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LinkState {
+    Connecting,
+    Connected,
+    Reconnecting {
+        attempt: u32,
+        retry_in_ms: u64,
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlFrame<'a> {
+    State(&'a LinkState),
+    QueueFull,
+}
+
+/// A client command on its way to the device.
+struct Command {
+    payload: Bytes,
+    received: Instant,
+}
+
+/// One upstream ZXTouch connection per device, shared by every client.
+struct ZxTouchSession {
+    commands: mpsc::Sender<Command>,
+    replies: broadcast::Sender<Bytes>,
+    state: watch::Receiver<LinkState>,
+    cancel: CancellationToken,
+}
+
+/// A session and the number of links using it.
+struct Attached {
+    session: Arc<ZxTouchSession>,
+    links: usize,
+}
+
+/// The ZXTouch connections of every device. Device clients, broadcasts and
+/// macro playback all go through them, so each device sees one connection.
+#[derive(Clone)]
+pub struct ZxTouchSessions {
+    registry: Arc<DeviceRegistry>,
+    shutdown: Shutdown,
+    sessions: Arc<StdMutex<HashMap<String, Attached>>>,
+}
+
+/// A user of a device's session. The connection is closed once the last
+/// link is dropped.
+pub struct ZxTouchLink {
+    id: String,
+    session: Arc<ZxTouchSession>,
+    sessions: ZxTouchSessions,
+}
+
+impl ZxTouchLink {
+    /// Queues a command, `false` when the queue is full.
+    pub fn try_send(&self, payload: Bytes) -> bool {
+        let command = Command {
+            payload,
+            received: Instant::now(),
+        };
+        !matches!(
+            self.session.commands.try_send(command),
+            Err(TrySendError::Full(_))
+        )
+    }
+
+    /// Queues a command once there's room, `false` when the session closed.
+    pub async fn send(&self, payload: Bytes) -> bool {
+        let command = Command {
+            payload,
+            received: Instant::now(),
+        };
+        self.session.commands.send(command).await.is_ok()
+    }
+
+    /// What the device sends, to every link.
+    pub fn replies(&self) -> broadcast::Receiver<Bytes> {
+        self.session.replies.subscribe()
+    }
+
+    pub fn state(&self) -> watch::Receiver<LinkState> {
+        self.session.state.clone()
+    }
+}
+
+impl Drop for ZxTouchLink {
+    fn drop(&mut self) {
+        self.sessions.detach(&self.id);
+    }
+}
+
+impl ZxTouchSessions {
+    pub fn new(registry: Arc<DeviceRegistry>, shutdown: Shutdown) -> Self {
+        Self {
+            registry,
+            shutdown,
+            sessions: Arc::default(),
+        }
+    }
+
+    /// Joins the device's session, connecting to it if nobody else is.
+    pub fn link(&self, id: &str) -> ZxTouchLink {
+        let mut sessions = self.sessions.lock().unwrap();
+        let attached = sessions.entry(id.to_string()).or_insert_with(|| Attached {
+            session: self.spawn(id.to_string()),
+            links: 0,
+        });
+        attached.links += 1;
+        ZxTouchLink {
+            id: id.to_string(),
+            session: attached.session.clone(),
+            sessions: self.clone(),
+        }
+    }
+
+    fn detach(&self, id: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(attached) = sessions.get_mut(id) {
+            attached.links -= 1;
+            if attached.links == 0 {
+                attached.session.cancel.cancel();
+                sessions.remove(id);
+            }
+        }
+    }
+
+    fn spawn(&self, id: String) -> Arc<ZxTouchSession> {
+        let (commands, commands_receiver) = mpsc::channel(COMMAND_QUEUE);
+        let (replies, _) = broadcast::channel(256);
+        let (state_sender, state) = watch::channel(LinkState::Connecting);
+        let cancel = self.shutdown.child_token();
+
+        self.shutdown.spawn(supervise(
+            self.registry.clone(),
+            id,
+            commands_receiver,
+            replies.clone(),
+            state_sender,
+            cancel.clone(),
+        ));
+
+        Arc::new(ZxTouchSession {
+            commands,
+            replies,
+            state,
+            cancel,
+        })
+    }
+
+    #[tracing::instrument(name = "zxtouch_client", skip_all, fields(device = %id))]
+    pub async fn handle_client(
+        self,
+        ws: WebSocket,
+        id: String,
+        mut recorder: Option<MacroRecorder>,
+    ) {
+        let link = Arc::new(self.link(&id));
+        let (mut ws_writer, mut ws_reader) = ws.split();
+        let (control, mut control_receiver) = mpsc::channel::<String>(16);
+        let cancel = link.session.cancel.child_token();
+        let cancel_reader = cancel.clone();
+        let cancel_writer = cancel.clone();
+
+        let commands = link.clone();
+        let ws_to_tcp = tokio::spawn(async move {
+            loop {
+                let payload = tokio::select! {
+                    _ = cancel_reader.cancelled() => break,
+                    message = ws_reader.next() => match message {
+                        Some(Ok(Message::Binary(buf))) => {
+                            if let Some(recorder) = &mut recorder { recorder.binary(&buf).await; }
+                            buf
+                        }
+                        Some(Ok(Message::Text(text))) => {
+                            if let Some(recorder) = &mut recorder { recorder.text(&text).await; }
+                            Bytes::copy_from_slice(text.as_bytes())
+                        }
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,
+                        Some(Err(_)) => break,
+                    },
+                };
+
+                if !commands.try_send(payload) {
+                    let frame = serde_json::to_string(&ControlFrame::QueueFull).unwrap();
+                    let _ = control.send(frame).await;
+                }
+            }
+            cancel_reader.cancel();
+            if let Some(recorder) = recorder {
+                recorder.finish().await;
+            }
+        });
+
+        let mut replies = link.replies();
+        let mut state = link.state();
+        let shutdown = self.shutdown.clone();
+        let tcp_to_ws = tokio::spawn(async move {
+            // Always tell the client where the upstream stands first.
+            state.mark_changed();
+            loop {
+                let message = tokio::select! {
+                    _ = cancel_writer.cancelled() => break,
+                    reply = replies.recv() => match reply {
+                        Ok(buf) => Message::binary(buf),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    changed = state.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                        let state = state.borrow_and_update().clone();
+                        Message::text(serde_json::to_string(&ControlFrame::State(&state)).unwrap())
+                    }
+                    Some(frame) = control_receiver.recv() => Message::text(frame),
+                };
+                if ws_writer.send(message).await.is_err() {
+                    break;
+                }
+            }
+            cancel_writer.cancel();
+            shutdown.close(&mut ws_writer).await;
+        });
+
+        let _ = tokio::join!(ws_to_tcp, tcp_to_ws);
+    }
+}
+
+#[tracing::instrument(name = "zxtouch", skip_all, fields(device = %id))]
+async fn supervise(
+    registry: Arc<DeviceRegistry>,
+    id: String,
+    mut commands: mpsc::Receiver<Command>,
+    replies: broadcast::Sender<Bytes>,
+    state: watch::Sender<LinkState>,
+    cancel: CancellationToken,
+) {
+    let mut backoff = Backoff::new(Duration::from_millis(250), Duration::from_secs(10));
+    // A command whose write failed is retried on the next connection.
+    let mut pending: Option<Command> = None;
+
+    loop {
+        let result = tokio::select! {
+            _ = cancel.cancelled() => return,
+            result = connect(&registry, &id) => result,
+        };
+
+        let error = match result {
+            Ok(stream) => {
+                backoff.reset();
+                state.send_replace(LinkState::Connected);
+                let error = tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    error = relay(&id, stream, &mut commands, &mut pending, &replies) => error,
+                };
+                tracing::debug!(id, error, "zxtouch upstream lost");
+                error
+            }
+            Err(error) => error,
+        };
+
+        let delay = backoff.next_delay();
+        state.send_replace(LinkState::Reconnecting {
+            attempt: backoff.attempt(),
+            retry_in_ms: delay.as_millis() as u64,
+            error,
+        });
+
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+}
+
+async fn connect(registry: &DeviceRegistry, id: &str) -> Result<TcpStream, String> {
+    // Look the device up on every attempt, its address may have changed.
+    let device = registry
+        .get_ios_device(id)
+        .await
+        .ok_or_else(|| "device offline".to_string())?;
+    let addr = format!("{}:{}", device.ip, device.status.zxtouch.port);
+
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| "connect timeout".to_string())?
+        .map_err(|err| err.to_string())?;
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+/// Pumps commands and replies until the connection fails.
+async fn relay(
+    id: &str,
+    stream: TcpStream,
+    commands: &mut mpsc::Receiver<Command>,
+    pending: &mut Option<Command>,
+    replies: &broadcast::Sender<Bytes>,
+) -> String {
+    let (mut tcp_reader, mut tcp_writer) = stream.into_split();
+    let mut buf = vec![0u8; 64 * 1024];
+    let metrics = metrics();
+
+    loop {
+        if let Some(command) = pending.as_ref() {
+            if let Err(err) = tcp_writer.write_all(&command.payload).await {
+                return err.to_string();
+            }
+            metrics.zxtouch_command(id, command.received.elapsed());
+            metrics.relayed("zxtouch", "to_device", command.payload.len());
+            *pending = None;
+        }
+
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => *pending = Some(command),
+                None => return "session closed".to_string(),
+            },
+            result = tcp_reader.read(&mut buf) => match result {
+                Ok(0) => return "connection closed by device".to_string(),
+                Ok(n) => {
+                    metrics.relayed("zxtouch", "to_client", n);
+                    let _ = replies.send(Bytes::copy_from_slice(&buf[..n]));
+                }
+                Err(err) => return err.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shares_one_session_per_device() {
+        let sessions = ZxTouchSessions::new(DeviceRegistry::new(), Shutdown::new());
+        let first = sessions.link("phone");
+        let second = sessions.link("phone");
+        assert!(Arc::ptr_eq(&first.session, &second.session));
+        assert_eq!(sessions.sessions.lock().unwrap().len(), 1);
+
+        drop(first);
+        assert!(!second.session.cancel.is_cancelled());
+        let session = second.session.clone();
+        drop(second);
+        assert!(session.cancel.is_cancelled());
+        assert!(sessions.sessions.lock().unwrap().is_empty());
+    }
+}
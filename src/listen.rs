@@ -0,0 +1,275 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::OnceLock,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use ipnet::IpNet;
+use tokio::net::TcpListener;
+
+use crate::config::{ConfigStore, ServerConfig};
+
+pub const DEFAULT_PORT: u16 = 15037;
+
+/// Networks LAN clients may connect from when `server.allow` is empty.
+const PRIVATE_NETWORKS: [&str; 6] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+pub fn private_networks() -> &'static [IpNet] {
+    static NETWORKS: OnceLock<Vec<IpNet>> = OnceLock::new();
+    NETWORKS.get_or_init(|| {
+        PRIVATE_NETWORKS
+            .iter()
+            .filter_map(|net| net.parse().ok())
+            .collect()
+    })
+}
+
+/// Where the bridge listens on `port`, loopback only unless `server.lan` is
+/// set.
+pub fn addrs(server: &ServerConfig, port: u16) -> Vec<SocketAddr> {
+    if !server.listen.is_empty() {
+        return server
+            .listen
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect();
+    }
+
+    if server.lan {
+        vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))]
+    } else {
+        vec![
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+        ]
+    }
+}
+
+/// Binds every address, failing only if none could be bound.
+pub async fn bind(addrs: &[SocketAddr]) -> std::io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+
+    for addr in addrs {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                tracing::warn!(%addr, %err, "failed to listen");
+                last_error = Some(err);
+            }
+        }
+    }
+
+    match (listeners.is_empty(), last_error) {
+        (true, Some(err)) => Err(err),
+        _ => Ok(listeners),
+    }
+}
+
+fn allowlist(server: &ServerConfig) -> &[IpNet] {
+    match (server.lan, server.allow.is_empty()) {
+        (false, _) => &[],
+        (true, true) => private_networks(),
+        (true, false) => &server.allow,
+    }
+}
+
+/// Loopback clients are always allowed.
+pub fn allows(server: &ServerConfig, ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    ip.is_loopback() || allowlist(server).iter().any(|net| net.contains(&ip))
+}
+
+/// Whether a `Host` header names this machine: `localhost`, a loopback
+/// address or one of `server.listen`. A page on another name that reaches
+/// a loopback listener got there through DNS rebinding.
+pub fn is_local_host(server: &ServerConfig, host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => name,
+        _ => host,
+    };
+    if name.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    name.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_ok_and(|ip| ip.is_loopback() || server.listen.contains(&ip))
+}
+
+/// Where a browser on this machine reaches the bridge.
+#[cfg(any(feature = "tray", not(target_os = "macos")))]
+pub fn local_url(server: &ServerConfig) -> String {
+    let local = server
+        .listen
+        .iter()
+        .all(|ip| !ip.is_loopback() && !ip.is_unspecified());
+    match server.listen.first() {
+        Some(ip) if local => format!("http://{}/", SocketAddr::new(*ip, server.port)),
+        _ => format!("http://localhost:{}/", server.port),
+    }
+}
+
+/// One-line summary for the tray tooltip.
+pub fn exposure(server: &ServerConfig) -> String {
+    if !server.lan {
+        return "Local connections only".to_string();
+    }
+
+    let addrs = addrs(server, server.port)
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let allowlist = allowlist(server)
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("LAN on {} from {}", addrs, allowlist)
+}
+
+/// Rejects peers outside the allowlist before any handler runs.
+pub async fn check_peer(
+    State(config): State<ConfigStore>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !allows(&config.get().server, peer.ip()) {
+        tracing::warn!(%peer, uri = %request.uri(), "rejected peer outside the allowlist");
+        return (StatusCode::FORBIDDEN, "address not allowed").into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Router};
+
+    use super::*;
+
+    fn server(lan: bool, allow: &[&str]) -> ServerConfig {
+        let mut server = crate::config::Config::default().server;
+        server.lan = lan;
+        server.allow = allow.iter().map(|net| net.parse().unwrap()).collect();
+        server
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn allows_only_loopback_without_lan() {
+        let server = server(false, &[]);
+        assert!(allows(&server, ip("127.0.0.1")));
+        assert!(allows(&server, ip("::1")));
+        assert!(allows(&server, ip("::ffff:127.0.0.1")));
+        assert!(!allows(&server, ip("192.168.1.20")));
+        assert!(!allows(&server, ip("::ffff:192.168.1.20")));
+    }
+
+    #[test]
+    fn allows_private_networks_on_the_lan() {
+        let server = server(true, &[]);
+        assert!(allows(&server, ip("192.168.1.20")));
+        assert!(allows(&server, ip("::ffff:192.168.1.20")));
+        assert!(allows(&server, ip("172.31.255.255")));
+        assert!(allows(&server, ip("fe80::1")));
+        assert!(!allows(&server, ip("172.32.0.1")));
+        assert!(!allows(&server, ip("8.8.8.8")));
+        assert!(!allows(&server, ip("::ffff:8.8.8.8")));
+        assert!(!allows(&server, ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn allows_only_the_allowlist_when_given() {
+        let server = server(true, &["10.1.0.0/16"]);
+        assert!(allows(&server, ip("10.1.2.3")));
+        assert!(allows(&server, ip("::ffff:10.1.2.3")));
+        assert!(allows(&server, ip("127.0.0.1")));
+        assert!(!allows(&server, ip("10.2.0.1")));
+        assert!(!allows(&server, ip("192.168.1.20")));
+    }
+
+    #[test]
+    fn recognizes_local_host_names() {
+        let mut server = server(false, &[]);
+        server.listen = vec![ip("192.168.1.5")];
+        assert!(is_local_host(&server, "localhost:15037"));
+        assert!(is_local_host(&server, "LOCALHOST"));
+        assert!(is_local_host(&server, "127.0.0.1:15037"));
+        assert!(is_local_host(&server, "[::1]:15037"));
+        assert!(is_local_host(&server, "[::1]"));
+        assert!(is_local_host(&server, "192.168.1.5:15037"));
+        assert!(!is_local_host(&server, "attacker.example:15037"));
+        assert!(!is_local_host(&server, "localhost.attacker.example"));
+        assert!(!is_local_host(&server, "192.168.1.6:15037"));
+        assert!(!is_local_host(&server, ""));
+    }
+
+    #[tokio::test]
+    async fn check_peer_rejects_peers_outside_the_allowlist() {
+        let missing = std::env::temp_dir().join("tango-listen-test-missing.toml");
+        let config = ConfigStore::load(
+            [
+                "--config",
+                missing.to_str().unwrap(),
+                "--allow",
+                "10.1.0.0/16",
+                "--lan",
+            ]
+            .map(str::to_string),
+        )
+        .unwrap();
+        // Every test request comes from loopback, so the peer is taken from
+        // a header instead.
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(config, check_peer))
+            .layer(middleware::from_fn(
+                |mut request: Request, next: Next| async move {
+                    let peer = request.headers()["x-peer"].to_str().unwrap().parse();
+                    request
+                        .extensions_mut()
+                        .insert(ConnectInfo::<SocketAddr>(peer.unwrap()));
+                    next.run(request).await
+                },
+            ));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        for (peer, status) in [
+            ("127.0.0.1:1000", StatusCode::OK),
+            ("[::1]:1000", StatusCode::OK),
+            ("10.1.2.3:1000", StatusCode::OK),
+            ("[::ffff:10.1.2.3]:1000", StatusCode::OK),
+            ("10.2.0.1:1000", StatusCode::FORBIDDEN),
+            ("[::ffff:192.168.1.20]:1000", StatusCode::FORBIDDEN),
+        ] {
+            let response = client
+                .get(&url)
+                .header("x-peer", peer)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), status.as_u16(), "{}", peer);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer,
+    Registry,
+};
+
+use crate::config::{ConfigStore, LogConfig};
+
+const FILE_PREFIX: &str = "tango-bridge";
+const FILE_SUFFIX: &str = "log";
+
+/// Lines buffered for followers before the slowest one misses some.
+const FOLLOW_BUFFER: usize = 1024;
+
+/// How far back from the end of the file a tail looks.
+const TAIL_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Where the logs go, and the lines written since startup for `follow`.
+#[derive(Clone)]
+pub struct Logs {
+    dir: PathBuf,
+    lines: broadcast::Sender<Arc<str>>,
+    filter: reload::Handle<EnvFilter, Registry>,
+}
+
+/// Sets up logging to the console and to daily files, the file is skipped
+/// when its directory can't be created.
+pub fn init(config: &LogConfig) -> Logs {
+    let dir = config.dir();
+    let (lines, _) = broadcast::channel(FOLLOW_BUFFER);
+    let (filter, handle) = reload::Layer::new(env_filter(&config.level));
+
+    let file = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(FILE_PREFIX)
+        .filename_suffix(FILE_SUFFIX)
+        .max_log_files(config.max_files)
+        .build(&dir);
+    let (file, file_error) = match file {
+        Ok(file) => (Some(file), None),
+        Err(err) => (None, Some(err)),
+    };
+
+    // journald stamps every line itself and doesn't render colours.
+    let console = match std::env::var_os("JOURNAL_STREAM") {
+        Some(_) => tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .without_time()
+            .boxed(),
+        None => tracing_subscriber::fmt::layer().boxed(),
+    };
+    let sink = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(Sink {
+            file,
+            lines: lines.clone(),
+        });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(console)
+        .with(sink)
+        .init();
+
+    if let Some(err) = file_error {
+        tracing::error!(dir = %dir.display(), %err, "can't write log files");
+    }
+    Logs {
+        dir,
+        lines,
+        filter: handle,
+    }
+}
+
+fn env_filter(level: &str) -> EnvFilter {
+    // Validated with the config, the fallback is for the default itself.
+    EnvFilter::try_new(level).unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+impl Logs {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn follow(&self) -> broadcast::Receiver<Arc<str>> {
+        self.lines.subscribe()
+    }
+
+    /// Applies `log.level` changes from the config file.
+    pub fn watch(&self, config: &ConfigStore, cancel: CancellationToken) {
+        let mut changes = config.subscribe();
+        let handle = self.filter.clone();
+        let mut level = config.get().log.level.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    changed = changes.changed() => if changed.is_err() { break },
+                }
+                let next = changes.borrow_and_update().log.level.clone();
+                if next == level {
+                    continue;
+                }
+                match handle.reload(env_filter(&next)) {
+                    Ok(()) => tracing::info!(level = next, "log level changed"),
+                    Err(err) => tracing::warn!(%err, "can't change the log level"),
+                }
+                level = next;
+            }
+        });
+    }
+
+    /// The last `count` lines of the newest log file.
+    pub fn tail(&self, count: usize) -> io::Result<Vec<String>> {
+        let Some(path) = self.current_file()? else {
+            return Ok(Vec::new());
+        };
+        let mut file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        let start = len.saturating_sub(TAIL_BYTES);
+        file.seek(SeekFrom::Start(start))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let text = String::from_utf8_lossy(&buf);
+        let mut lines = text.lines().collect::<Vec<_>>();
+        // The first line is likely cut in half.
+        if start > 0 && !lines.is_empty() {
+            lines.remove(0);
+        }
+        let skip = lines.len().saturating_sub(count);
+        Ok(lines[skip..].iter().map(|line| line.to_string()).collect())
+    }
+
+    /// File names end in the date, so the newest sorts last.
+    fn current_file(&self) -> io::Result<Option<PathBuf>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut files = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        name.starts_with(FILE_PREFIX) && name.ends_with(FILE_SUFFIX)
+                    })
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        Ok(files.pop())
+    }
+}
+
+/// Writes each event to the log file and hands it to followers.
+struct Sink {
+    file: Option<RollingFileAppender>,
+    lines: broadcast::Sender<Arc<str>>,
+}
+
+impl<'a> MakeWriter<'a> for Sink {
+    type Writer = EventWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventWriter {
+            sink: self,
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// Collects one formatted event, written out when dropped.
+struct EventWriter<'a> {
+    sink: &'a Sink,
+    buf: Vec<u8>,
+}
+
+impl Write for EventWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventWriter<'_> {
+    fn drop(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        if let Some(file) = &self.sink.file {
+            let _ = file.make_writer().write_all(&self.buf);
+        }
+        if self.sink.lines.receiver_count() > 0 {
+            let text = String::from_utf8_lossy(&self.buf);
+            let _ = self.sink.lines.send(Arc::from(text.trim_end()));
+        }
+    }
+}
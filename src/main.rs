@@ -1,111 +1,366 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use std::{
+    convert::Infallible,
     env,
     future::IntoFuture,
-    sync::OnceLock,
-    thread,
-    time::{Duration, Instant},
+    net::{Ipv4Addr, SocketAddr},
 };
 
-use auto_launch::AutoLaunchBuilder;
 use axum::{
     body::Bytes,
     extract::{
         ws::{Message, WebSocket},
-        Path, Request, State, WebSocketUpgrade,
+        ConnectInfo, DefaultBodyLimit, Path, Query, Request, State, WebSocketUpgrade,
     },
-    response::{IntoResponse, Response},
-    routing::get,
-    Json, Router,
-};
-use futures_util::{SinkExt, StreamExt};
-use http::{Method, StatusCode};
-use reqwest::Url;
-use tao::event_loop::EventLoopBuilder;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::mpsc::channel,
-};
-use tokio_util::sync::CancellationToken;
-use tower_http::cors::{Any, CorsLayer};
-use tray_icon::{
-    menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem},
-    TrayIconBuilder, TrayIconEvent,
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    serve::ListenerExt,
+    Extension, Json, Router,
 };
+use futures_util::{future::try_join_all, stream, FutureExt, SinkExt, StreamExt};
+use http::{header, HeaderMap, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::broadcast};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
 
 mod adb;
+mod adb_inspector;
+mod adb_policy;
+mod adb_relay;
+mod backoff;
+mod cli;
+mod config;
+mod daemon;
+mod health;
+mod ios_agent;
+mod ios_broadcast;
 mod ios_lan_scanner;
+mod ios_macros;
 mod ios_provider;
+mod ios_scripts;
+mod ios_stream;
+mod ios_zxtouch;
+mod listen;
+mod logging;
+mod metrics;
+mod mpegts;
+mod origins;
+mod pairing;
+mod paths;
+mod proxy;
+mod proxy_cache;
 mod registry;
+mod shutdown;
+mod tls;
+#[cfg(feature = "tray")]
+mod tray;
+mod web_ui;
+mod zxtouch;
+
+use adb_inspector::{AllowAll, ClientInfo, RequestPolicy};
+use adb_policy::AccessPolicy;
+use adb_relay::{AdbSessions, SessionList};
+use config::{Config, ConfigError, ConfigStore};
+use health::{Health, HealthReport};
+use ios_agent::AgentCommand;
+use ios_broadcast::BroadcastQuery;
+use ios_lan_scanner::{IosLanScanner, ProbeError};
+use ios_macros::{MacroError, MacroInfo, MacroStore, PlayOptions, PlayResult};
+use ios_provider::{HelloStatusPayload, IosProvider, ScanStatus, ScriptStatus};
+use ios_scripts::{InstalledBundle, ScriptError, ScriptUploader, UploadResult};
+use ios_stream::Quality;
+use ios_zxtouch::ZxTouchSessions;
+use logging::Logs;
+use metrics::metrics;
+use pairing::{PairingError, PairingTicket, Pairings};
+use proxy::{Proxy, ViaTls};
+use registry::{DeviceEvent, DeviceRegistry};
+use shutdown::Shutdown;
+use tls::{CertStatus, TlsIdentity, TlsListener};
+use web_ui::WebBundle;
+
+/// Opened by the tray, and by a second launch where the OS allows one.
+#[cfg(any(feature = "tray", not(target_os = "macos")))]
+const WEB_URL: &str = "https://app.tangoapp.dev/?desktop=true";
+
+/// The hosted web UI, or the bridge's own copy in offline mode.
+#[cfg(any(feature = "tray", not(target_os = "macos")))]
+fn web_url(config: &Config) -> String {
+    if config.web.offline {
+        listen::local_url(&config.server)
+    } else {
+        WEB_URL.to_string()
+    }
+}
 
-use ios_lan_scanner::IosLanScanner;
-use ios_provider::IosProvider;
-use registry::DeviceRegistry;
+#[cfg(any(feature = "tray", not(target_os = "macos")))]
+fn start_browser(url: &str) {
+    open::that_detached(url).unwrap();
+}
 
-fn start_browser() {
-    open::that_detached("https://app.tangoapp.dev/?desktop=true").unwrap();
+async fn adb_websocket_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let client = ClientInfo {
+        origin: headers
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+            .map(str::to_string),
+        token: pairing::request_token(&headers),
+    };
+    let task = state.adb_sessions.clone();
+    ws.on_upgrade(move |socket| state.shutdown.track(task.handle_client(socket, client)))
 }
 
-async fn handle_websocket(ws: WebSocket) {
-    let (mut ws_writer, mut ws_reader) = ws.split();
-    let adb_stream = adb::connect_or_start().await.unwrap();
-    // Reduce latency for small writes.
-    let _ = adb_stream.set_nodelay(true);
-    let (mut adb_reader, mut adb_writer) = adb_stream.into_split();
-
-    let (ws_to_adb_sender, mut ws_to_adb_receiver) = channel::<Bytes>(16);
-    let (adb_to_ws_sender, mut adb_to_ws_receiver) = channel::<Vec<u8>>(16);
-
-    tokio::join!(
-        async move {
-            while let Some(Ok(message)) = ws_reader.next().await {
-                // Don't merge with `if` above to ignore other message types
-                if let Message::Binary(packet) = message {
-                    if ws_to_adb_sender.send(packet).await.is_err() {
-                        break;
-                    }
-                }
-            }
-        },
-        async move {
-            while let Some(buf) = ws_to_adb_receiver.recv().await {
-                if adb_writer.write_all(buf.as_ref()).await.is_err() {
-                    break;
-                }
-            }
-            let _ = adb_writer.shutdown().await;
-        },
-        async move {
-            let mut buf = vec![0u8; 64 * 1024];
-            loop {
-                match adb_reader.read(&mut buf).await {
-                    Ok(0) | Err(_) => {
-                        break;
-                    }
-                    Ok(n) => {
-                        if adb_to_ws_sender.send(buf[..n].to_vec()).await.is_err() {
-                            break;
-                        }
-                    }
-                }
-            }
-        },
-        async move {
-            while let Some(buf) = adb_to_ws_receiver.recv().await {
-                if ws_writer.send(Message::binary(buf)).await.is_err() {
-                    break;
-                }
+async fn adb_sessions_handler(State(state): State<AppState>) -> Json<SessionList> {
+    Json(state.adb_sessions.list().await)
+}
+
+#[derive(Serialize)]
+struct BridgeStatus {
+    version: &'static str,
+    tls: Option<CertStatus>,
+}
+
+async fn status_handler(State(state): State<AppState>) -> Json<BridgeStatus> {
+    Json(BridgeStatus {
+        version: env!("CARGO_PKG_VERSION"),
+        tls: state.tls.as_ref().map(TlsIdentity::status),
+    })
+}
+
+/// For monitors, 503 while a critical component is down.
+async fn health_handler(State(state): State<AppState>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.check(&state.config, &state.scans).await;
+    let status = if report.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// The local CA, for trusting it by hand when the bridge issued its own cert.
+async fn tls_ca_handler() -> Response {
+    match tls::ca_pem() {
+        Ok(pem) => (
+            [
+                (header::CONTENT_TYPE, "application/x-pem-file"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"tango-bridge-ca.pem\"",
+                ),
+            ],
+            pem,
+        )
+            .into_response(),
+        Err(_) => (StatusCode::NOT_FOUND, "no local CA").into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct ConfigReply {
+    path: String,
+    config: Config,
+    /// Set on the command line or in the environment, these win over the file.
+    overridden: Vec<String>,
+    /// Changed since the bridge started but only applied after a restart.
+    restart_required: Vec<&'static str>,
+}
+
+fn config_reply(store: &ConfigStore, config: std::sync::Arc<Config>) -> Json<ConfigReply> {
+    Json(ConfigReply {
+        path: store.path().display().to_string(),
+        restart_required: config.restart_required(store.started()),
+        overridden: store.overridden(),
+        config: (*config).clone(),
+    })
+}
+
+async fn config_handler(State(state): State<AppState>) -> Json<ConfigReply> {
+    config_reply(&state.config, state.config.get())
+}
+
+fn config_error(err: ConfigError) -> Response {
+    let status = match err {
+        ConfigError::Parse { .. } | ConfigError::Invalid { .. } => StatusCode::BAD_REQUEST,
+        ConfigError::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(err)).into_response()
+}
+
+/// Replaces the config file, keys left out take their defaults. File-only
+/// and overridden keys can't be changed.
+async fn config_update_handler(
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<Json<ConfigReply>, Response> {
+    let file: Config =
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(&body))
+            .map_err(|err| {
+                config_error(ConfigError::Invalid {
+                    errors: vec![format!("{}: {}", err.path(), err.inner())],
+                })
+            })?;
+    // Parsed as a `Config` above, so this is an object.
+    let sent = serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(&body)
+        .map(|sections| {
+            sections
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let config = state.config.update(file, &sent).map_err(config_error)?;
+    Ok(config_reply(&state.config, config))
+}
+
+fn pairing_error(err: PairingError) -> Response {
+    let status = match err {
+        PairingError::MissingOrigin => StatusCode::BAD_REQUEST,
+        PairingError::NotFound => StatusCode::NOT_FOUND,
+        PairingError::Pending => StatusCode::ACCEPTED,
+        PairingError::InvalidCode => StatusCode::FORBIDDEN,
+        PairingError::TooManyAttempts { retry_after_secs } => {
+            let retry_after = [(header::RETRY_AFTER, retry_after_secs.to_string())];
+            return (StatusCode::TOO_MANY_REQUESTS, retry_after, Json(err)).into_response();
+        }
+        PairingError::Unauthorized => StatusCode::UNAUTHORIZED,
+        PairingError::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(err)).into_response()
+}
+
+/// Lines sent by `/bridge/logs` when not asked for a number.
+const LOG_LINES: usize = 200;
+
+const MAX_LOG_LINES: usize = 10_000;
+
+#[derive(Deserialize)]
+struct LogsQuery {
+    #[serde(default)]
+    lines: Option<usize>,
+    /// Keeps the response open as server-sent events with new lines.
+    #[serde(default)]
+    follow: bool,
+}
+
+async fn logs_handler(State(state): State<AppState>, Query(query): Query<LogsQuery>) -> Response {
+    let count = query.lines.unwrap_or(LOG_LINES).min(MAX_LOG_LINES);
+    // Subscribed first so nothing falls between the tail and the live lines.
+    let live = query.follow.then(|| state.logs.follow());
+    let logs = state.logs.clone();
+    let tail = tokio::task::spawn_blocking(move || logs.tail(count))
+        .await
+        .map_err(|err| err.to_string())
+        .and_then(|tail| tail.map_err(|err| err.to_string()));
+    let tail = match tail {
+        Ok(tail) => tail,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    };
+
+    let Some(live) = live else {
+        let mut text = tail.join("\n");
+        text.push('\n');
+        return ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response();
+    };
+
+    let event = |line: &str| Ok::<_, Infallible>(Event::default().data(line.replace('\r', "")));
+    let live = stream::unfold(live, move |mut live| async move {
+        match live.recv().await {
+            Ok(line) => Some((event(&line), live)),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                let comment = format!("{} lines skipped", skipped);
+                Some((Ok(Event::default().comment(comment)), live))
             }
-            let _ = ws_writer.close().await;
+            Err(broadcast::error::RecvError::Closed) => None,
         }
-    );
+    });
+    let events = stream::iter(tail.iter().map(|line| event(line)).collect::<Vec<_>>())
+        .chain(live)
+        // Otherwise the server waits for followers when shutting down.
+        .take_until(state.shutdown.token().cancelled_owned());
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn pair_start_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<PairingTicket>, Response> {
+    let origin = headers
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok())
+        .ok_or_else(|| pairing_error(PairingError::MissingOrigin))?;
+    state
+        .pairings
+        .start(origin)
+        .map(Json)
+        .map_err(pairing_error)
+}
+
+#[derive(Deserialize)]
+struct PairCompleteRequest {
+    #[serde(default)]
+    code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PairCompleteReply {
+    token: String,
+}
+
+/// Polled until the pairing is approved in the tray, or called with the code.
+async fn pair_complete_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(request): Json<PairCompleteRequest>,
+) -> Result<Json<PairCompleteReply>, Response> {
+    let token = state
+        .pairings
+        .complete(&id, request.code.as_deref())
+        .map_err(pairing_error)?;
+    Ok(Json(PairCompleteReply { token }))
 }
 
 #[derive(Clone)]
 struct AppState {
+    config: ConfigStore,
     registry: std::sync::Arc<DeviceRegistry>,
+    scanner: IosLanScanner,
+    scripts: ScriptUploader,
+    macros: MacroStore,
+    zxtouch: ZxTouchSessions,
+    adb_sessions: AdbSessions,
+    pairings: Pairings,
+    tls: Option<TlsIdentity>,
+    logs: Logs,
+    scans: ScanStatus,
+    health: Health,
+    proxy: Proxy,
+    shutdown: Shutdown,
+}
+
+/// Prometheus scrape target.
+async fn metrics_handler(State(state): State<AppState>) -> Response {
+    let metrics = metrics();
+    metrics.set_devices(&state.registry.list_unified_devices().await);
+    (
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics.encode(),
+    )
+        .into_response()
 }
 
 async fn list_devices(State(state): State<AppState>) -> impl IntoResponse {
@@ -113,9 +368,219 @@ async fn list_devices(State(state): State<AppState>) -> impl IntoResponse {
     Json(devices)
 }
 
+async fn events_handler(State(state): State<AppState>, ws: WebSocketUpgrade) -> Response {
+    let events = state.registry.subscribe();
+    let shutdown = state.shutdown;
+    ws.on_upgrade(move |socket| shutdown.track(handle_events(socket, events, shutdown.clone())))
+}
+
+#[tracing::instrument(name = "events", skip_all)]
+async fn handle_events(
+    ws: WebSocket,
+    mut events: broadcast::Receiver<DeviceEvent>,
+    shutdown: Shutdown,
+) {
+    let (mut ws_writer, mut ws_reader) = ws.split();
+
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            message = ws_reader.next() => {
+                match message {
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Ok(text) = serde_json::to_string(&event) else {
+                    continue;
+                };
+                if ws_writer.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    shutdown.close(&mut ws_writer).await;
+}
+
+async fn ios_probe_handler(
+    State(state): State<AppState>,
+    Path(ip): Path<Ipv4Addr>,
+) -> impl IntoResponse {
+    Json(state.scanner.probe(ip).await)
+}
+
+#[derive(Deserialize)]
+struct ScriptPlayRequest {
+    path: String,
+}
+
+async fn ios_script_command(
+    state: &AppState,
+    id: &str,
+    command: Option<AgentCommand>,
+) -> Result<Json<ScriptStatus>, Response> {
+    let device = state
+        .registry
+        .get_ios_device(id)
+        .await
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "device not found").into_response())?;
+
+    let ip: Ipv4Addr = device
+        .ip
+        .parse()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "invalid device ip").into_response())?;
+    let port = device.status.zxtouch.port;
+    let timeouts = state.scanner.timeouts();
+    let agent_error = |err: ProbeError| {
+        let status = match err {
+            ProbeError::Agent { .. } => StatusCode::CONFLICT,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        (status, Json(err)).into_response()
+    };
+
+    if let Some(command) = command {
+        ios_agent::send(ip, port, &command, timeouts)
+            .await
+            .and_then(|reply| reply.ok())
+            .map_err(agent_error)?;
+    }
+
+    let status = ios_agent::send(ip, port, &AgentCommand::Status, timeouts)
+        .await
+        .and_then(|reply| reply.json::<HelloStatusPayload>())
+        .map_err(agent_error)?;
+
+    Ok(Json(status.script))
+}
+
+async fn ios_script_status_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ScriptStatus>, Response> {
+    ios_script_command(&state, &id, None).await
+}
+
+async fn ios_script_play_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(request): Json<ScriptPlayRequest>,
+) -> Result<Json<ScriptStatus>, Response> {
+    let command = AgentCommand::play_script(request.path).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            "path must not contain CR, LF or NUL",
+        )
+            .into_response()
+    })?;
+    ios_script_command(&state, &id, Some(command)).await
+}
+
+async fn ios_script_stop_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ScriptStatus>, Response> {
+    ios_script_command(&state, &id, Some(AgentCommand::StopScript)).await
+}
+
+#[derive(Deserialize)]
+struct ScriptUploadQuery {
+    name: String,
+    /// Comma separated device ids, only used for group uploads.
+    #[serde(default)]
+    ids: String,
+}
+
+fn script_error(err: ScriptError) -> Response {
+    let status = match err {
+        ScriptError::InvalidBundle { .. } => StatusCode::BAD_REQUEST,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    (status, Json(err)).into_response()
+}
+
+async fn ios_scripts_list_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<InstalledBundle>>, Response> {
+    let device = state
+        .registry
+        .get_ios_device(&id)
+        .await
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "device not found").into_response())?;
+
+    state
+        .scripts
+        .list(&device)
+        .await
+        .map(Json)
+        .map_err(script_error)
+}
+
+async fn ios_scripts_upload_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<ScriptUploadQuery>,
+    bundle: Bytes,
+) -> Result<Json<UploadResult>, Response> {
+    let device = state
+        .registry
+        .get_ios_device(&id)
+        .await
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "device not found").into_response())?;
+
+    state
+        .scripts
+        .upload(&device, &query.name, bundle)
+        .await
+        .map(Json)
+        .map_err(script_error)
+}
+
+async fn ios_scripts_group_upload_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ScriptUploadQuery>,
+    bundle: Bytes,
+) -> Result<Json<Vec<UploadResult>>, Response> {
+    let mut devices = Vec::new();
+    for id in query
+        .ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+    {
+        let device = state.registry.get_ios_device(id).await.ok_or_else(|| {
+            (StatusCode::NOT_FOUND, format!("device not found: {}", id)).into_response()
+        })?;
+        devices.push(device);
+    }
+
+    state
+        .scripts
+        .upload_many(&devices, &query.name, bundle)
+        .await
+        .map(Json)
+        .map_err(script_error)
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    #[serde(default)]
+    quality: Option<Quality>,
+}
+
 async fn ios_stream_handler(
     State(state): State<AppState>,
     Path(id): Path<String>,
+    Query(query): Query<StreamQuery>,
     ws: WebSocketUpgrade,
 ) -> Result<Response, Response> {
     let device = state
@@ -124,9 +589,20 @@ async fn ios_stream_handler(
         .await
         .ok_or_else(|| (StatusCode::NOT_FOUND, "device not found").into_response())?;
 
-    Ok(ws.on_upgrade(move |socket| handle_ios_stream(socket, device.ip, 7001)))
+    let quality = query.quality.unwrap_or(Quality::Full);
+    Ok(ws.on_upgrade(move |socket| {
+        state.shutdown.track(ios_stream::handle_ios_stream(
+            socket,
+            state.registry,
+            state.config,
+            state.shutdown.clone(),
+            device.id,
+            quality,
+        ))
+    }))
 }
 
+/// Kept for viewers that predate quality switching on `/stream`.
 async fn ios_stream_eco_handler(
     State(state): State<AppState>,
     Path(id): Path<String>,
@@ -138,12 +614,29 @@ async fn ios_stream_eco_handler(
         .await
         .ok_or_else(|| (StatusCode::NOT_FOUND, "device not found").into_response())?;
 
-    Ok(ws.on_upgrade(move |socket| handle_ios_stream(socket, device.ip, 7002)))
+    Ok(ws.on_upgrade(move |socket| {
+        state.shutdown.track(ios_stream::handle_ios_stream(
+            socket,
+            state.registry,
+            state.config,
+            state.shutdown.clone(),
+            device.id,
+            Quality::Eco,
+        ))
+    }))
+}
+
+#[derive(Deserialize)]
+struct ZxTouchQuery {
+    /// Records the commands of this session into the named macro.
+    #[serde(default)]
+    record: Option<String>,
 }
 
 async fn ios_zxtouch_handler(
     State(state): State<AppState>,
     Path(id): Path<String>,
+    Query(query): Query<ZxTouchQuery>,
     ws: WebSocketUpgrade,
 ) -> Result<Response, Response> {
     let device = state
@@ -152,179 +645,114 @@ async fn ios_zxtouch_handler(
         .await
         .ok_or_else(|| (StatusCode::NOT_FOUND, "device not found").into_response())?;
 
-    Ok(ws.on_upgrade(move |socket| handle_ios_zxtouch(socket, device.ip)))
-}
-
-async fn handle_ios_stream(mut ws: WebSocket, ip: String, port: u16) {
-    let addr = format!("{}:{}", ip, port);
-    let stream = match tokio::time::timeout(Duration::from_secs(2), TcpStream::connect(addr)).await {
-        Ok(Ok(stream)) => stream,
-        _ => {
-            let _ = ws.close().await;
-            return;
-        }
+    let recorder = match query.record {
+        Some(name) => Some(state.macros.record(&name).await.map_err(macro_error)?),
+        None => None,
     };
 
-    // Reduce latency for small writes.
-    let _ = stream.set_nodelay(true);
-
-    let (mut ws_writer, mut ws_reader) = ws.split();
-    let (mut tcp_reader, mut tcp_writer) = stream.into_split();
-    let cancel = CancellationToken::new();
-    let cancel_reader = cancel.clone();
-    let cancel_writer = cancel.clone();
-
-    let ws_read_task = tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                _ = cancel_reader.cancelled() => break,
-                message = ws_reader.next() => {
-                    match message {
-                        Some(Ok(Message::Close(_))) | None => break,
-                        Some(Ok(_)) => {}
-                        Some(Err(_)) => break,
-                    }
-                }
-            }
-        }
-        cancel_reader.cancel();
-    });
-
-    let tcp_to_ws = tokio::spawn(async move {
-        // Smaller chunks reduce end-to-end buffering latency.
-        let mut buf = vec![0u8; 8 * 1024];
-        loop {
-            tokio::select! {
-                _ = cancel_writer.cancelled() => break,
-                result = tcp_reader.read(&mut buf) => {
-                    let n = match result {
-                        Ok(0) | Err(_) => break,
-                        Ok(n) => n,
-                    };
-                    if ws_writer.send(Message::binary(buf[..n].to_vec())).await.is_err() {
-                        break;
-                    }
-                }
-            }
-        }
-        cancel_writer.cancel();
-    });
-
-    let _ = tokio::join!(ws_read_task, tcp_to_ws);
-    let _ = tcp_writer.shutdown().await;
+    Ok(ws.on_upgrade(move |socket| {
+        state
+            .shutdown
+            .track(state.zxtouch.handle_client(socket, device.id, recorder))
+    }))
 }
 
-async fn handle_ios_zxtouch(mut ws: WebSocket, ip: String) {
-    let addr = format!("{}:6000", ip);
-    let stream = match TcpStream::connect(addr).await {
-        Ok(stream) => stream,
-        Err(_) => {
-            let _ = ws.close().await;
-            return;
-        }
+fn macro_error(err: MacroError) -> Response {
+    let status = match err {
+        MacroError::InvalidName | MacroError::Parse { .. } => StatusCode::BAD_REQUEST,
+        MacroError::NotFound => StatusCode::NOT_FOUND,
+        MacroError::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
     };
-
-    let _ = stream.set_nodelay(true);
-
-    let (mut ws_writer, mut ws_reader) = ws.split();
-    let (mut tcp_reader, mut tcp_writer) = stream.into_split();
-    let cancel = CancellationToken::new();
-    let cancel_reader = cancel.clone();
-    let cancel_writer = cancel.clone();
-
-    let ws_to_tcp = tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                _ = cancel_reader.cancelled() => break,
-                message = ws_reader.next() => {
-                    match message {
-                        Some(Ok(Message::Binary(buf))) => {
-                            if tcp_writer.write_all(&buf).await.is_err() { break; }
-                        }
-                        Some(Ok(Message::Text(text))) => {
-                            if tcp_writer.write_all(text.as_bytes()).await.is_err() { break; }
-                        }
-                        Some(Ok(Message::Close(_))) | None => break,
-                        Some(Ok(_)) => {}
-                        Some(Err(_)) => break,
-                    }
-                }
-            }
-        }
-        cancel_reader.cancel();
-    });
-
-    let tcp_to_ws = tokio::spawn(async move {
-        let mut buf = vec![0u8; 64 * 1024];
-        loop {
-            tokio::select! {
-                _ = cancel_writer.cancelled() => break,
-                result = tcp_reader.read(&mut buf) => {
-                    let n = match result {
-                        Ok(0) | Err(_) => break,
-                        Ok(n) => n,
-                    };
-                    if ws_writer.send(Message::binary(buf[..n].to_vec())).await.is_err() {
-                        break;
-                    }
-                }
-            }
-        }
-        cancel_writer.cancel();
-    });
-
-    let _ = tokio::join!(ws_to_tcp, tcp_to_ws);
+    (status, Json(err)).into_response()
 }
 
-const ARG_AUTO_RUN: &str = "--auto-run";
+async fn ios_macros_list_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<MacroInfo>>, Response> {
+    state.macros.list().await.map(Json).map_err(macro_error)
+}
 
-#[cfg(debug_assertions)]
-const PROXY_HOST: &str = "https://tangoapp.dev";
-#[cfg(not(debug_assertions))]
-const PROXY_HOST: &str = "https://tangoapp.dev";
+async fn ios_macro_play_handler(
+    State(state): State<AppState>,
+    Path((id, name)): Path<(String, String)>,
+    Json(options): Json<PlayOptions>,
+) -> Result<Json<Vec<PlayResult>>, Response> {
+    let device = state
+        .registry
+        .get_ios_device(&id)
+        .await
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "device not found").into_response())?;
 
-static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    let steps = state.macros.load(&name).await.map_err(macro_error)?;
 
-#[axum::debug_handler]
-async fn proxy_request(request: Request) -> Result<Response, Response> {
-    println!("proxy_request: {} {}", request.method(), request.uri());
+    let mut devices = options
+        .selector
+        .select(state.registry.list_ios_devices().await);
+    devices.retain(|other| other.id != device.id);
+    devices.insert(0, device);
 
-    let url = Url::options()
-        .base_url(Some(&Url::parse(PROXY_HOST).unwrap()))
-        .parse(&request.uri().to_string())
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Bad Request").into_response())?;
+    Ok(Json(
+        ios_macros::play(&state.zxtouch, devices, steps, &options, &state.shutdown).await,
+    ))
+}
 
-    let mut headers = request.headers().clone();
-    headers.insert("Host", url.host_str().unwrap().parse().unwrap());
+async fn ios_broadcast_zxtouch_handler(
+    State(state): State<AppState>,
+    Query(query): Query<BroadcastQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, Response> {
+    let devices = query
+        .selector
+        .select(state.registry.list_ios_devices().await);
+    if devices.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "no matching devices").into_response());
+    }
 
-    let (client, request) = CLIENT
-        .get_or_init(|| reqwest::Client::new())
-        .request(request.method().clone(), url)
-        .headers(headers)
-        .body(reqwest::Body::wrap_stream(
-            request.into_body().into_data_stream(),
+    Ok(ws.on_upgrade(move |socket| {
+        state.shutdown.track(ios_broadcast::handle_broadcast(
+            socket,
+            state.zxtouch.clone(),
+            devices,
+            query,
+            state.shutdown.clone(),
         ))
-        .build_split();
+    }))
+}
 
-    let request = request.map_err(|_| (StatusCode::BAD_REQUEST, "Bad Request").into_response())?;
+/// Runs without the tray, implied when built without the `tray` feature.
+const ARG_HEADLESS: &str = "--headless";
 
-    let response = client
-        .execute(request)
-        .await
-        .map_err(|_| (StatusCode::BAD_GATEWAY, "Bad Gateway").into_response())?;
+const MAX_BUNDLE_SIZE: usize = 64 * 1024 * 1024;
 
-    Ok((
-        response.status(),
-        response.headers().clone(),
-        axum::body::Body::new(reqwest::Body::from(response)),
-    )
-        .into_response())
+/// Everything else goes to the web UI upstream.
+async fn proxy_request(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    tls: Option<Extension<ViaTls>>,
+    request: Request,
+) -> Response {
+    state.proxy.forward(request, peer, tls.is_some()).await
 }
 
-static SINGLE_INSTANCE: OnceLock<single_instance::SingleInstance> = OnceLock::new();
+#[cfg(not(target_os = "macos"))]
+static SINGLE_INSTANCE: std::sync::OnceLock<single_instance::SingleInstance> =
+    std::sync::OnceLock::new();
 
 #[tokio::main]
 async fn main() {
+    if let Some(cli) = cli::Cli::parse(env::args().skip(1)) {
+        let code = match cli {
+            Ok(cli) => cli::run(cli).await,
+            Err(err) => {
+                eprintln!("error: {}\n\nSee tango-bridge help", err);
+                2
+            }
+        };
+        std::process::exit(code);
+    }
+
+    let headless = cfg!(not(feature = "tray")) || env::args().any(|arg| arg == ARG_HEADLESS);
+
     // macOS app bundle prevents re-launching by default
     #[cfg(not(target_os = "macos"))]
     {
@@ -332,232 +760,250 @@ async fn main() {
 
         let single_instance =
             SINGLE_INSTANCE.get_or_init(|| SingleInstance::new("tango-bridge-rs").unwrap());
-        println!(
-            "single_instance.is_single(): {}",
-            single_instance.is_single()
-        );
         if !single_instance.is_single() {
-            start_browser();
+            if headless {
+                eprintln!("another instance is already running");
+                std::process::exit(1);
+            }
+            let url = ConfigStore::load(env::args().skip(1))
+                .map(|config| web_url(&config.get()))
+                .unwrap_or_else(|_| WEB_URL.to_string());
+            start_browser(&url);
             return;
         }
     }
 
-    // Very strangely, running this in `tokio::spawn`
-    // will cause `listener` to not stop on Windows
-    adb::connect_or_start()
-        .await
-        .unwrap()
-        .shutdown()
-        .await
-        .unwrap();
+    let config = match ConfigStore::load(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid configuration:\n{}", err);
+            std::process::exit(2);
+        }
+    };
+    let settings = config.get();
 
-    #[cfg(debug_assertions)]
-    {
-        use tracing::Level;
-        use tracing_subscriber::FmtSubscriber;
-
-        let subscriber = FmtSubscriber::builder()
-            // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
-            // will be written to stdout.
-            .with_max_level(Level::TRACE)
-            // completes the builder.
-            .finish();
-
-        tracing::subscriber::set_global_default(subscriber)
-            .expect("setting default subscriber failed");
+    let logs = logging::init(&settings.log);
+
+    // Very strangely, running this in `tokio::spawn`
+    // will cause `listener` to not stop on Windows.
+    // Sessions start the server again when they connect, so a machine
+    // without adb still serves everything else.
+    match adb::connect_or_start(&settings.adb).await {
+        Ok(mut stream) => {
+            let _ = stream.shutdown().await;
+        }
+        Err(err) => tracing::error!(%err, "failed to start the ADB server"),
     }
 
+    let addrs = listen::addrs(&settings.server, settings.server.port);
+    let tls = settings
+        .tls
+        .enabled
+        .then(|| TlsIdentity::load(&settings.tls, &addrs))
+        .and_then(|identity| {
+            identity
+                .inspect_err(|err| tracing::error!(err, "TLS disabled"))
+                .ok()
+        });
+
+    // Falls back to the proxy, which may still have the UI cached.
+    let web = WebBundle::load(&settings.web)
+        .inspect_err(|err| tracing::error!(err, "can't load the offline web UI"))
+        .ok()
+        .flatten();
+
+    let pairings = Pairings::load(headless);
+    let require_token =
+        middleware::from_fn_with_state((pairings.clone(), config.clone()), pairing::require_token);
+
     let app = Router::new()
         .route("/devices", get(list_devices))
+        .route("/ios/probe/{ip}", get(ios_probe_handler))
         .route("/ios/{id}/stream", get(ios_stream_handler))
         .route("/ios/{id}/stream-eco", get(ios_stream_eco_handler))
         .route("/ios/{id}/zxtouch", get(ios_zxtouch_handler))
+        .route("/ios/broadcast/zxtouch", get(ios_broadcast_zxtouch_handler))
+        .route("/ios/macros", get(ios_macros_list_handler))
+        .route("/ios/{id}/macros/{name}/play", post(ios_macro_play_handler))
+        .route("/ios/{id}/script", get(ios_script_status_handler))
+        .route("/ios/{id}/script/play", post(ios_script_play_handler))
+        .route("/ios/{id}/script/stop", post(ios_script_stop_handler))
+        .route(
+            "/ios/{id}/scripts",
+            get(ios_scripts_list_handler)
+                .post(ios_scripts_upload_handler)
+                .layer(DefaultBodyLimit::max(MAX_BUNDLE_SIZE)),
+        )
+        .route(
+            "/ios/scripts",
+            post(ios_scripts_group_upload_handler).layer(DefaultBodyLimit::max(MAX_BUNDLE_SIZE)),
+        )
+        .route("/events", get(events_handler))
+        .route("/metrics", get(metrics_handler))
+        .route_layer(require_token.clone())
         .nest(
             "/bridge",
             Router::new()
+                .route("/sessions", get(adb_sessions_handler))
+                .route("/config", get(config_handler).put(config_update_handler))
+                .route("/logs", get(logs_handler))
+                .route("/", get(adb_websocket_handler))
+                .route_layer(require_token)
                 .route("/ping", get(|| async { env!("CARGO_PKG_VERSION") }))
-                .route(
-                    "/",
-                    get(|ws: WebSocketUpgrade| async { ws.on_upgrade(handle_websocket) }),
-                )
-                .route_layer(cors_layer()),
+                .route("/status", get(status_handler))
+                .route("/health", get(health_handler))
+                .route("/tls/ca.pem", get(tls_ca_handler))
+                .route("/pair", post(pair_start_handler))
+                .route("/pair/{id}", post(pair_complete_handler))
+                .route_layer(cors_layer(config.clone())),
         )
-        .route_layer(cors_layer())
-        .fallback(proxy_request);
-
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:15037")
-        .await
-        .unwrap();
+        .route_layer(cors_layer(config.clone()));
+    let app = match web {
+        Some(bundle) => app.fallback_service(bundle.router()),
+        None => app.fallback(proxy_request),
+    };
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            config.clone(),
+            origins::check_origin,
+        ))
+        .layer(middleware::from_fn_with_state(
+            config.clone(),
+            listen::check_peer,
+        ));
+
+    let shutdown = Shutdown::new();
+    let listeners = listen::bind(&addrs).await.unwrap();
+    let tls_listeners = match &tls {
+        Some(identity) => {
+            let addrs = listen::addrs(&settings.server, identity.port());
+            match listen::bind(&addrs).await {
+                Ok(listeners) => listeners
+                    .into_iter()
+                    .filter_map(|listener| {
+                        TlsListener::new(listener, identity.config.clone(), &shutdown).ok()
+                    })
+                    .collect(),
+                Err(err) => {
+                    tracing::error!(%err, "failed to listen for TLS");
+                    Vec::new()
+                }
+            }
+        }
+        None => Vec::new(),
+    };
 
-    let token = CancellationToken::new();
+    config.watch(shutdown.token());
+    logs.watch(&config, shutdown.token());
 
     let registry = DeviceRegistry::new();
-    let ios_provider = IosProvider::new(
+    let scanner = IosLanScanner::new(config.clone());
+    let provider = IosProvider::new(registry.clone(), scanner.clone(), config.clone());
+    let scans = provider.status();
+    provider.start(&shutdown);
+    if let Some(identity) = &tls {
+        identity.clone().renew(&shutdown);
+    }
+
+    let scripts = ScriptUploader::new(registry.clone());
+    let macros = MacroStore::new();
+    let zxtouch = ZxTouchSessions::new(registry.clone(), shutdown.clone());
+    let adb_policy: Option<std::sync::Arc<dyn RequestPolicy>> = match AccessPolicy::load() {
+        Ok(Some(policy)) => Some(std::sync::Arc::new(policy)),
+        Ok(None) => settings
+            .adb
+            .inspect
+            .then(|| std::sync::Arc::new(AllowAll) as _),
+        Err(err) => {
+            tracing::error!(err, "invalid ADB access policy, denying all requests");
+            Some(std::sync::Arc::new(AccessPolicy::deny_all()))
+        }
+    };
+    let adb_sessions = AdbSessions::new(
         registry.clone(),
-        IosLanScanner::new(Duration::from_millis(600), 64),
-        Duration::from_secs(10),
+        config.clone(),
+        adb_policy,
+        shutdown.clone(),
     );
-    let _ios_task = ios_provider.start();
 
-    let app = app.with_state(AppState { registry });
+    let proxy = Proxy::new(config.clone());
+
+    let app = app.with_state(AppState {
+        config,
+        registry,
+        scanner,
+        scripts,
+        macros,
+        zxtouch,
+        adb_sessions,
+        pairings: pairings.clone(),
+        tls,
+        logs: logs.clone(),
+        scans,
+        health: Health::new(),
+        proxy,
+        shutdown: shutdown.clone(),
+    });
 
-    let mut server = {
-        let token = token.clone();
+    let server = {
+        let token = shutdown.token();
         let server = tokio::spawn(async move {
-            axum::serve(listener, app)
-                .with_graceful_shutdown(token.cancelled_owned())
+            let plain = listeners.into_iter().map(|listener| {
+                axum::serve(
+                    listener,
+                    app.clone()
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(token.clone().cancelled_owned())
                 .into_future()
-                .await
+                .boxed()
+            });
+            // `tap_io` keeps the peer address available as `ConnectInfo<SocketAddr>`.
+            let tls = tls_listeners.into_iter().map(|listener| {
+                axum::serve(
+                    listener.tap_io(|stream| {
+                        let _ = stream.get_ref().0.set_nodelay(true);
+                    }),
+                    app.clone()
+                        .layer(Extension(ViaTls))
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(token.clone().cancelled_owned())
+                .into_future()
+                .boxed()
+            });
+            try_join_all(plain.chain(tls)).await.map(|_| ())
         });
-        Some(server)
+        server
     };
-    println!("server started on thread {:?}", thread::current().id());
-
-    // Temporarily disable auto-opening the website on startup.
-    // if env::args().all(|arg| arg != ARG_AUTO_RUN) {
-    //     start_browser()
-    // }
-
-    let menu_open = MenuItem::new("Open", true, None);
-
-    let auto_launch = AutoLaunchBuilder::new()
-        .set_app_name("Tango")
-        .set_app_path(env::current_exe().unwrap().to_str().unwrap())
-        .set_args(&[ARG_AUTO_RUN])
-        .set_use_launch_agent(true)
-        .build()
-        .unwrap();
-    let menu_auto_run = CheckMenuItem::new(
-        "Run at startup",
-        true,
-        auto_launch.is_enabled().unwrap(),
-        None,
-    );
-
-    let menu_quit = MenuItem::new("Quit", true, None);
-
-    let tray_menu = Menu::new();
-    tray_menu
-        .append_items(&[
-            &menu_open,
-            &menu_auto_run,
-            &PredefinedMenuItem::separator(),
-            &menu_quit,
-        ])
-        .unwrap();
-
-    let menu_receiver = MenuEvent::receiver();
-    let tray_receiver = TrayIconEvent::receiver();
-
-    let mut tray_icon = None;
-
-    #[allow(unused_mut)]
-    let mut event_loop = EventLoopBuilder::new().build();
-
-    #[cfg(target_os = "macos")]
-    {
-        use tao::platform::macos::EventLoopExtMacOS;
-
-        // https://github.com/glfw/glfw/issues/1552
-        event_loop.set_activation_policy(tao::platform::macos::ActivationPolicy::Accessory);
+    let exposure = listen::exposure(&settings.server);
+    tracing::info!(?addrs, exposure, logs = %logs.dir().display(), "bridge started");
+
+    #[cfg(feature = "tray")]
+    if !headless {
+        tray::run(tray::Tray {
+            shutdown,
+            server,
+            pairings,
+            logs_dir: logs.dir().to_path_buf(),
+            web_url: web_url(&settings),
+            tooltip: format!("Tango (rs)\n{}", exposure),
+        });
     }
 
-    println!("before main loop");
-
-    event_loop.run(move |event, _, control_flow| {
-        *control_flow =
-            tao::event_loop::ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(16));
-
-        if let tao::event::Event::Reopen { .. } = event {
-            start_browser();
-            return;
-        }
-
-        if let tao::event::Event::NewEvents(tao::event::StartCause::Init) = event {
-            let image = image::load_from_memory_with_format(
-                include_bytes!("../tango.png"),
-                image::ImageFormat::Png,
-            )
-            .unwrap()
-            .into_rgba8();
-            let (width, height) = image.dimensions();
-            let rgba = image.into_raw();
-            let icon = tray_icon::Icon::from_rgba(rgba, width, height).unwrap();
-
-            tray_icon = Some(
-                TrayIconBuilder::new()
-                    .with_tooltip("Tango (rs)")
-                    .with_icon(icon)
-                    .with_menu(Box::new(tray_menu.clone()))
-                    .build()
-                    .unwrap(),
-            );
-
-            #[cfg(target_os = "macos")]
-            unsafe {
-                use core_foundation::runloop::{CFRunLoopGetMain, CFRunLoopWakeUp};
-
-                let rl = CFRunLoopGetMain();
-                CFRunLoopWakeUp(rl);
-            }
-        }
-
-        if let Ok(event) = menu_receiver.try_recv() {
-            if event.id == menu_open.id() {
-                start_browser();
-                return;
-            }
-
-            if event.id == menu_auto_run.id() {
-                if auto_launch.is_enabled().unwrap() {
-                    auto_launch.disable().unwrap();
-                } else {
-                    auto_launch.enable().unwrap();
-                }
-                menu_auto_run.set_checked(auto_launch.is_enabled().unwrap());
-                return;
-            }
-
-            if event.id == menu_quit.id() {
-                tray_icon.take();
-
-                token.cancel();
-                println!("trigger token cancel");
-
-                let server = server.take().unwrap();
-                tokio::task::block_in_place(|| {
-                    tokio::runtime::Handle::current()
-                        .block_on(server)
-                        .unwrap()
-                        .unwrap();
-                    println!("server exited");
-                });
-
-                println!("exiting main loop");
-                *control_flow = tao::event_loop::ControlFlow::Exit;
-                return;
-            }
-        }
-
-        if let Ok(TrayIconEvent::Click {
-            button: tray_icon::MouseButton::Left,
-            button_state: tray_icon::MouseButtonState::Down,
-            ..
-        }) = tray_receiver.try_recv()
-        {
-            start_browser();
-            return;
-        }
-    });
+    if !daemon::run(shutdown, server).await {
+        std::process::exit(1);
+    }
 }
 
-fn cors_layer() -> CorsLayer {
+fn cors_layer(config: ConfigStore) -> CorsLayer {
     // Web UI is often hosted on HTTPS but talks to a local bridge
     // (e.g. https://app.example.com -> http://localhost:15037).
-    // Use permissive CORS here to avoid deployment-specific origin drift.
     CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers(Any)
-        .allow_origin(Any)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::OPTIONS])
+        .allow_headers(AllowHeaders::mirror_request())
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            origins::allows_header(&config.get(), origin)
+        }))
         .allow_private_network(true)
 }
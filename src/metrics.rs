@@ -0,0 +1,206 @@
+use std::{sync::OnceLock, time::Duration};
+
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::{Metric, Registry},
+};
+
+use crate::registry::UnifiedDevice;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceStatusLabels {
+    pub platform: String,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceLabels {
+    pub device: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct OutcomeLabels {
+    pub outcome: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RelayLabels {
+    /// `adb`, `ios_stream` or `zxtouch`.
+    pub relay: &'static str,
+    /// `to_client` or `to_device`.
+    pub direction: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StatusLabels {
+    /// The HTTP status, or `error` when the upstream couldn't be reached.
+    pub status: String,
+}
+
+/// Everything `/metrics` reports. Counters only go up for the life of the
+/// process, as Prometheus expects.
+pub struct Metrics {
+    registry: Registry,
+    pub devices: Family<DeviceStatusLabels, Gauge>,
+    pub scan_duration: Histogram,
+    pub probes: Family<OutcomeLabels, Counter>,
+    pub adb_sessions_active: Gauge,
+    pub adb_sessions: Counter,
+    pub stream_viewers: Family<DeviceLabels, Gauge>,
+    pub stream_upstreams: Family<DeviceLabels, Gauge>,
+    pub relayed_bytes: Family<RelayLabels, Counter>,
+    pub zxtouch_commands: Family<DeviceLabels, Counter>,
+    /// Client to device write, ZXTouch doesn't acknowledge touches.
+    pub zxtouch_queued: Histogram,
+    pub proxy_requests: Family<StatusLabels, Counter>,
+}
+
+/// Registers `metric` and hands it back for `Metrics` to keep.
+fn register<M: Metric + Clone>(registry: &mut Registry, name: &str, help: &str, metric: M) -> M {
+    registry.register(name, help, metric.clone());
+    metric
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut registry = Registry::with_prefix("tango");
+        Self {
+            devices: register(
+                &mut registry,
+                "devices",
+                "Known devices by platform and status",
+                Family::default(),
+            ),
+            scan_duration: register(
+                &mut registry,
+                "ios_scan_duration_seconds",
+                "Time taken by each sweep of the local network",
+                // 0.25 s to about 2 min.
+                Histogram::new(exponential_buckets(0.25, 2.0, 10)),
+            ),
+            probes: register(
+                &mut registry,
+                "ios_probes",
+                "iOS probes during sweeps by outcome, `found` or the error kind",
+                Family::default(),
+            ),
+            adb_sessions_active: register(
+                &mut registry,
+                "adb_sessions_active",
+                "Open ADB bridge sessions",
+                Gauge::default(),
+            ),
+            adb_sessions: register(
+                &mut registry,
+                "adb_sessions",
+                "ADB bridge sessions opened",
+                Counter::default(),
+            ),
+            stream_viewers: register(
+                &mut registry,
+                "ios_stream_viewers",
+                "Viewers connected to each device's stream",
+                Family::default(),
+            ),
+            stream_upstreams: register(
+                &mut registry,
+                "ios_stream_upstreams",
+                "Stream connections open to each device",
+                Family::default(),
+            ),
+            relayed_bytes: register(
+                &mut registry,
+                "relayed_bytes",
+                "Bytes relayed by each relay and direction",
+                Family::default(),
+            ),
+            zxtouch_commands: register(
+                &mut registry,
+                "zxtouch_commands",
+                "ZXTouch commands written to each device",
+                Family::default(),
+            ),
+            zxtouch_queued: register(
+                &mut registry,
+                "zxtouch_command_queued_seconds",
+                "Time a ZXTouch command waits in the bridge before it's written to the device",
+                // 0.1 ms to about 3 s.
+                Histogram::new(exponential_buckets(0.0001, 2.0, 16)),
+            ),
+            proxy_requests: register(
+                &mut registry,
+                "proxy_requests",
+                "Proxied web UI requests by response status",
+                Family::default(),
+            ),
+            registry,
+        }
+    }
+
+    /// Replaces the device counts, run on every scrape.
+    pub fn set_devices(&self, devices: &[UnifiedDevice]) {
+        self.devices.clear();
+        for device in devices {
+            self.devices
+                .get_or_create(&DeviceStatusLabels {
+                    platform: device.platform.clone(),
+                    status: device.status.clone(),
+                })
+                .inc();
+        }
+    }
+
+    pub fn relayed(&self, relay: &'static str, direction: &'static str, bytes: usize) {
+        self.relayed_bytes
+            .get_or_create(&RelayLabels { relay, direction })
+            .inc_by(bytes as u64);
+    }
+
+    pub fn zxtouch_command(&self, device: &str, queued: Duration) {
+        self.zxtouch_commands
+            .get_or_create(&DeviceLabels {
+                device: device.to_string(),
+            })
+            .inc();
+        self.zxtouch_queued.observe(queued.as_secs_f64());
+    }
+
+    pub fn encode(&self) -> String {
+        let mut text = String::new();
+        encode(&mut text, &self.registry).expect("writing to a String can't fail");
+        text
+    }
+}
+
+/// Adds one to a per-device gauge until dropped.
+pub struct DeviceGauge {
+    family: &'static Family<DeviceLabels, Gauge>,
+    labels: DeviceLabels,
+}
+
+impl DeviceGauge {
+    pub fn new(family: &'static Family<DeviceLabels, Gauge>, device: &str) -> Self {
+        let labels = DeviceLabels {
+            device: device.to_string(),
+        };
+        family.get_or_create(&labels).inc();
+        Self { family, labels }
+    }
+}
+
+impl Drop for DeviceGauge {
+    fn drop(&mut self) {
+        self.family.get_or_create(&self.labels).dec();
+    }
+}
@@ -0,0 +1,268 @@
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+
+/// PMT stream types of video: MPEG-1, MPEG-2, MPEG-4 part 2, H.264, HEVC.
+const VIDEO_STREAM_TYPES: [u8; 5] = [0x01, 0x02, 0x10, 0x1b, 0x24];
+
+fn pid(packet: &[u8]) -> u16 {
+    (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2])
+}
+
+fn payload_unit_start(packet: &[u8]) -> bool {
+    packet[1] & 0x40 != 0
+}
+
+/// Offset of the payload, or `None` when the packet carries none.
+fn payload_offset(packet: &[u8]) -> Option<usize> {
+    let control = (packet[3] >> 4) & 0x3;
+    let offset = match control {
+        0b01 => 4,
+        0b11 => 5 + usize::from(packet[4]),
+        _ => return None,
+    };
+    (offset < PACKET_SIZE).then_some(offset)
+}
+
+/// Whether the adaptation field has the random access indicator set,
+/// which encoders set on packets that start a keyframe.
+fn is_random_access(packet: &[u8]) -> bool {
+    let control = (packet[3] >> 4) & 0x3;
+    control & 0b10 != 0 && packet[4] > 0 && packet[5] & 0x40 != 0
+}
+
+/// Start of the PSI section in a packet and the end of its data, before
+/// the CRC. `None` unless the packet starts a section of at least `header`
+/// bytes.
+fn section_bounds(packet: &[u8], header: usize) -> Option<(usize, usize)> {
+    if !payload_unit_start(packet) {
+        return None;
+    }
+    let offset = payload_offset(packet)?;
+    let section = offset + 1 + usize::from(packet[offset]);
+    if section + header > PACKET_SIZE {
+        return None;
+    }
+    let section_length =
+        (usize::from(packet[section + 1] & 0x0f) << 8) | usize::from(packet[section + 2]);
+    let end = (section + 3 + section_length)
+        .saturating_sub(4)
+        .min(PACKET_SIZE);
+    Some((section, end))
+}
+
+/// PMT PIDs listed in a PAT packet.
+fn pmt_pids(packet: &[u8]) -> Vec<u16> {
+    let mut pids = Vec::new();
+    // Programs start after the 8 byte header.
+    let Some((section, end)) = section_bounds(packet, 8) else {
+        return pids;
+    };
+
+    let mut entry = section + 8;
+    while entry + 4 <= end {
+        let program = u16::from_be_bytes([packet[entry], packet[entry + 1]]);
+        // Program 0 points at the network PID, not a PMT.
+        if program != 0 {
+            pids.push((u16::from(packet[entry + 2] & 0x1f) << 8) | u16::from(packet[entry + 3]));
+        }
+        entry += 4;
+    }
+    pids
+}
+
+/// Video elementary stream PIDs listed in a PMT packet.
+fn video_pids(packet: &[u8]) -> Vec<u16> {
+    let mut pids = Vec::new();
+    // Streams start after the 12 byte header and the program descriptors.
+    let Some((section, end)) = section_bounds(packet, 12) else {
+        return pids;
+    };
+    let program_info_length =
+        (usize::from(packet[section + 10] & 0x0f) << 8) | usize::from(packet[section + 11]);
+
+    let mut entry = section + 12 + program_info_length;
+    while entry + 5 <= end {
+        let stream_type = packet[entry];
+        let pid = (u16::from(packet[entry + 1] & 0x1f) << 8) | u16::from(packet[entry + 2]);
+        if VIDEO_STREAM_TYPES.contains(&stream_type) {
+            pids.push(pid);
+        }
+        let es_info_length =
+            (usize::from(packet[entry + 3] & 0x0f) << 8) | usize::from(packet[entry + 4]);
+        entry += 5 + es_info_length;
+    }
+    pids
+}
+
+/// Holds back an MPEG-TS stream until the next keyframe.
+///
+/// Used after an upstream reconnect so the player never receives a partial
+/// GOP. Opens on the first video packet with the random access indicator
+/// once a PMT has named the video PID. The latest PAT and PMT are replayed
+/// in front of the keyframe so the demuxer can pick up a restarted encoder.
+#[derive(Default)]
+pub struct KeyframeGate {
+    pending: Vec<u8>,
+    pat: Option<Vec<u8>>,
+    pmts: Vec<(u16, Vec<u8>)>,
+    open: bool,
+}
+
+impl KeyframeGate {
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Feeds upstream bytes and returns what may be sent to the viewer.
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.open {
+            return data.to_vec();
+        }
+
+        self.pending.extend_from_slice(data);
+        let mut offset = 0;
+        while offset + PACKET_SIZE <= self.pending.len() {
+            if self.pending[offset] != SYNC_BYTE {
+                // Resynchronize on the next sync byte.
+                offset += 1;
+                continue;
+            }
+
+            let packet = &self.pending[offset..offset + PACKET_SIZE];
+            let pid = pid(packet);
+            if pid == PAT_PID {
+                let pids = pmt_pids(packet);
+                if !pids.is_empty() {
+                    self.pmts.retain(|(pmt, _)| pids.contains(pmt));
+                    self.pat = Some(packet.to_vec());
+                }
+            } else if let Some(pmt) = self.pmts.iter_mut().find(|(pmt, _)| *pmt == pid) {
+                pmt.1 = packet.to_vec();
+            } else if self.is_pmt_pid(pid) {
+                self.pmts.push((pid, packet.to_vec()));
+            } else if is_random_access(packet) && self.is_video_pid(pid) {
+                let mut out = Vec::new();
+                if let Some(pat) = &self.pat {
+                    out.extend_from_slice(pat);
+                }
+                for (_, pmt) in &self.pmts {
+                    out.extend_from_slice(pmt);
+                }
+                out.extend_from_slice(&self.pending[offset..]);
+                self.pending = Vec::new();
+                self.open = true;
+                return out;
+            }
+
+            offset += PACKET_SIZE;
+        }
+
+        self.pending.drain(..offset);
+        Vec::new()
+    }
+
+    /// Audio packets carry the random access indicator too, only a video
+    /// one starts a keyframe.
+    fn is_video_pid(&self, pid: u16) -> bool {
+        self.pmts
+            .iter()
+            .any(|(_, pmt)| video_pids(pmt).contains(&pid))
+    }
+
+    fn is_pmt_pid(&self, pid: u16) -> bool {
+        self.pat
+            .as_deref()
+            .is_some_and(|pat| pmt_pids(pat).contains(&pid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PMT_PID: u16 = 0x1000;
+    const VIDEO_PID: u16 = 0x100;
+    const AUDIO_PID: u16 = 0x101;
+
+    /// A packet with `payload` padded to size, and an adaptation field with
+    /// the random access indicator when `random_access` is set.
+    fn packet(pid: u16, start: bool, random_access: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            SYNC_BYTE,
+            (u8::from(start) << 6) | (pid >> 8) as u8,
+            pid as u8,
+            0x10,
+        ];
+        if random_access {
+            packet[3] = 0x30;
+            packet.extend_from_slice(&[1, 0x40]);
+        }
+        packet.extend_from_slice(payload);
+        packet.resize(PACKET_SIZE, 0xff);
+        packet
+    }
+
+    fn pat() -> Vec<u8> {
+        let mut section = vec![0x00, 0xb0, 13, 0x00, 0x01, 0xc1, 0x00, 0x00];
+        section.extend_from_slice(&[0x00, 0x01, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8]);
+        section.extend_from_slice(&[0; 4]);
+        packet(PAT_PID, true, false, &[&[0][..], &section].concat())
+    }
+
+    /// H.264 video and AAC audio, with a program descriptor in front.
+    fn pmt() -> Vec<u8> {
+        let mut section = vec![0x02, 0xb0, 30, 0x00, 0x01, 0xc1, 0x00, 0x00];
+        section.extend_from_slice(&[0xe1, 0x00, 0xf0, 0x03, 0x0e, 0x01, 0xff]);
+        section.extend_from_slice(&[0x1b, 0xe1, 0x00, 0xf0, 0x00]);
+        section.extend_from_slice(&[0x0f, 0xe1, 0x01, 0xf0, 0x04, 0x0a, 0x02, b'e', b'n']);
+        section.extend_from_slice(&[0; 4]);
+        packet(PMT_PID, true, false, &[&[0][..], &section].concat())
+    }
+
+    #[test]
+    fn reads_video_pids_from_the_pmt() {
+        assert_eq!(pmt_pids(&pat()), [PMT_PID]);
+        assert_eq!(video_pids(&pmt()), [VIDEO_PID]);
+    }
+
+    #[test]
+    fn opens_on_a_video_keyframe_only() {
+        let mut gate = KeyframeGate::default();
+        let mut stream = [pat(), pmt()].concat();
+        // Audio frames are all random access points.
+        stream.extend(packet(AUDIO_PID, true, true, b"audio"));
+        stream.extend(packet(VIDEO_PID, true, false, b"p-frame"));
+        assert!(gate.push(&stream).is_empty());
+        assert!(!gate.is_open());
+
+        let keyframe = packet(VIDEO_PID, true, true, b"idr");
+        let audio = packet(AUDIO_PID, true, true, b"audio");
+        // Split mid-packet, as reads from the upstream are.
+        assert!(gate.push(&keyframe[..100]).is_empty());
+        let out = gate.push(&[&keyframe[100..], &audio[..]].concat());
+        assert!(gate.is_open());
+        assert_eq!(out, [pat(), pmt(), keyframe, audio].concat());
+    }
+
+    #[test]
+    fn waits_for_a_pmt() {
+        let mut gate = KeyframeGate::default();
+        assert!(gate.push(&packet(VIDEO_PID, true, true, b"idr")).is_empty());
+        assert!(gate.push(&[pat(), pmt()].concat()).is_empty());
+        assert!(!gate.push(&packet(VIDEO_PID, true, true, b"idr")).is_empty());
+    }
+
+    #[test]
+    fn resynchronizes_after_garbage() {
+        let mut gate = KeyframeGate::default();
+        let stream = [
+            vec![0x00, 0x12, 0x34],
+            pat(),
+            pmt(),
+            packet(VIDEO_PID, true, true, b"idr"),
+        ]
+        .concat();
+        assert_eq!(gate.push(&stream), stream[3..]);
+    }
+}
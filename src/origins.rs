@@ -0,0 +1,198 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header, HeaderValue, Method, StatusCode};
+
+use crate::config::{Config, ConfigStore};
+
+pub const DEFAULT_ORIGIN: &str = "https://app.tangoapp.dev";
+
+/// `<scheme>://<host>[:<port>]` where the host may start with `*.` to match
+/// any subdomain and the port may be `*`.
+#[derive(Debug, Clone, PartialEq)]
+struct OriginPattern {
+    scheme: String,
+    host: String,
+    port: Option<String>,
+}
+
+impl OriginPattern {
+    fn parse(value: &str) -> Option<Self> {
+        let (scheme, rest) = value.trim().trim_end_matches('/').split_once("://")?;
+        let (host, port) = match rest.rsplit_once(':') {
+            // Skip the colons inside an IPv6 literal.
+            Some((host, port)) if !port.contains(']') => (host, Some(port.to_string())),
+            _ => (rest, None),
+        };
+        if scheme.is_empty() || host.is_empty() || host.contains('/') {
+            return None;
+        }
+
+        Some(Self {
+            scheme: scheme.to_ascii_lowercase(),
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+
+    fn matches(&self, origin: &OriginPattern) -> bool {
+        let host_matches = match self.host.strip_prefix("*.") {
+            Some(domain) => origin
+                .host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            None => self.host == origin.host,
+        };
+        let port_matches = self.port.as_deref() == Some("*") || self.port == origin.port;
+
+        self.scheme == origin.scheme && host_matches && port_matches
+    }
+}
+
+pub fn is_valid_pattern(value: &str) -> bool {
+    value.trim() == "*" || OriginPattern::parse(value).is_some()
+}
+
+/// Whether `origin` may call the bridge from a browser: the Tango web app,
+/// the bridge's own addresses, and `server.allow_origins` such as
+/// `https://*.tangoapp.dev`, `http://localhost:*` or `*` for any.
+pub fn allows(config: &Config, origin: &str) -> bool {
+    let Some(origin) = OriginPattern::parse(origin) else {
+        return false;
+    };
+
+    // The web app proxied through the bridge runs on the bridge's own origin.
+    let is_local = matches!(origin.host.as_str(), "localhost" | "127.0.0.1" | "[::1]");
+    let port = origin
+        .port
+        .as_deref()
+        .and_then(|port| port.parse::<u16>().ok());
+    let is_bridge = is_local
+        && match origin.scheme.as_str() {
+            "http" => port == Some(config.server.port),
+            "https" => config.tls.enabled && port == Some(config.tls.port),
+            _ => false,
+        };
+
+    is_bridge
+        || OriginPattern::parse(DEFAULT_ORIGIN).is_some_and(|pattern| pattern.matches(&origin))
+        || config.server.allow_origins.iter().any(|value| {
+            value.trim() == "*"
+                || OriginPattern::parse(value).is_some_and(|pattern| pattern.matches(&origin))
+        })
+}
+
+pub fn allows_header(config: &Config, origin: &HeaderValue) -> bool {
+    origin.to_str().is_ok_and(|origin| allows(config, origin))
+}
+
+/// CORS doesn't apply to WebSockets and doesn't stop simple requests from
+/// running, so reject those from other origins here.
+pub async fn check_origin(
+    State(config): State<ConfigStore>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let is_upgrade = headers
+        .get(header::UPGRADE)
+        .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"));
+    let has_effects = !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+
+    if is_upgrade || has_effects {
+        if let Some(origin) = headers.get(header::ORIGIN) {
+            if !allows_header(&config.get(), origin) {
+                tracing::warn!(?origin, uri = %request.uri(), "rejected request from origin not in allowlist");
+                return (StatusCode::FORBIDDEN, "origin not allowed").into_response();
+            }
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allow_origins: &[&str]) -> Config {
+        let mut config = Config::default();
+        config.server.allow_origins = allow_origins
+            .iter()
+            .map(|value| value.to_string())
+            .collect();
+        config
+    }
+
+    #[test]
+    fn allows_the_web_app() {
+        let config = config(&[]);
+        assert!(allows(&config, DEFAULT_ORIGIN));
+        assert!(allows(&config, "HTTPS://App.TangoApp.dev"));
+        assert!(!allows(&config, "https://evil-tangoapp.dev"));
+        assert!(!allows(&config, "https://app.tangoapp.dev.evil.example"));
+        assert!(!allows(&config, "http://app.tangoapp.dev"));
+        assert!(!allows(&config, "https://app.tangoapp.dev:8443"));
+        assert!(!allows(&config, "null"));
+        assert!(!allows(&config, ""));
+    }
+
+    #[test]
+    fn matches_subdomain_wildcards() {
+        let config = config(&["https://*.tangoapp.dev"]);
+        assert!(allows(&config, "https://preview.tangoapp.dev"));
+        assert!(allows(&config, "https://a.b.tangoapp.dev"));
+        assert!(!allows(&config, "https://tangoapp.dev"));
+        assert!(!allows(&config, "https://evil-tangoapp.dev"));
+        assert!(!allows(&config, "https://eviltangoapp.dev"));
+        assert!(!allows(&config, "https://preview.tangoapp.dev:444"));
+    }
+
+    #[test]
+    fn matches_ports() {
+        let config = config(&["http://localhost:*", "http://10.0.0.2:3000"]);
+        assert!(allows(&config, "http://localhost:5173"));
+        assert!(!allows(&config, "https://localhost:5173"));
+        assert!(allows(&config, "http://10.0.0.2:3000"));
+        assert!(!allows(&config, "http://10.0.0.2:3001"));
+        assert!(!allows(&config, "http://10.0.0.2"));
+        assert!(!allows(&config, "http://10.0.0.20:3000"));
+    }
+
+    #[test]
+    fn allows_the_bridge_itself() {
+        let mut config = config(&[]);
+        let port = config.server.port;
+        assert!(allows(&config, &format!("http://localhost:{}", port)));
+        assert!(allows(&config, &format!("http://127.0.0.1:{}", port)));
+        assert!(allows(&config, &format!("http://[::1]:{}", port)));
+        assert!(!allows(&config, &format!("http://localhost:{}", port + 1)));
+        assert!(!allows(&config, &format!("http://evil.example:{}", port)));
+
+        let tls = format!("https://localhost:{}", config.tls.port);
+        assert!(!allows(&config, &tls));
+        config.tls.enabled = true;
+        assert!(allows(&config, &tls));
+    }
+
+    #[test]
+    fn allows_any_origin_with_a_star() {
+        let config = config(&["*"]);
+        assert!(allows(&config, "https://evil.example"));
+        assert!(!allows(&config, "null"));
+    }
+
+    #[test]
+    fn validates_patterns() {
+        assert!(is_valid_pattern("*"));
+        assert!(is_valid_pattern("https://*.tangoapp.dev"));
+        assert!(is_valid_pattern("http://[::1]:8080"));
+        assert!(!is_valid_pattern("tangoapp.dev"));
+        assert!(!is_valid_pattern("https://tangoapp.dev/path"));
+    }
+}
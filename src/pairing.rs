@@ -0,0 +1,512 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ConfigStore, listen, paths};
+
+const PAIRINGS_FILE: &str = "pairings.json";
+
+/// How long a pairing code can be used.
+const PAIRING_TTL: Duration = Duration::from_secs(120);
+
+const MAX_CODE_ATTEMPTS: u32 = 5;
+
+/// Wrong codes across all tickets before pairing is paused, so new tickets
+/// don't give a client fresh guesses.
+const MAX_FAILURES: u32 = 10;
+
+/// Failures older than this are forgotten.
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+const COOLDOWN: Duration = Duration::from_secs(60);
+
+/// WebSocket subprotocol the bridge answers with when a client sends its
+/// token as `bearer.<token>` next to it.
+pub const SUBPROTOCOL: &str = "tango-bridge";
+
+const TOKEN_SUBPROTOCOL_PREFIX: &str = "bearer.";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PairedOrigin {
+    pub origin: String,
+    pub token: String,
+    /// Unix time in milliseconds.
+    pub paired_at: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingPairing {
+    pub id: String,
+    pub origin: String,
+    pub code: String,
+    created: Instant,
+    approved: bool,
+    attempts: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PairingError {
+    MissingOrigin,
+    NotFound,
+    Pending,
+    InvalidCode,
+    /// Too many wrong codes, pairing with a code is paused.
+    TooManyAttempts {
+        retry_after_secs: u64,
+    },
+    Unauthorized,
+    Io {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingTicket {
+    pub id: String,
+    pub expires_in_ms: u64,
+}
+
+#[derive(Default)]
+struct PairingState {
+    paired: Vec<PairedOrigin>,
+    pending: Vec<PendingPairing>,
+    failures: Failures,
+}
+
+/// Wrong codes from any client.
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    since: Option<Instant>,
+    paused_until: Option<Instant>,
+}
+
+impl Failures {
+    fn check(&self) -> Result<(), PairingError> {
+        match self.paused_until {
+            Some(until) if until > Instant::now() => Err(PairingError::TooManyAttempts {
+                retry_after_secs: (until - Instant::now()).as_secs() + 1,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&mut self) {
+        if self
+            .since
+            .is_none_or(|since| since.elapsed() > FAILURE_WINDOW)
+        {
+            self.count = 0;
+            self.since = Some(Instant::now());
+        }
+        self.count += 1;
+        if self.count >= MAX_FAILURES {
+            tracing::warn!(
+                failures = self.count,
+                "too many wrong pairing codes, pausing pairing"
+            );
+            self.paused_until = Some(Instant::now() + COOLDOWN);
+            self.count = 0;
+            self.since = None;
+        }
+    }
+}
+
+/// Origins paired with the bridge, persisted in the config directory.
+#[derive(Clone)]
+pub struct Pairings {
+    path: PathBuf,
+    state: Arc<Mutex<PairingState>>,
+    /// Bumped on every change so the tray knows to rebuild its menu.
+    version: Arc<AtomicU64>,
+    /// Without a tray, codes are printed to stderr instead.
+    print_codes: bool,
+}
+
+impl Pairings {
+    pub fn load(print_codes: bool) -> Self {
+        let path = paths::config_dir().join(PAIRINGS_FILE);
+        let paired = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|err| {
+                tracing::error!(path = %path.display(), %err, "invalid pairings file");
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        Self {
+            path,
+            state: Arc::new(Mutex::new(PairingState {
+                paired,
+                ..PairingState::default()
+            })),
+            version: Arc::default(),
+            print_codes,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PairingState> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.pending.len();
+        state
+            .pending
+            .retain(|pending| pending.created.elapsed() < PAIRING_TTL);
+        if state.pending.len() != before {
+            self.changed();
+        }
+        state
+    }
+
+    fn changed(&self) {
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn save(&self, state: &PairingState) -> Result<(), PairingError> {
+        let io_error = |err: std::io::Error| PairingError::Io {
+            message: err.to_string(),
+        };
+        if let Some(dir) = self.path.parent() {
+            paths::ensure_dir(dir.to_path_buf()).map_err(io_error)?;
+        }
+        let text = serde_json::to_string_pretty(&state.paired).unwrap();
+        // Holds the tokens.
+        paths::write_private(&self.path, text.as_bytes()).map_err(io_error)
+    }
+
+    /// Starts pairing `origin`. The code is shown in the tray or on stderr,
+    /// never sent back to the client that asked for it. It stays out of the
+    /// log, which `/bridge/logs` serves.
+    pub fn start(&self, origin: &str) -> Result<PairingTicket, PairingError> {
+        self.lock().failures.check()?;
+        let pending = PendingPairing {
+            id: random_hex::<16>(),
+            origin: origin.to_string(),
+            code: format!("{:06}", random_u32() % 1_000_000),
+            created: Instant::now(),
+            approved: false,
+            attempts: 0,
+        };
+        tracing::info!(origin, "pairing requested");
+        if self.print_codes {
+            eprintln!("Pairing code for {}: {}", origin, pending.code);
+        }
+
+        let ticket = PairingTicket {
+            id: pending.id.clone(),
+            expires_in_ms: PAIRING_TTL.as_millis() as u64,
+        };
+
+        let mut state = self.lock();
+        state.pending.retain(|existing| existing.origin != origin);
+        state.pending.push(pending);
+        self.changed();
+
+        Ok(ticket)
+    }
+
+    /// Finishes a pairing approved in the tray or confirmed with its code,
+    /// and returns the token for the origin.
+    pub fn complete(&self, id: &str, code: Option<&str>) -> Result<String, PairingError> {
+        let mut state = self.lock();
+        let index = state
+            .pending
+            .iter()
+            .position(|pending| pending.id == id)
+            .ok_or(PairingError::NotFound)?;
+
+        let pending = &mut state.pending[index];
+        if !pending.approved {
+            let Some(code) = code else {
+                return Err(PairingError::Pending);
+            };
+            state.failures.check()?;
+            let pending = &mut state.pending[index];
+            if code.trim() != pending.code {
+                pending.attempts += 1;
+                if pending.attempts >= MAX_CODE_ATTEMPTS {
+                    state.pending.remove(index);
+                    self.changed();
+                }
+                state.failures.record();
+                return Err(PairingError::InvalidCode);
+            }
+        }
+
+        let pending = state.pending.remove(index);
+        let token = random_hex::<32>();
+        state
+            .paired
+            .retain(|paired| paired.origin != pending.origin);
+        state.paired.push(PairedOrigin {
+            origin: pending.origin.clone(),
+            token: token.clone(),
+            paired_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or_default(),
+        });
+        self.changed();
+        self.save(&state)?;
+        tracing::info!(origin = pending.origin, "origin paired");
+
+        Ok(token)
+    }
+
+    /// A token is only valid for the origin it was issued to. Clients that
+    /// don't send an origin aren't browsers and may use any paired token.
+    pub fn verify(&self, token: &str, origin: Option<&str>) -> bool {
+        self.lock().paired.iter().any(|paired| {
+            paired.token == token && origin.is_none_or(|origin| origin == paired.origin)
+        })
+    }
+}
+
+/// Used by the tray menu.
+#[cfg(feature = "tray")]
+impl Pairings {
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    pub fn approve(&self, id: &str) {
+        let mut state = self.lock();
+        if let Some(pending) = state.pending.iter_mut().find(|pending| pending.id == id) {
+            pending.approved = true;
+            self.changed();
+        }
+    }
+
+    pub fn revoke(&self, origin: &str) {
+        let mut state = self.lock();
+        state.paired.retain(|paired| paired.origin != origin);
+        self.changed();
+        if let Err(err) = self.save(&state) {
+            tracing::error!(?err, "failed to save pairings");
+        }
+        tracing::info!(origin, "origin revoked");
+    }
+
+    /// Pairings waiting for approval that haven't been approved yet.
+    pub fn pending(&self) -> Vec<PendingPairing> {
+        self.lock()
+            .pending
+            .iter()
+            .filter(|pending| !pending.approved)
+            .cloned()
+            .collect()
+    }
+
+    pub fn paired_origins(&self) -> Vec<String> {
+        self.lock()
+            .paired
+            .iter()
+            .map(|paired| paired.origin.clone())
+            .collect()
+    }
+}
+
+/// Reads the token from `Authorization: Bearer` or the WebSocket subprotocols.
+pub fn request_token(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let subprotocol = || {
+        subprotocols(headers).find_map(|protocol| protocol.strip_prefix(TOKEN_SUBPROTOCOL_PREFIX))
+    };
+
+    bearer.or_else(subprotocol).map(str::to_string)
+}
+
+/// Removes the token from requests that leave the bridge, keeping the other
+/// subprotocols.
+pub fn strip_token(headers: &mut HeaderMap) {
+    headers.remove(header::AUTHORIZATION);
+    let others = subprotocols(headers)
+        .filter(|protocol| !protocol.starts_with(TOKEN_SUBPROTOCOL_PREFIX))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let others = (!others.is_empty()).then(|| HeaderValue::from_str(&others));
+    headers.remove(header::SEC_WEBSOCKET_PROTOCOL);
+    if let Some(Ok(others)) = others {
+        headers.insert(header::SEC_WEBSOCKET_PROTOCOL, others);
+    }
+}
+
+fn subprotocols(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// Lets requests through only with a paired token. Local programs that
+/// aren't browsers (no `Origin`) don't need one, as long as they address
+/// the bridge by a local name. Browsers leave out `Origin` on same-origin
+/// GETs, which a DNS rebinding page would make.
+pub async fn require_token(
+    State((pairings, config)): State<(Pairings, ConfigStore)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let origin = headers
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| {
+            request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str())
+        });
+    let is_local = peer.ip().to_canonical().is_loopback()
+        && host.is_some_and(|host| listen::is_local_host(&config.get().server, host));
+
+    if origin.is_some() || !is_local {
+        let authorized =
+            request_token(headers).is_some_and(|token| pairings.verify(&token, origin));
+        if !authorized {
+            tracing::warn!(%peer, origin, host, uri = %request.uri(), "rejected unpaired client");
+            return (StatusCode::UNAUTHORIZED, Json(PairingError::Unauthorized)).into_response();
+        }
+    }
+
+    // Browsers drop the connection unless one of the offered subprotocols
+    // is selected.
+    let offers_subprotocol = subprotocols(headers).any(|protocol| protocol == SUBPROTOCOL);
+    let mut response = next.run(request).await;
+    if offers_subprotocol && response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+    }
+    response
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut buf = [0u8; N];
+    getrandom::fill(&mut buf).expect("system random number generator unavailable");
+    buf
+}
+
+fn random_u32() -> u32 {
+    u32::from_le_bytes(random_bytes())
+}
+
+fn random_hex<const N: usize>() -> String {
+    random_bytes::<N>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = "https://app.tangoapp.dev";
+
+    fn pairings() -> Pairings {
+        Pairings {
+            path: std::env::temp_dir().join(format!("tango-pairings-{}.json", random_hex::<8>())),
+            state: Arc::default(),
+            version: Arc::default(),
+            print_codes: false,
+        }
+    }
+
+    fn code(pairings: &Pairings, id: &str) -> String {
+        let state = pairings.lock();
+        let pending = state.pending.iter().find(|pending| pending.id == id);
+        pending.unwrap().code.clone()
+    }
+
+    fn wrong(code: &str) -> String {
+        format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000)
+    }
+
+    #[test]
+    fn pairs_with_the_code() {
+        let pairings = pairings();
+        let ticket = pairings.start(ORIGIN).unwrap();
+        let code = code(&pairings, &ticket.id);
+        let token = pairings.complete(&ticket.id, Some(&code)).unwrap();
+        assert!(pairings.verify(&token, Some(ORIGIN)));
+        assert!(!pairings.verify(&token, Some("https://evil.example")));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&pairings.path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_file(&pairings.path);
+    }
+
+    #[test]
+    fn drops_a_ticket_after_too_many_wrong_codes() {
+        let pairings = pairings();
+        let ticket = pairings.start(ORIGIN).unwrap();
+        let wrong = wrong(&code(&pairings, &ticket.id));
+        for _ in 0..MAX_CODE_ATTEMPTS {
+            assert!(matches!(
+                pairings.complete(&ticket.id, Some(&wrong)),
+                Err(PairingError::InvalidCode)
+            ));
+        }
+        assert!(matches!(
+            pairings.complete(&ticket.id, Some(&wrong)),
+            Err(PairingError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn pauses_after_wrong_codes_across_tickets() {
+        let pairings = pairings();
+        for _ in 0..MAX_FAILURES {
+            let ticket = pairings.start(ORIGIN).unwrap();
+            let wrong = wrong(&code(&pairings, &ticket.id));
+            assert!(matches!(
+                pairings.complete(&ticket.id, Some(&wrong)),
+                Err(PairingError::InvalidCode)
+            ));
+        }
+
+        assert!(matches!(
+            pairings.start(ORIGIN),
+            Err(PairingError::TooManyAttempts { .. })
+        ));
+        // The last ticket is still there, but its code can't be tried now.
+        let id = pairings.lock().pending[0].id.clone();
+        let code = code(&pairings, &id);
+        assert!(matches!(
+            pairings.complete(&id, Some(&code)),
+            Err(PairingError::TooManyAttempts { .. })
+        ));
+    }
+}
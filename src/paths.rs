@@ -0,0 +1,77 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const APP_DIR: &str = "tango-bridge";
+
+/// Per-user data directory, e.g. `~/.local/share/tango-bridge` on Linux.
+pub fn data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(APP_DIR)
+}
+
+/// Per-user config directory, e.g. `~/.config/tango-bridge` on Linux.
+pub fn config_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(APP_DIR)
+}
+
+/// Per-user cache directory, e.g. `~/.cache/tango-bridge` on Linux.
+pub fn cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(APP_DIR)
+}
+
+/// Per-user log directory: `~/Library/Logs/tango-bridge` on macOS,
+/// `%LOCALAPPDATA%\tango-bridge\logs` on Windows and
+/// `~/.local/state/tango-bridge/logs` on Linux.
+pub fn log_dir() -> PathBuf {
+    if cfg!(target_os = "macos") {
+        if let Some(home) = dirs::home_dir() {
+            return home.join("Library/Logs").join(APP_DIR);
+        }
+    }
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(APP_DIR)
+        .join("logs")
+}
+
+/// Creates `dir` and its parents if needed and returns it.
+pub fn ensure_dir(dir: PathBuf) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Writes a file only the current user can read, for secrets.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // `mode` only applies to new files.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
+
+/// Whether `name` is safe to use as a single path component.
+pub fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
@@ -0,0 +1,425 @@
+use std::{net::SocketAddr, time::Instant};
+
+use axum::{
+    body::Body,
+    extract::Request,
+    response::{IntoResponse, Response},
+};
+use bytes::BytesMut;
+use futures_util::{stream, StreamExt};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ConfigStore,
+    metrics::{metrics, StatusLabels},
+    pairing,
+    proxy_cache::{etag_matches, CacheControl, Entry, ProxyCache, MAX_ENTRY_SIZE},
+};
+
+/// Meaningful for a single connection only, never forwarded.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Tells clients, and whoever reads the log, where a response came from.
+const X_CACHE: &str = "x-cache";
+
+/// What the upstream is told about the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedPolicy {
+    /// No `X-Forwarded-*` or `Forwarded` headers at all.
+    Off,
+    /// `X-Forwarded-*` describing the connection to the bridge, replacing
+    /// whatever the client sent.
+    Set,
+    /// Adds to the client's `X-Forwarded-For`, for a bridge behind a proxy
+    /// that's trusted to set it.
+    Append,
+}
+
+/// Added by the TLS listeners, for `X-Forwarded-Proto`.
+#[derive(Debug, Clone, Copy)]
+pub struct ViaTls;
+
+#[derive(Debug, Clone, Copy)]
+enum CacheOutcome {
+    /// Not a request or response that can be cached.
+    Bypass,
+    Hit,
+    Miss,
+    /// Checked with the upstream, which said it's unchanged.
+    Revalidated,
+    /// Served from the cache because the upstream failed.
+    Stale,
+}
+
+impl CacheOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheOutcome::Bypass => "bypass",
+            CacheOutcome::Hit => "hit",
+            CacheOutcome::Miss => "miss",
+            CacheOutcome::Revalidated => "revalidated",
+            CacheOutcome::Stale => "stale",
+        }
+    }
+}
+
+/// Forwards requests no bridge route handles to `proxy.upstream`, which is
+/// how the web UI is served from the bridge's own origin.
+#[derive(Clone)]
+pub struct Proxy {
+    config: ConfigStore,
+    client: reqwest::Client,
+}
+
+impl Proxy {
+    pub fn new(config: ConfigStore) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn forward(&self, request: Request, peer: SocketAddr, tls: bool) -> Response {
+        let started = Instant::now();
+        let method = request.method().clone();
+        let uri = request.uri().clone();
+
+        // Only failing to reach the upstream is an error.
+        let (mut response, outcome, reached) = match self.send(request, peer, tls).await {
+            Ok((response, outcome)) => (response, outcome, true),
+            Err(response) => (response, CacheOutcome::Bypass, false),
+        };
+        if !matches!(outcome, CacheOutcome::Bypass) {
+            response
+                .headers_mut()
+                .insert(X_CACHE, HeaderValue::from_static(outcome.as_str()));
+        }
+
+        let status = response.status();
+        let label = if reached {
+            status.as_str().to_string()
+        } else {
+            "error".to_string()
+        };
+        metrics()
+            .proxy_requests
+            .get_or_create(&StatusLabels { status: label })
+            .inc();
+        tracing::info!(
+            %method,
+            %uri,
+            status = status.as_u16(),
+            cache = outcome.as_str(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "proxy request"
+        );
+        response
+    }
+
+    async fn send(
+        &self,
+        request: Request,
+        peer: SocketAddr,
+        tls: bool,
+    ) -> Result<(Response, CacheOutcome), Response> {
+        let settings = self.config.get();
+        let config = &settings.proxy;
+
+        // Validated when the config is loaded.
+        let upstream = Url::parse(&config.upstream).unwrap();
+        let Some(url) = target(&upstream, &request.uri().to_string()) else {
+            let response = (StatusCode::BAD_REQUEST, "Bad Request").into_response();
+            return Ok((response, CacheOutcome::Bypass));
+        };
+
+        let (parts, body) = request.into_parts();
+        let mut headers = parts.headers;
+        strip_hop_by_hop(&mut headers);
+        // The bridge's own token is no business of the upstream's.
+        pairing::strip_token(&mut headers);
+        let host = headers.remove(header::HOST);
+        forwarded(&mut headers, config.forwarded, peer, host, tls);
+
+        let cacheable =
+            config.cache && parts.method == Method::GET && !headers.contains_key(header::RANGE);
+        if !cacheable {
+            let response = self
+                .client
+                .request(parts.method, url)
+                .headers(headers)
+                .body(reqwest::Body::wrap_stream(body.into_data_stream()))
+                .send()
+                .await
+                .map_err(|err| bad_gateway(&err))?;
+            return Ok((pass_through(response), CacheOutcome::Bypass));
+        }
+
+        let cache = ProxyCache::new(config.cache_dir());
+        let key = url.to_string();
+        let if_none_match = headers.remove(header::IF_NONE_MATCH);
+        headers.remove(header::IF_MODIFIED_SINCE);
+
+        let mut stored = cache
+            .get(&key)
+            .await
+            .filter(|entry| entry.matches(&headers));
+        if let Some(entry) = &stored {
+            if entry.is_fresh() && !CacheControl::wants_revalidation(&headers) {
+                return Ok((respond(entry, if_none_match.as_ref()), CacheOutcome::Hit));
+            }
+            headers.extend(entry.validators());
+        }
+
+        let response = self.client.get(url).headers(headers.clone()).send().await;
+        let mut response = match (response, stored.as_mut()) {
+            (Ok(response), Some(entry)) if response.status() == StatusCode::NOT_MODIFIED => {
+                entry.revalidated(&response_headers(response.headers()));
+                if let Err(err) = cache.put(&key, entry).await {
+                    tracing::warn!(%err, "can't update the proxy cache");
+                }
+                return Ok((
+                    respond(entry, if_none_match.as_ref()),
+                    CacheOutcome::Revalidated,
+                ));
+            }
+            (Ok(response), Some(entry)) if response.status().is_server_error() => {
+                return Ok((respond(entry, if_none_match.as_ref()), CacheOutcome::Stale));
+            }
+            (Err(_), Some(entry)) => {
+                return Ok((respond(entry, if_none_match.as_ref()), CacheOutcome::Stale));
+            }
+            (Ok(response), _) => response,
+            (Err(err), None) => return Err(bad_gateway(&err)),
+        };
+
+        let Some(vary) = Entry::storable(response.status(), response.headers(), &headers) else {
+            return Ok((pass_through(response), CacheOutcome::Miss));
+        };
+
+        // Kept only when it turns out small enough, otherwise what was read
+        // so far goes out ahead of the rest.
+        let mut body = BytesMut::new();
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    body.extend_from_slice(&chunk);
+                    if body.len() > MAX_ENTRY_SIZE {
+                        let head =
+                            stream::once(async move { Ok::<_, reqwest::Error>(body.freeze()) });
+                        let status = response.status();
+                        let headers = response_headers(response.headers());
+                        let body = Body::from_stream(head.chain(response.bytes_stream()));
+                        return Ok(((status, headers, body).into_response(), CacheOutcome::Miss));
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => match &stored {
+                    Some(entry) => {
+                        return Ok((respond(entry, if_none_match.as_ref()), CacheOutcome::Stale))
+                    }
+                    None => return Err(bad_gateway(&err)),
+                },
+            }
+        }
+
+        let entry = Entry::new(
+            response.status(),
+            response_headers(response.headers()),
+            vary,
+            body.freeze(),
+        );
+        if let Err(err) = cache.put(&key, &entry).await {
+            tracing::warn!(%err, "can't write to the proxy cache");
+        }
+        let max_bytes = config.cache_max_mb * 1024 * 1024;
+        tokio::spawn(async move {
+            if let Err(err) = cache.evict(max_bytes).await {
+                tracing::warn!(%err, "can't trim the proxy cache");
+            }
+        });
+        Ok((respond(&entry, if_none_match.as_ref()), CacheOutcome::Miss))
+    }
+}
+
+/// The upstream URL for a request target, under the upstream's path, so
+/// with `https://host/tango` the target `/x.js` is `https://host/tango/x.js`.
+/// Targets that resolve to another origin, like `//evil.example/x` or an
+/// absolute URI, or outside that path are refused.
+fn target(upstream: &Url, request_target: &str) -> Option<Url> {
+    let mut base = upstream.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    let relative = request_target
+        .strip_prefix('/')
+        .filter(|rest| !rest.starts_with('/'))
+        .unwrap_or(request_target);
+    let url = Url::options().base_url(Some(&base)).parse(relative).ok()?;
+    (url.origin() == base.origin() && url.path().starts_with(base.path())).then_some(url)
+}
+
+/// Removes the standard hop-by-hop headers and any the `Connection` header
+/// names.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+fn forwarded(
+    headers: &mut HeaderMap,
+    policy: ForwardedPolicy,
+    peer: SocketAddr,
+    host: Option<HeaderValue>,
+    tls: bool,
+) {
+    let proto = HeaderValue::from_static(if tls { "https" } else { "http" });
+    let client = peer.ip().to_string();
+    match policy {
+        ForwardedPolicy::Off | ForwardedPolicy::Set => {
+            for name in [X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PROTO] {
+                headers.remove(name);
+            }
+            headers.remove(header::FORWARDED);
+            if policy == ForwardedPolicy::Off {
+                return;
+            }
+            headers.insert(X_FORWARDED_FOR, HeaderValue::from_str(&client).unwrap());
+            headers.insert(X_FORWARDED_PROTO, proto);
+        }
+        ForwardedPolicy::Append => {
+            let chain = match headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+                Some(chain) => format!("{}, {}", chain, client),
+                None => client,
+            };
+            if let Ok(chain) = HeaderValue::from_str(&chain) {
+                headers.insert(X_FORWARDED_FOR, chain);
+            }
+            if !headers.contains_key(X_FORWARDED_PROTO) {
+                headers.insert(X_FORWARDED_PROTO, proto);
+            }
+        }
+    }
+    if let Some(host) = host {
+        headers.entry(X_FORWARDED_HOST).or_insert(host);
+    }
+}
+
+fn response_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    strip_hop_by_hop(&mut headers);
+    headers
+}
+
+fn pass_through(response: reqwest::Response) -> Response {
+    (
+        response.status(),
+        response_headers(response.headers()),
+        Body::new(reqwest::Body::from(response)),
+    )
+        .into_response()
+}
+
+/// Answers a conditional request with 304 when the client already has it.
+fn respond(entry: &Entry, if_none_match: Option<&HeaderValue>) -> Response {
+    let unchanged = if_none_match
+        .zip(entry.headers.get(header::ETAG))
+        .is_some_and(|(if_none_match, etag)| etag_matches(if_none_match, etag));
+    if unchanged {
+        return (StatusCode::NOT_MODIFIED, entry.headers.clone()).into_response();
+    }
+    (entry.status, entry.headers.clone(), entry.body.clone()).into_response()
+}
+
+fn bad_gateway(err: &reqwest::Error) -> Response {
+    tracing::warn!(%err, "proxy upstream unreachable");
+    (StatusCode::BAD_GATEWAY, "Bad Gateway").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_requests_on_the_upstream() {
+        let upstream = Url::parse("https://tangoapp.dev").unwrap();
+        let url = target(&upstream, "/assets/index.js?v=1").unwrap();
+        assert_eq!(url.as_str(), "https://tangoapp.dev/assets/index.js?v=1");
+        assert!(target(&upstream, "https://tangoapp.dev/").is_some());
+
+        for other in [
+            "//evil.example/x",
+            "http://tangoapp.dev/",
+            "https://tangoapp.dev:8443/",
+            "https://evil.example/",
+        ] {
+            assert!(target(&upstream, other).is_none(), "{}", other);
+        }
+    }
+
+    #[test]
+    fn keeps_the_upstream_path() {
+        for upstream in ["https://example.com/tango/", "https://example.com/tango"] {
+            let upstream = Url::parse(upstream).unwrap();
+            let url = target(&upstream, "/assets/index.js?v=1").unwrap();
+            assert_eq!(
+                url.as_str(),
+                "https://example.com/tango/assets/index.js?v=1"
+            );
+            let url = target(&upstream, "/").unwrap();
+            assert_eq!(url.as_str(), "https://example.com/tango/");
+            assert!(target(&upstream, "/../admin").is_none());
+        }
+    }
+
+    #[test]
+    fn strips_the_bridge_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer abc"),
+        );
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("adb, bearer.abc"),
+        );
+        pairing::strip_token(&mut headers);
+        assert!(!headers.contains_key(header::AUTHORIZATION));
+        assert_eq!(headers[header::SEC_WEBSOCKET_PROTOCOL], "adb");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("bearer.abc"),
+        );
+        pairing::strip_token(&mut headers);
+        assert!(headers.is_empty());
+    }
+}
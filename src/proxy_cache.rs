@@ -0,0 +1,422 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::paths;
+
+/// Larger responses are passed through without being kept.
+pub const MAX_ENTRY_SIZE: usize = 32 * 1024 * 1024;
+
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// A stored response. On disk it's one file per URL: the metadata as a line
+/// of JSON, then the body.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// The request headers named by `Vary`, as they were when stored.
+    pub vary: Vec<(String, Option<String>)>,
+    /// Unix time in milliseconds.
+    pub stored_at: u64,
+    pub body: Bytes,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    status: u16,
+    headers: Vec<(String, String)>,
+    vary: Vec<(String, Option<String>)>,
+    stored_at: u64,
+}
+
+impl Entry {
+    /// `None` when the response mustn't be shared with other requests.
+    /// Responses to requests with credentials are kept only when marked
+    /// shared, as RFC 9111 section 3.5 asks.
+    pub fn storable(
+        status: StatusCode,
+        headers: &HeaderMap,
+        request: &HeaderMap,
+    ) -> Option<Vec<(String, Option<String>)>> {
+        let control = CacheControl::parse(headers);
+        if status != StatusCode::OK
+            || control.no_store
+            || control.private
+            || headers.contains_key(header::SET_COOKIE)
+            || (has_credentials(request) && !control.shared)
+        {
+            return None;
+        }
+        let mut vary = Vec::new();
+        for name in list(headers, header::VARY) {
+            if name == "*" {
+                return None;
+            }
+            vary.push((name.clone(), value(request, &name)));
+        }
+        Some(vary)
+    }
+
+    pub fn new(
+        status: StatusCode,
+        mut headers: HeaderMap,
+        vary: Vec<(String, Option<String>)>,
+        body: Bytes,
+    ) -> Self {
+        headers.remove(header::CONTENT_LENGTH);
+        Self {
+            status,
+            headers,
+            vary,
+            stored_at: now_ms(),
+            body,
+        }
+    }
+
+    /// Whether the entry was stored for a request like this one.
+    pub fn matches(&self, request: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, stored)| value(request, name) == *stored)
+    }
+
+    /// Still within its `max-age`, so usable without asking the upstream.
+    pub fn is_fresh(&self) -> bool {
+        let control = CacheControl::parse(&self.headers);
+        let age = value(&self.headers, header::AGE.as_str())
+            .and_then(|age| age.parse::<u64>().ok())
+            .unwrap_or_default()
+            + now_ms().saturating_sub(self.stored_at) / 1000;
+        !control.no_cache && control.max_age.is_some_and(|max_age| age < max_age)
+    }
+
+    /// Headers for a conditional request to the upstream.
+    pub fn validators(&self) -> impl Iterator<Item = (HeaderName, HeaderValue)> + '_ {
+        [
+            (header::ETAG, header::IF_NONE_MATCH),
+            (header::LAST_MODIFIED, header::IF_MODIFIED_SINCE),
+        ]
+        .into_iter()
+        .filter_map(|(name, condition)| Some((condition, self.headers.get(name)?.clone())))
+    }
+
+    /// Takes the headers of a 304, which describe the entry as it is now.
+    pub fn revalidated(&mut self, headers: &HeaderMap) {
+        for (name, value) in headers {
+            if name != header::CONTENT_LENGTH {
+                self.headers.insert(name, value.clone());
+            }
+        }
+        self.stored_at = now_ms();
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let meta = Meta {
+            status: self.status.as_u16(),
+            headers: self
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            vary: self.vary.clone(),
+            stored_at: self.stored_at,
+        };
+        let mut data = serde_json::to_vec(&meta).expect("metadata serializes");
+        data.push(b'\n');
+        data.extend_from_slice(&self.body);
+        data
+    }
+
+    fn decode(data: Vec<u8>) -> Option<Self> {
+        let split = data.iter().position(|byte| *byte == b'\n')?;
+        let meta: Meta = serde_json::from_slice(&data[..split]).ok()?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in meta.headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(&value).ok()?,
+            );
+        }
+        Some(Self {
+            status: StatusCode::from_u16(meta.status).ok()?,
+            headers,
+            vary: meta.vary,
+            stored_at: meta.stored_at,
+            body: Bytes::from(data).slice(split + 1..),
+        })
+    }
+}
+
+/// Upstream responses kept on disk, keyed by URL.
+#[derive(Debug, Clone)]
+pub struct ProxyCache {
+    dir: PathBuf,
+}
+
+impl ProxyCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let key = Sha256::digest(url.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        self.dir.join(key)
+    }
+
+    pub async fn get(&self, url: &str) -> Option<Entry> {
+        let data = tokio::fs::read(self.path(url)).await.ok()?;
+        Entry::decode(data)
+    }
+
+    /// Written to a temporary file first, so readers never see half of it.
+    pub async fn put(&self, url: &str, entry: &Entry) -> io::Result<()> {
+        let dir = paths::ensure_dir(self.dir.clone())?;
+        let temp = dir.join(format!("{}.tmp", NEXT_TEMP.fetch_add(1, Ordering::Relaxed)));
+        tokio::fs::write(&temp, entry.encode()).await?;
+        tokio::fs::rename(&temp, self.path(url)).await
+    }
+
+    /// Deletes the least recently stored entries until the rest fit in
+    /// `max_bytes`.
+    pub async fn evict(&self, max_bytes: u64) -> io::Result<()> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            if entry.path().extension().is_some() {
+                continue;
+            }
+            let meta = entry.metadata().await?;
+            total += meta.len();
+            entries.push((meta.modified()?, meta.len(), entry.path()));
+        }
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= max_bytes {
+                break;
+            }
+            tokio::fs::remove_file(&path).await?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+/// The parts of `Cache-Control` a shared cache acts on.
+#[derive(Debug, Default)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    /// `public` or `s-maxage`, either lets a response to a request with
+    /// credentials be stored.
+    pub shared: bool,
+    /// `s-maxage` when given, otherwise `max-age`, in seconds.
+    pub max_age: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut control = Self::default();
+        let mut shared_max_age = None;
+        for directive in list(headers, header::CACHE_CONTROL) {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.as_str(), None),
+            };
+            let seconds = || argument.and_then(|argument| argument.parse().ok());
+            match name {
+                "no-store" => control.no_store = true,
+                "no-cache" => control.no_cache = true,
+                "private" => control.private = true,
+                "public" => control.shared = true,
+                "max-age" => control.max_age = seconds(),
+                "s-maxage" => shared_max_age = seconds(),
+                _ => {}
+            }
+        }
+        if shared_max_age.is_some() {
+            control.shared = true;
+            control.max_age = shared_max_age;
+        }
+        control
+    }
+
+    /// Whether a request asks for a response checked with the upstream,
+    /// as browsers do on reload.
+    pub fn wants_revalidation(request: &HeaderMap) -> bool {
+        let control = Self::parse(request);
+        control.no_cache
+            || control.max_age == Some(0)
+            || list(request, header::PRAGMA).any(|pragma| pragma == "no-cache")
+    }
+}
+
+fn has_credentials(request: &HeaderMap) -> bool {
+    request.contains_key(header::AUTHORIZATION) || request.contains_key(header::COOKIE)
+}
+
+/// Whether `If-None-Match` names the `ETag`, compared weakly.
+pub fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (Ok(if_none_match), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// The items of comma separated headers, trimmed and lowercased.
+fn list(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|item| item.trim().to_ascii_lowercase())
+        .filter(|item| !item.is_empty())
+}
+
+fn value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stores_responses_to_credentialed_requests_only_when_shared() {
+        let request = headers(&[("cookie", "session=1")]);
+        let private = headers(&[("cache-control", "max-age=60")]);
+        assert!(Entry::storable(StatusCode::OK, &private, &request).is_none());
+        let request = headers(&[("authorization", "Basic YTpi")]);
+        assert!(Entry::storable(StatusCode::OK, &private, &request).is_none());
+
+        for shared in ["public, max-age=60", "s-maxage=60"] {
+            let response = headers(&[("cache-control", shared)]);
+            assert!(Entry::storable(StatusCode::OK, &response, &request).is_some());
+        }
+        assert!(Entry::storable(StatusCode::OK, &private, &HeaderMap::new()).is_some());
+    }
+
+    #[test]
+    fn parses_cache_control() {
+        let control = CacheControl::parse(&headers(&[(
+            "cache-control",
+            "No-Cache, max-age=\"60\", private",
+        )]));
+        assert!(control.no_cache && control.private && !control.no_store);
+        assert_eq!(control.max_age, Some(60));
+
+        let control = CacheControl::parse(&headers(&[(
+            "cache-control",
+            "s-maxage=600, max-age=60, no-store",
+        )]));
+        assert!(control.no_store && control.shared);
+        assert_eq!(control.max_age, Some(600));
+
+        let control = CacheControl::parse(&headers(&[("cache-control", "max-age=soon")]));
+        assert_eq!(control.max_age, None);
+    }
+
+    fn entry(response: HeaderMap, age_secs: u64) -> Entry {
+        let mut entry = Entry::new(StatusCode::OK, response, Vec::new(), Bytes::new());
+        entry.stored_at -= age_secs * 1000;
+        entry
+    }
+
+    #[test]
+    fn is_fresh_within_max_age() {
+        let response = headers(&[("cache-control", "max-age=60")]);
+        assert!(entry(response.clone(), 10).is_fresh());
+        assert!(!entry(response, 60).is_fresh());
+
+        let aged = headers(&[("cache-control", "max-age=60"), ("age", "55")]);
+        assert!(!entry(aged, 10).is_fresh());
+        let no_cache = headers(&[("cache-control", "no-cache, max-age=60")]);
+        assert!(!entry(no_cache, 0).is_fresh());
+        assert!(!entry(HeaderMap::new(), 0).is_fresh());
+    }
+
+    #[test]
+    fn matches_the_request_headers_named_by_vary() {
+        let response = headers(&[("vary", "Accept-Encoding, Accept-Language")]);
+        let request = headers(&[("accept-encoding", "br")]);
+        let vary = Entry::storable(StatusCode::OK, &response, &request).unwrap();
+        let stored = Entry::new(StatusCode::OK, response, vary, Bytes::new());
+
+        assert!(stored.matches(&request));
+        assert!(!stored.matches(&headers(&[("accept-encoding", "gzip")])));
+        assert!(!stored.matches(&headers(&[
+            ("accept-encoding", "br"),
+            ("accept-language", "de"),
+        ])));
+
+        let any = headers(&[("vary", "*")]);
+        assert!(Entry::storable(StatusCode::OK, &any, &request).is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_entries() {
+        let dir = std::env::temp_dir().join(format!("tango-proxy-cache-{}", std::process::id()));
+        let cache = ProxyCache::new(dir.clone());
+        let body = Bytes::from(vec![0u8; 1000]);
+        let now = SystemTime::now();
+        for (index, url) in ["/old", "/middle", "/new"].into_iter().enumerate() {
+            let entry = Entry::new(StatusCode::OK, HeaderMap::new(), Vec::new(), body.clone());
+            cache.put(url, &entry).await.unwrap();
+            let age = std::time::Duration::from_secs(60 * (3 - index as u64));
+            std::fs::File::options()
+                .write(true)
+                .open(cache.path(url))
+                .unwrap()
+                .set_modified(now - age)
+                .unwrap();
+        }
+
+        cache.evict(2500).await.unwrap();
+        assert!(cache.get("/old").await.is_none());
+        assert!(cache.get("/middle").await.is_some());
+        assert!(cache.get("/new").await.is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,15 +1,24 @@
 use std::{collections::HashMap, sync::Arc};
 
 use serde::Serialize;
-use serde_json::json;
 use tokio::sync::{broadcast, RwLock};
 
-use crate::ios_provider::IosDevice;
+use crate::{adb_inspector::HostRequest, ios_provider::IosDevice, ios_scripts::UploadProgress};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum DeviceEvent {
     IosOnline(IosDevice),
-    IosOffline { id: String },
+    IosOffline {
+        id: String,
+    },
+    ScriptUpload(UploadProgress),
+    AdbRequest {
+        session: u64,
+        #[serde(flatten)]
+        request: HostRequest,
+        allowed: bool,
+    },
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -42,6 +51,10 @@ impl DeviceRegistry {
         self.events.subscribe()
     }
 
+    pub fn publish(&self, event: DeviceEvent) {
+        let _ = self.events.send(event);
+    }
+
     pub async fn update_ios_devices(&self, devices: Vec<IosDevice>) {
         let mut ios_devices = self.ios_devices.write().await;
         let mut incoming = HashMap::new();
@@ -81,6 +94,11 @@ impl DeviceRegistry {
         ios_devices.get(id).cloned()
     }
 
+    pub async fn list_ios_devices(&self) -> Vec<IosDevice> {
+        let ios_devices = self.ios_devices.read().await;
+        ios_devices.values().cloned().collect()
+    }
+
     pub async fn list_unified_devices(&self) -> Vec<UnifiedDevice> {
         let ios_devices = self.ios_devices.read().await;
         let android_devices = self.android_devices.read().await;
@@ -92,12 +110,7 @@ impl DeviceRegistry {
                 platform: "ios".to_string(),
                 status: "online".to_string(),
                 display_name: device.display_name.clone(),
-                meta: json!({
-                    "ip": device.ip,
-                    "device": device.status.device,
-                    "zxtouch": device.status.zxtouch,
-                    "script": device.status.script,
-                }),
+                meta: device.meta(),
                 capabilities: vec!["stream_mpegts_ws".to_string(), "zxtouch".to_string()],
             });
         }
@@ -0,0 +1,121 @@
+use std::{future::Future, io, time::Duration};
+
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use futures_util::{Sink, SinkExt};
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::{
+    sync::CancellationToken,
+    task::{task_tracker::TrackedFuture, TaskTracker},
+};
+
+/// Sent to WebSocket clients in the close frame.
+pub const CLOSE_REASON: &str = "bridge shutting down";
+
+/// How long sessions get to wind down before the bridge exits anyway.
+const DEADLINE: Duration = Duration::from_secs(5);
+
+/// One token watched by every relay, provider and supervisor, and the tasks
+/// to wait for once it is cancelled.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// For a session that can also end on its own.
+    pub fn child_token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn wait(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.spawn(task)
+    }
+
+    /// For futures spawned elsewhere, like WebSocket handlers.
+    pub fn track<F: Future>(&self, task: F) -> TrackedFuture<F> {
+        self.tasks.track_future(task)
+    }
+
+    /// Closes a WebSocket, telling the client why when the bridge is the one
+    /// going away.
+    pub async fn close<S>(&self, writer: &mut S)
+    where
+        S: Sink<Message> + Unpin,
+    {
+        if self.is_shutting_down() {
+            let frame = CloseFrame {
+                code: close_code::AWAY,
+                reason: CLOSE_REASON.into(),
+            };
+            let _ = writer.send(Message::Close(Some(frame))).await;
+        }
+        let _ = writer.close().await;
+    }
+
+    /// Cancels everything, then waits for the server and the tracked tasks
+    /// for up to `DEADLINE`.
+    /// `false` when the server failed.
+    pub async fn stop(self, server: JoinHandle<io::Result<()>>) -> bool {
+        tracing::info!(tasks = self.tasks.len(), "shutting down");
+        self.token.cancel();
+        self.tasks.close();
+
+        let drained = tokio::time::timeout(DEADLINE, async {
+            let result = server.await;
+            self.tasks.wait().await;
+            result
+        })
+        .await;
+
+        match drained {
+            Ok(result) => report(result),
+            Err(_) => {
+                tracing::warn!(
+                    tasks = self.tasks.len(),
+                    "still running after {:?}, exiting anyway",
+                    DEADLINE
+                );
+                true
+            }
+        }
+    }
+}
+
+/// Logs how the server ended, `false` when it failed.
+pub fn report(result: Result<io::Result<()>, JoinError>) -> bool {
+    match result {
+        Ok(Ok(())) => {
+            tracing::info!("server exited");
+            true
+        }
+        Ok(Err(err)) => {
+            tracing::error!(%err, "server failed");
+            false
+        }
+        Err(err) => {
+            tracing::error!(%err, "server task failed");
+            false
+        }
+    }
+}
@@ -0,0 +1,554 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use rcgen::{
+    BasicConstraints, CertificateParams, CidrSubnet, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, GeneralSubtree, IsCa, KeyPair, KeyUsagePurpose, NameConstraints,
+};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::CryptoProvider,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::{config::TlsConfig, ios_lan_scanner::local_ipv4, listen, paths, shutdown::Shutdown};
+
+pub const DEFAULT_TLS_PORT: u16 = 15038;
+
+const CA_NAME: &str = "Tango Bridge Local CA";
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+
+/// What the CA may issue for besides `localhost` and private networks.
+const LOOPBACK_NETWORKS: [&str; 2] = ["127.0.0.0/8", "::1/128"];
+
+const CA_VALIDITY: time::Duration = time::Duration::days(3650);
+
+/// Reissued `RENEW_BEFORE` ahead of its expiry, so it can be short lived.
+const LEAF_VALIDITY: time::Duration = time::Duration::days(90);
+
+const RENEW_BEFORE: time::Duration = time::Duration::days(30);
+
+/// How often the certificate is checked for renewal, and a user certificate
+/// re-read in case it was replaced on disk.
+const RENEW_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertSource {
+    User,
+    LocalCa,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertStatus {
+    pub source: CertSource,
+    pub port: u16,
+    /// Unix time in milliseconds.
+    pub not_after: i64,
+    pub expires_in_days: i64,
+    /// Expiry of the local CA, if one is used.
+    pub ca_not_after: Option<i64>,
+}
+
+#[derive(Debug)]
+struct Cert {
+    key: Arc<CertifiedKey>,
+    source: CertSource,
+    not_after: i64,
+    ca_not_after: Option<i64>,
+}
+
+/// Hands every handshake the current certificate, so a renewed one is used
+/// without restarting the listeners.
+#[derive(Debug)]
+struct CertResolver(RwLock<Cert>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().key.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsIdentity {
+    pub config: Arc<ServerConfig>,
+    settings: TlsConfig,
+    addrs: Vec<SocketAddr>,
+    resolver: Arc<CertResolver>,
+}
+
+impl TlsIdentity {
+    /// Loads the user certificate, or issues one from the local CA for
+    /// localhost and the LAN addresses in `addrs`.
+    pub fn load(settings: &TlsConfig, addrs: &[SocketAddr]) -> Result<Self, String> {
+        let resolver = Arc::new(CertResolver(RwLock::new(load_cert(settings, addrs)?)));
+        let mut config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        // WebSockets are upgraded over HTTP/1.1.
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let identity = Self {
+            config: Arc::new(config),
+            settings: settings.clone(),
+            addrs: addrs.to_vec(),
+            resolver,
+        };
+        identity.warn_if_expiring();
+        Ok(identity)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.settings.port
+    }
+
+    pub fn status(&self) -> CertStatus {
+        let cert = self.resolver.0.read().unwrap();
+        let now = OffsetDateTime::now_utc().unix_timestamp() * 1000;
+        CertStatus {
+            source: cert.source,
+            port: self.settings.port,
+            not_after: cert.not_after,
+            expires_in_days: (cert.not_after - now) / DAY_MS,
+            ca_not_after: cert.ca_not_after,
+        }
+    }
+
+    /// Reissues the local certificate before it expires, and picks up a user
+    /// certificate replaced on disk.
+    pub fn renew(self, shutdown: &Shutdown) -> JoinHandle<()> {
+        let token = shutdown.token();
+        shutdown.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = tokio::time::sleep(RENEW_INTERVAL) => {}
+                }
+                if let Err(err) = self.refresh() {
+                    tracing::error!(err, "can't renew the TLS certificate");
+                }
+                self.warn_if_expiring();
+            }
+        })
+    }
+
+    fn refresh(&self) -> Result<(), String> {
+        let status = self.status();
+        let now = OffsetDateTime::now_utc().unix_timestamp() * 1000;
+        let due = match status.source {
+            CertSource::LocalCa => {
+                status.not_after - now <= RENEW_BEFORE.whole_milliseconds() as i64
+            }
+            CertSource::User => true,
+        };
+        if !due {
+            return Ok(());
+        }
+        let cert = load_cert(&self.settings, &self.addrs)?;
+        if cert.not_after != status.not_after {
+            tracing::info!(not_after = cert.not_after, "TLS certificate renewed");
+            *self.resolver.0.write().unwrap() = cert;
+        }
+        Ok(())
+    }
+
+    fn warn_if_expiring(&self) {
+        let days = self.status().expires_in_days;
+        if days < EXPIRY_WARNING_DAYS {
+            tracing::warn!(days, "TLS certificate expires soon");
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_cert(settings: &TlsConfig, addrs: &[SocketAddr]) -> Result<Cert, String> {
+    let (chain, key, source, ca_not_after) = match (&settings.cert, &settings.key) {
+        (Some(cert), Some(key)) => {
+            let (chain, key) = read_user_cert(cert, key)?;
+            (chain, key, CertSource::User, None)
+        }
+        (None, None) => {
+            let (chain, key) = issue_local_cert(addrs)?;
+            let ca_not_after = chain.get(1).map(|ca| not_after(ca)).transpose()?;
+            (chain, key, CertSource::LocalCa, ca_not_after)
+        }
+        _ => return Err("tls.cert and tls.key must be set together".to_string()),
+    };
+    let not_after = not_after(&chain[0])?;
+    let key = CertifiedKey::from_der(chain, key, &provider()).map_err(|err| err.to_string())?;
+    Ok(Cert {
+        key: Arc::new(key),
+        source,
+        not_after,
+        ca_not_after,
+    })
+}
+
+fn read_user_cert(
+    cert: &Path,
+    key: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("{}: {}", cert.display(), err))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificate found", cert.display()));
+    }
+    let key =
+        PrivateKeyDer::from_pem_file(key).map_err(|err| format!("{}: {}", key.display(), err))?;
+    Ok((chain, key))
+}
+
+fn ca_dir() -> PathBuf {
+    paths::data_dir().join("tls")
+}
+
+/// PEM of the local CA, for users to trust it manually.
+pub fn ca_pem() -> io::Result<String> {
+    std::fs::read_to_string(ca_dir().join(CA_CERT_FILE))
+}
+
+fn ca_params() -> Result<CertificateParams, rcgen::Error> {
+    let mut params = CertificateParams::new(Vec::new())?;
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, CA_NAME);
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    // Once trusted, its key must not be able to vouch for other sites.
+    let mut permitted = vec![GeneralSubtree::DnsName("localhost".to_string())];
+    permitted.extend(
+        LOOPBACK_NETWORKS
+            .iter()
+            .filter_map(|net| net.parse::<IpNet>().ok())
+            .chain(listen::private_networks().iter().copied())
+            .map(|net| {
+                GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(
+                    net.addr(),
+                    net.prefix_len(),
+                ))
+            }),
+    );
+    params.name_constraints = Some(NameConstraints {
+        permitted_subtrees: permitted,
+        excluded_subtrees: Vec::new(),
+    });
+    Ok(params)
+}
+
+/// Whether an address is one the CA may issue for.
+fn is_permitted(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    ip.is_loopback()
+        || listen::private_networks()
+            .iter()
+            .any(|net| net.contains(&ip))
+}
+
+/// CAs made before they had name constraints are replaced.
+fn has_name_constraints(cert_pem: &str) -> bool {
+    let Ok(der) = CertificateDer::from_pem_slice(cert_pem.as_bytes()) else {
+        return false;
+    };
+    x509_parser::parse_x509_certificate(&der)
+        .is_ok_and(|(_, cert)| matches!(cert.name_constraints(), Ok(Some(_))))
+}
+
+/// Creates the CA on first use and keeps it in the data directory, so it
+/// only has to be trusted once.
+fn load_or_create_ca() -> Result<(rcgen::Certificate, KeyPair, String), String> {
+    let dir = paths::ensure_dir(ca_dir()).map_err(|err| err.to_string())?;
+    let cert_path = dir.join(CA_CERT_FILE);
+    let key_path = dir.join(CA_KEY_FILE);
+
+    if let (Ok(cert_pem), Ok(key_pem)) = (
+        std::fs::read_to_string(&cert_path),
+        std::fs::read_to_string(&key_path),
+    ) {
+        if !has_name_constraints(&cert_pem) {
+            tracing::warn!(path = %cert_path.display(), "replacing the local CA with one limited to local addresses, trust it again");
+            return create_ca(&cert_path, &key_path);
+        }
+        let key = KeyPair::from_pem(&key_pem).map_err(|err| err.to_string())?;
+        // Same name and key as the stored CA, so leaves it signs chain to it.
+        let cert = ca_params()
+            .and_then(|params| params.self_signed(&key))
+            .map_err(|err| err.to_string())?;
+        return Ok((cert, key, cert_pem));
+    }
+
+    create_ca(&cert_path, &key_path)
+}
+
+fn create_ca(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(rcgen::Certificate, KeyPair, String), String> {
+    let key = KeyPair::generate().map_err(|err| err.to_string())?;
+    let mut params = ca_params().map_err(|err| err.to_string())?;
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = params.not_before + CA_VALIDITY;
+    let cert = params.self_signed(&key).map_err(|err| err.to_string())?;
+    let cert_pem = cert.pem();
+
+    paths::write_private(key_path, key.serialize_pem().as_bytes())
+        .map_err(|err| err.to_string())?;
+    std::fs::write(cert_path, &cert_pem).map_err(|err| err.to_string())?;
+    tracing::info!(path = %cert_path.display(), "created local CA");
+
+    Ok((cert, key, cert_pem))
+}
+
+fn issue_local_cert(
+    addrs: &[SocketAddr],
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+    let (ca, ca_key, ca_pem) = load_or_create_ca()?;
+
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    for addr in addrs {
+        let ip = addr.ip();
+        let ip = if ip.is_unspecified() {
+            match local_ipv4() {
+                Ok(ip) => IpAddr::V4(ip),
+                Err(_) => continue,
+            }
+        } else {
+            ip
+        };
+        if !is_permitted(ip) {
+            tracing::warn!(%ip, "the local CA only issues for loopback and private addresses");
+            continue;
+        }
+        if !names.contains(&ip.to_string()) {
+            names.push(ip.to_string());
+        }
+    }
+
+    let key = KeyPair::generate().map_err(|err| err.to_string())?;
+    let mut params = CertificateParams::new(names).map_err(|err| err.to_string())?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Tango Bridge");
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = params.not_before + LEAF_VALIDITY;
+    let cert = params
+        .signed_by(&key, &ca, &ca_key)
+        .map_err(|err| err.to_string())?;
+
+    // Send the stored CA, not the re-signed copy, so clients see what they trusted.
+    let ca_der =
+        CertificateDer::from_pem_slice(ca_pem.as_bytes()).map_err(|err| err.to_string())?;
+    Ok((
+        vec![cert.der().clone(), ca_der],
+        PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+    ))
+}
+
+/// Expiry in Unix milliseconds.
+fn not_after(cert: &CertificateDer) -> Result<i64, String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).map_err(|err| err.to_string())?;
+    Ok(cert.validity().not_after.timestamp() * 1000)
+}
+
+/// Accepts TCP connections and completes TLS handshakes in the background,
+/// so a slow client doesn't hold up the others. Both stop on shutdown.
+pub struct TlsListener {
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(
+        listener: TcpListener,
+        config: Arc<ServerConfig>,
+        shutdown: &Shutdown,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (sender, accepted) = mpsc::channel(64);
+
+        let tasks = shutdown.clone();
+        shutdown.spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    _ = tasks.wait() => break,
+                    accepted = listener.accept() => accepted,
+                };
+                let (stream, addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::debug!(%err, "TLS accept failed");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                if sender.is_closed() {
+                    break;
+                }
+
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                let shutdown = tasks.clone();
+                tasks.spawn(async move {
+                    let handshake =
+                        tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+                    let result = tokio::select! {
+                        _ = shutdown.wait() => return,
+                        result = handshake => result,
+                    };
+                    match result {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, addr)).await;
+                        }
+                        Ok(Err(err)) => tracing::debug!(%addr, %err, "TLS handshake failed"),
+                        Err(_) => tracing::debug!(%addr, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            accepted,
+            local_addr,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            // The accept task only stops once this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::{
+        client::{danger::ServerCertVerifier, WebPkiServerVerifier},
+        pki_types::{ServerName, UnixTime},
+        RootCertStore,
+    };
+
+    use super::*;
+
+    /// Signs a leaf for `names` and checks it against the CA as a client
+    /// trusting it would.
+    fn verify(names: &[&str], server_name: &str) -> Result<(), rustls::Error> {
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params().unwrap().self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let leaf = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider())
+            .build()
+            .unwrap();
+        verifier
+            .verify_server_cert(
+                leaf.der(),
+                &[],
+                &ServerName::try_from(server_name.to_string()).unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn ca_only_vouches_for_local_names() {
+        verify(&["localhost"], "localhost").unwrap();
+        verify(&["127.0.0.1"], "127.0.0.1").unwrap();
+        verify(&["::1"], "::1").unwrap();
+        verify(&["192.168.1.5"], "192.168.1.5").unwrap();
+        verify(&["10.1.2.3"], "10.1.2.3").unwrap();
+        assert!(verify(&["example.com"], "example.com").is_err());
+        assert!(verify(&["localhost", "example.com"], "localhost").is_err());
+        assert!(verify(&["8.8.8.8"], "8.8.8.8").is_err());
+    }
+
+    #[test]
+    fn replaces_cas_without_name_constraints() {
+        let key = KeyPair::generate().unwrap();
+        assert!(has_name_constraints(
+            &ca_params().unwrap().self_signed(&key).unwrap().pem()
+        ));
+
+        let mut params = ca_params().unwrap();
+        params.name_constraints = None;
+        assert!(!has_name_constraints(
+            &params.self_signed(&key).unwrap().pem()
+        ));
+    }
+
+    #[test]
+    fn permits_loopback_and_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "::1",
+            "::ffff:192.168.1.5",
+            "172.16.0.1",
+            "fd00::1",
+        ] {
+            assert!(is_permitted(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "2001:db8::1"] {
+            assert!(!is_permitted(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
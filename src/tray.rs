@@ -0,0 +1,239 @@
+use std::{
+    env,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use auto_launch::AutoLaunchBuilder;
+use tao::event_loop::EventLoopBuilder;
+use tokio::task::JoinHandle;
+use tray_icon::{
+    menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu},
+    TrayIconBuilder, TrayIconEvent,
+};
+
+use crate::{pairing::Pairings, shutdown::Shutdown, start_browser};
+
+const ARG_AUTO_RUN: &str = "--auto-run";
+
+pub struct Tray {
+    pub shutdown: Shutdown,
+    pub server: JoinHandle<std::io::Result<()>>,
+    pub pairings: Pairings,
+    pub logs_dir: PathBuf,
+    /// Opened by "Open" and clicks on the icon.
+    pub web_url: String,
+    pub tooltip: String,
+}
+
+/// Runs the tray icon and its menu on the main thread until Quit is clicked.
+pub fn run(tray: Tray) -> ! {
+    let Tray {
+        shutdown,
+        server,
+        pairings,
+        logs_dir,
+        web_url,
+        tooltip,
+    } = tray;
+    let mut server = Some(server);
+    // Quit waits for sessions off the main thread so the menu stays usable.
+    let mut stopping: Option<JoinHandle<bool>> = None;
+
+    // Temporarily disable auto-opening the website on startup.
+    // if env::args().all(|arg| arg != ARG_AUTO_RUN) {
+    //     start_browser(&web_url)
+    // }
+
+    let menu_open = MenuItem::new("Open", true, None);
+
+    let auto_launch = AutoLaunchBuilder::new()
+        .set_app_name("Tango")
+        .set_app_path(env::current_exe().unwrap().to_str().unwrap())
+        .set_args(&[ARG_AUTO_RUN])
+        .set_use_launch_agent(true)
+        .build()
+        .unwrap();
+    let menu_auto_run = CheckMenuItem::new(
+        "Run at startup",
+        true,
+        auto_launch.is_enabled().unwrap(),
+        None,
+    );
+
+    let menu_pairing = Submenu::new("Paired origins", true);
+    let mut pairing_items = Vec::new();
+    let mut pairing_version = None;
+
+    let menu_logs = MenuItem::new("Open logs folder", true, None);
+    let menu_quit = MenuItem::new("Quit", true, None);
+
+    let tray_menu = Menu::new();
+    tray_menu
+        .append_items(&[
+            &menu_open,
+            &menu_auto_run,
+            &menu_pairing,
+            &menu_logs,
+            &PredefinedMenuItem::separator(),
+            &menu_quit,
+        ])
+        .unwrap();
+
+    let menu_receiver = MenuEvent::receiver();
+    let tray_receiver = TrayIconEvent::receiver();
+
+    let mut tray_icon = None;
+
+    #[allow(unused_mut)]
+    let mut event_loop = EventLoopBuilder::new().build();
+
+    #[cfg(target_os = "macos")]
+    {
+        use tao::platform::macos::EventLoopExtMacOS;
+
+        // https://github.com/glfw/glfw/issues/1552
+        event_loop.set_activation_policy(tao::platform::macos::ActivationPolicy::Accessory);
+    }
+
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow =
+            tao::event_loop::ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(16));
+
+        if let tao::event::Event::Reopen { .. } = event {
+            start_browser(&web_url);
+            return;
+        }
+
+        if let tao::event::Event::NewEvents(tao::event::StartCause::Init) = event {
+            let image = image::load_from_memory_with_format(
+                include_bytes!("../tango.png"),
+                image::ImageFormat::Png,
+            )
+            .unwrap()
+            .into_rgba8();
+            let (width, height) = image.dimensions();
+            let rgba = image.into_raw();
+            let icon = tray_icon::Icon::from_rgba(rgba, width, height).unwrap();
+
+            tray_icon = Some(
+                TrayIconBuilder::new()
+                    .with_tooltip(&tooltip)
+                    .with_icon(icon)
+                    .with_menu(Box::new(tray_menu.clone()))
+                    .build()
+                    .unwrap(),
+            );
+
+            #[cfg(target_os = "macos")]
+            unsafe {
+                use core_foundation::runloop::{CFRunLoopGetMain, CFRunLoopWakeUp};
+
+                let rl = CFRunLoopGetMain();
+                CFRunLoopWakeUp(rl);
+            }
+        }
+
+        if stopping.as_ref().is_some_and(JoinHandle::is_finished) {
+            *control_flow = tao::event_loop::ControlFlow::Exit;
+            return;
+        }
+
+        if pairing_version != Some(pairings.version()) {
+            pairing_version = Some(pairings.version());
+            refresh_pairing_menu(&menu_pairing, &mut pairing_items, &pairings);
+        }
+
+        if let Ok(event) = menu_receiver.try_recv() {
+            if let Some((_, action)) = pairing_items.iter().find(|(item, _)| event.id == item.id())
+            {
+                match action {
+                    Some(PairingAction::Approve(id)) => pairings.approve(id),
+                    Some(PairingAction::Revoke(origin)) => pairings.revoke(origin),
+                    None => {}
+                }
+                return;
+            }
+
+            if event.id == menu_open.id() {
+                start_browser(&web_url);
+                return;
+            }
+
+            if event.id == menu_logs.id() {
+                if let Err(err) = open::that_detached(&logs_dir) {
+                    tracing::warn!(%err, "can't open the logs folder");
+                }
+                return;
+            }
+
+            if event.id == menu_auto_run.id() {
+                if auto_launch.is_enabled().unwrap() {
+                    auto_launch.disable().unwrap();
+                } else {
+                    auto_launch.enable().unwrap();
+                }
+                menu_auto_run.set_checked(auto_launch.is_enabled().unwrap());
+                return;
+            }
+
+            if event.id == menu_quit.id() {
+                tray_icon.take();
+
+                if let Some(server) = server.take() {
+                    stopping = Some(tokio::spawn(shutdown.clone().stop(server)));
+                }
+                return;
+            }
+        }
+
+        if let Ok(TrayIconEvent::Click {
+            button: tray_icon::MouseButton::Left,
+            button_state: tray_icon::MouseButtonState::Down,
+            ..
+        }) = tray_receiver.try_recv()
+        {
+            start_browser(&web_url);
+            return;
+        }
+    })
+}
+
+enum PairingAction {
+    Approve(String),
+    Revoke(String),
+}
+
+/// Lists pairings waiting for approval and paired origins, clicking one
+/// approves or revokes it.
+fn refresh_pairing_menu(
+    menu: &Submenu,
+    items: &mut Vec<(MenuItem, Option<PairingAction>)>,
+    pairings: &Pairings,
+) {
+    for (item, _) in items.drain(..) {
+        let _ = menu.remove(&item);
+    }
+
+    for pending in pairings.pending() {
+        let text = format!("Approve {} (code {})", pending.origin, pending.code);
+        items.push((
+            MenuItem::new(text, true, None),
+            Some(PairingAction::Approve(pending.id)),
+        ));
+    }
+    for origin in pairings.paired_origins() {
+        let text = format!("Revoke {}", origin);
+        items.push((
+            MenuItem::new(text, true, None),
+            Some(PairingAction::Revoke(origin)),
+        ));
+    }
+    if items.is_empty() {
+        items.push((MenuItem::new("No paired origins", false, None), None));
+    }
+
+    for (item, _) in items.iter() {
+        let _ = menu.append(item);
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Router,
+};
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use sha2::{Digest, Sha256};
+use tower_http::compression::CompressionLayer;
+
+use crate::{config::WebConfig, proxy_cache::etag_matches};
+
+const INDEX: &str = "index.html";
+
+/// Where the bundler puts its output, every file named after its content.
+const ASSETS: &str = "assets/";
+
+/// The zip given by `TANGO_WEB_BUNDLE` when built with `embedded-web`.
+#[cfg(feature = "embedded-web")]
+const EMBEDDED: Option<&[u8]> = Some(include_bytes!(env!("TANGO_WEB_BUNDLE")));
+#[cfg(not(feature = "embedded-web"))]
+const EMBEDDED: Option<&[u8]> = None;
+
+pub fn has_embedded() -> bool {
+    EMBEDDED.is_some()
+}
+
+#[derive(Clone)]
+struct Asset {
+    body: Bytes,
+    /// Weak, the same file is sent gzip, brotli or uncompressed.
+    etag: String,
+}
+
+enum Source {
+    /// Read on every request, so a rebuilt UI shows up without a restart.
+    Dir(PathBuf),
+    /// Unpacked into memory once.
+    Archive(HashMap<String, Asset>),
+}
+
+/// A built copy of the web UI, served in place of the proxy for machines
+/// without internet.
+#[derive(Clone)]
+pub struct WebBundle {
+    source: Arc<Source>,
+}
+
+impl WebBundle {
+    /// `None` unless `web.offline` is set. `web.bundle` is a directory or a
+    /// zip, the embedded copy is used when it's unset.
+    pub fn load(config: &WebConfig) -> Result<Option<Self>, String> {
+        if !config.offline {
+            return Ok(None);
+        }
+        let source = match &config.bundle {
+            Some(path) if path.is_dir() => Source::Dir(path.clone()),
+            Some(path) => {
+                let data =
+                    std::fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+                Source::Archive(unzip(&data).map_err(|err| format!("{}: {}", path.display(), err))?)
+            }
+            None => match EMBEDDED {
+                Some(data) => {
+                    Source::Archive(unzip(data).map_err(|err| format!("embedded web UI: {}", err))?)
+                }
+                None => return Err("no web.bundle and no embedded web UI".to_string()),
+            },
+        };
+        Ok(Some(Self {
+            source: Arc::new(source),
+        }))
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .fallback(serve)
+            .layer(CompressionLayer::new())
+            .with_state(self)
+    }
+
+    async fn get(&self, path: &str) -> Option<Asset> {
+        match &*self.source {
+            Source::Dir(dir) => {
+                let relative = Path::new(path);
+                if !relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+                {
+                    return None;
+                }
+                let path = dir.join(relative);
+                let meta = tokio::fs::metadata(&path).await.ok()?;
+                if !meta.is_file() {
+                    return None;
+                }
+                let modified = meta
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                Some(Asset {
+                    body: tokio::fs::read(&path).await.ok()?.into(),
+                    etag: format!("W/\"{:x}-{:x}\"", meta.len(), modified.as_secs()),
+                })
+            }
+            Source::Archive(files) => files.get(path).cloned(),
+        }
+    }
+}
+
+/// Unpacks every file, dropping a single top-level folder like `dist/` that
+/// holds `index.html`.
+fn unzip(data: &[u8]) -> Result<HashMap<String, Asset>, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|err| err.to_string())?;
+    let mut files = HashMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|err| err.to_string())?;
+        if file.is_dir() {
+            continue;
+        }
+        // `enclosed_name` rejects absolute paths and `..`.
+        let Some(name) = file.enclosed_name() else {
+            continue;
+        };
+        let name = name
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let mut body = Vec::new();
+        file.read_to_end(&mut body)
+            .map_err(|err| format!("{}: {}", name, err))?;
+        let etag = Sha256::digest(&body)[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        files.insert(
+            name,
+            Asset {
+                body: body.into(),
+                etag: format!("W/\"{}\"", etag),
+            },
+        );
+    }
+
+    if !files.contains_key(INDEX) {
+        let roots = files
+            .keys()
+            .filter_map(|name| name.strip_suffix(INDEX)?.strip_suffix('/'))
+            .filter(|root| !root.contains('/'))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if let [root] = roots.as_slice() {
+            let prefix = format!("{}/", root);
+            files = files
+                .into_iter()
+                .filter_map(|(name, asset)| Some((name.strip_prefix(&prefix)?.to_string(), asset)))
+                .collect();
+        }
+    }
+    if !files.contains_key(INDEX) {
+        return Err(format!("no {} in the bundle", INDEX));
+    }
+    Ok(files)
+}
+
+/// Serves files by path, and `index.html` for any other path without an
+/// extension so the UI's own routes survive a reload.
+async fn serve(
+    State(bundle): State<WebBundle>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    if method != Method::GET && method != Method::HEAD {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+
+    let mut path = uri.path().trim_start_matches('/').to_string();
+    if path.is_empty() || path.ends_with('/') {
+        path.push_str(INDEX);
+    }
+    let (path, asset) = match bundle.get(&path).await {
+        Some(asset) => (path, asset),
+        None if accepts_html(&headers) || !is_asset_path(&path) => match bundle.get(INDEX).await {
+            Some(asset) => (INDEX.to_string(), asset),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let mime = mime_guess::from_path(&path).first_or_octet_stream();
+    let content_type = match mime.type_() {
+        mime_guess::mime::TEXT => format!("{}; charset=utf-8", mime),
+        _ => mime.to_string(),
+    };
+    let cache_control = if path.ends_with(".html") {
+        // Always checked, so a new build is picked up straight away.
+        "no-cache"
+    } else if path.starts_with(ASSETS) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, max-age=3600"
+    };
+    let etag = HeaderValue::from_str(&asset.etag).unwrap();
+    let response_headers = [
+        (
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&content_type).unwrap(),
+        ),
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(cache_control),
+        ),
+        (header::ETAG, etag.clone()),
+    ];
+
+    let unchanged = headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|if_none_match| etag_matches(if_none_match, &etag));
+    if unchanged {
+        return (StatusCode::NOT_MODIFIED, response_headers).into_response();
+    }
+    (response_headers, asset.body).into_response()
+}
+
+/// Browsers navigating to a route, e.g. `/device/ios:192.168.1.20`, ask for html.
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// Paths that would be a missing file rather than a route, so a stale script
+/// or image 404s instead of getting the page.
+fn is_asset_path(path: &str) -> bool {
+    path.starts_with(ASSETS) || mime_guess::from_path(path).first().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(names: &[&str]) -> WebBundle {
+        let files = names
+            .iter()
+            .map(|name| {
+                let asset = Asset {
+                    body: Bytes::from_static(b"body"),
+                    etag: "W/\"0123456789abcdef\"".to_string(),
+                };
+                (name.to_string(), asset)
+            })
+            .collect();
+        WebBundle {
+            source: Arc::new(Source::Archive(files)),
+        }
+    }
+
+    async fn get(bundle: &WebBundle, path: &str) -> Response {
+        serve(
+            State(bundle.clone()),
+            Method::GET,
+            path.parse().unwrap(),
+            HeaderMap::new(),
+        )
+        .await
+    }
+
+    fn cache_control(response: &Response) -> &str {
+        response.headers()[header::CACHE_CONTROL].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn caches_only_assets_for_a_year() {
+        let bundle = bundle(&[INDEX, "assets/index-BX3k9aQ1.js", "favicon-20240101.png"]);
+        let asset = get(&bundle, "/assets/index-BX3k9aQ1.js").await;
+        assert_eq!(cache_control(&asset), "public, max-age=31536000, immutable");
+        let favicon = get(&bundle, "/favicon-20240101.png").await;
+        assert_eq!(cache_control(&favicon), "public, max-age=3600");
+        let index = get(&bundle, "/devices").await;
+        assert_eq!(cache_control(&index), "no-cache");
+    }
+
+    #[tokio::test]
+    async fn serves_the_page_for_routes_with_dots() {
+        let bundle = bundle(&[INDEX]);
+        let route = get(&bundle, "/device/ios:192.168.1.20").await;
+        assert_eq!(route.status(), StatusCode::OK);
+        assert_eq!(cache_control(&route), "no-cache");
+
+        let missing = get(&bundle, "/assets/index-old.js").await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        let missing = get(&bundle, "/logo.png").await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("text/html,*/*"));
+        let uri = "/docs/readme.md".parse().unwrap();
+        let page = serve(State(bundle), Method::GET, uri, headers).await;
+        assert_eq!(page.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_the_weak_etag() {
+        let bundle = bundle(&[INDEX]);
+        let response = get(&bundle, "/").await;
+        let etag = response.headers()[header::ETAG].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag);
+        let response = serve(State(bundle), Method::GET, "/".parse().unwrap(), headers).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+}
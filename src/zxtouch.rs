@@ -0,0 +1,134 @@
+/// Task id of a touch command: `10<count>` followed by `count` events.
+const TASK_PERFORM_TOUCH: &str = "10";
+
+pub const TOUCH_UP: u8 = 0;
+pub const TOUCH_DOWN: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TouchEvent {
+    pub kind: u8,
+    pub finger: u8,
+    pub x: f64,
+    pub y: f64,
+}
+
+impl TouchEvent {
+    fn encode(&self, out: &mut String) {
+        let x = (self.x * 10.0).round().clamp(0.0, 99999.0) as u32;
+        let y = (self.y * 10.0).round().clamp(0.0, 99999.0) as u32;
+        out.push_str(&format!("{}{:02}{:05}{:05}", self.kind, self.finger, x, y));
+    }
+}
+
+/// Parses a touch command line such as `1011010050001000`.
+///
+/// Returns `None` for every other task so callers can forward it untouched.
+pub fn parse_touch(line: &str) -> Option<Vec<TouchEvent>> {
+    let body = line
+        .trim_end_matches(['\r', '\n'])
+        .strip_prefix(TASK_PERFORM_TOUCH)?;
+    let count = body.get(..1)?.parse::<usize>().ok()?;
+    let events = body.get(1..)?;
+    if count == 0 || events.len() != count * 13 || !events.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(
+        (0..count)
+            .map(|i| {
+                let event = &events[i * 13..(i + 1) * 13];
+                TouchEvent {
+                    kind: event[..1].parse().unwrap(),
+                    finger: event[1..3].parse().unwrap(),
+                    x: event[3..8].parse::<f64>().unwrap() / 10.0,
+                    y: event[8..13].parse::<f64>().unwrap() / 10.0,
+                }
+            })
+            .collect(),
+    )
+}
+
+pub fn encode_touch(events: &[TouchEvent]) -> String {
+    let mut line = format!("{}{}", TASK_PERFORM_TOUCH, events.len());
+    for event in events {
+        event.encode(&mut line);
+    }
+    line.push_str("\r\n");
+    line
+}
+
+/// Touch down and up at one point with the first finger.
+pub fn tap(x: f64, y: f64) -> [String; 2] {
+    [TOUCH_DOWN, TOUCH_UP].map(|kind| {
+        encode_touch(&[TouchEvent {
+            kind,
+            finger: 1,
+            x,
+            y,
+        }])
+    })
+}
+
+/// Rewrites the coordinates of every touch command in a frame from one
+/// screen size to another. Other commands are kept unchanged.
+pub fn scale_lines(lines: &str, from: (u32, u32), to: (u32, u32)) -> String {
+    if from.0 == 0 || from.1 == 0 || to.0 == 0 || to.1 == 0 || from == to {
+        return lines.to_string();
+    }
+
+    let sx = to.0 as f64 / from.0 as f64;
+    let sy = to.1 as f64 / from.1 as f64;
+    lines
+        .split_inclusive('\n')
+        .map(|line| match parse_touch(line) {
+            Some(mut events) => {
+                for event in &mut events {
+                    event.x *= sx;
+                    event.y *= sy;
+                }
+                encode_touch(&events)
+            }
+            None => line.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_every_touch_in_a_frame() {
+        let frame = [
+            encode_touch(&[TouchEvent {
+                kind: TOUCH_DOWN,
+                finger: 1,
+                x: 100.0,
+                y: 200.0,
+            }]),
+            "9\r\n".to_string(),
+            encode_touch(&[TouchEvent {
+                kind: TOUCH_UP,
+                finger: 1,
+                x: 100.0,
+                y: 200.0,
+            }]),
+        ]
+        .concat();
+
+        let scaled = scale_lines(&frame, (1000, 2000), (500, 1000));
+        let lines = scaled.split_inclusive('\n').collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(parse_touch(lines[0]).unwrap()[0].x, 50.0);
+        assert_eq!(parse_touch(lines[0]).unwrap()[0].y, 100.0);
+        assert_eq!(lines[1], "9\r\n");
+        assert_eq!(parse_touch(lines[2]).unwrap()[0].kind, TOUCH_UP);
+        assert_eq!(parse_touch(lines[2]).unwrap()[0].x, 50.0);
+    }
+
+    #[test]
+    fn keeps_the_frame_for_the_same_screen() {
+        let frame = "1011010050001000\r\n1010010050001000\r\n";
+        assert_eq!(scale_lines(frame, (750, 1334), (750, 1334)), frame);
+    }
+}